@@ -0,0 +1,48 @@
+#!/bin/sh
+# Swaps money between two local chains with hash time-locked contracts.
+#
+# Alice owns money on chain A and Bob on chain B. Alice locks 5 coins on A
+# for Bob with the hash of a secret only she knows, and Bob locks 3 coins on B
+# for Alice with the same hash. Alice claims the coins on B, revealing the
+# secret, which Bob then uses to claim the coins on A. If either side walks
+# away, the money goes back to its owner after the timeout.
+set -e
+
+BLOCKCHAIN=${BLOCKCHAIN:-"cargo run -q --"}
+DIR=${1:-swap-data}
+
+rm -rf "$DIR"
+a() { $BLOCKCHAIN --data-dir "$DIR/chain-a" "$@"; }
+b() { $BLOCKCHAIN --data-dir "$DIR/chain-b" "$@"; }
+field() { grep "^$1" | awk '{print $NF}'; }
+
+ALICE_A=$(a create-wallet | awk '{print $NF}')
+BOB_A=$(a create-wallet | awk '{print $NF}')
+ALICE_B=$(b create-wallet | awk '{print $NF}')
+BOB_B=$(b create-wallet | awk '{print $NF}')
+
+a create "$ALICE_A"
+b create "$BOB_B"
+
+echo "== Alice locks 5 coins on chain A for Bob"
+OUT=$(a htlc-create "$ALICE_A" "$BOB_A" 5 --timeout 20)
+echo "$OUT"
+HTLC_A=$(echo "$OUT" | field "HTLC address")
+HASH=$(echo "$OUT" | field "Payment hash")
+SECRET=$(echo "$OUT" | field "Secret")
+
+echo "== Bob locks 3 coins on chain B for Alice with the same hash"
+OUT=$(b htlc-create "$BOB_B" "$ALICE_B" 3 --timeout 10 --hash "$HASH")
+echo "$OUT"
+HTLC_B=$(echo "$OUT" | field "HTLC address")
+
+echo "== Alice claims the coins on chain B with her secret"
+b htlc-claim "$HTLC_B" "$SECRET"
+
+echo "== Bob reads the secret from chain B and claims the coins on chain A"
+REVEALED=$(b htlc-secret "$HTLC_B")
+a htlc-claim "$HTLC_A" "$REVEALED"
+
+echo "== Balances, including the rewards for mining the claims"
+a get-balance "$BOB_A"
+b get-balance "$ALICE_B"
@@ -0,0 +1,192 @@
+use crate::errors::Result;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// Number of decimal places of a coin.
+pub const DECIMALS: usize = 8;
+
+/// Number of base units in one coin.
+pub const COIN: u64 = 100_000_000;
+
+/// Amount is a non-negative quantity of money counted in base units, where
+/// one coin is [`COIN`] units.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX: Amount = Amount(u64::MAX);
+
+    /// Creates an amount of base units.
+    pub const fn from_units(units: u64) -> Amount {
+        Amount(units)
+    }
+
+    /// Creates an amount of whole coins.
+    pub const fn from_coins(coins: u64) -> Amount {
+        Amount(coins * COIN)
+    }
+
+    /// Returns the amount in base units.
+    pub const fn units(&self) -> u64 {
+        self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// Sums the amounts, failing if the total overflows.
+    pub fn sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Result<Amount> {
+        amounts.into_iter().try_fold(Amount::ZERO, |acc, a| {
+            acc.checked_add(a)
+                .ok_or_else(|| format_err!("Total amount overflows"))
+        })
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:0width$}",
+            self.0 / COIN,
+            self.0 % COIN,
+            width = DECIMALS
+        )
+    }
+}
+
+impl FromStr for Amount {
+    type Err = failure::Error;
+
+    /// Parses an amount of coins with up to [`DECIMALS`] decimal places.
+    fn from_str(s: &str) -> Result<Amount> {
+        let s = s.trim();
+        let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+
+        let is_digits = |p: &str| p.chars().all(|c| c.is_ascii_digit());
+        if whole.is_empty() && fraction.is_empty() || !is_digits(whole) || !is_digits(fraction) {
+            return Err(format_err!("Invalid amount '{}'", s));
+        }
+        if fraction.len() > DECIMALS {
+            return Err(format_err!(
+                "Amount '{}' has more than {} decimal places",
+                s,
+                DECIMALS
+            ));
+        }
+
+        let whole: u64 = if whole.is_empty() { 0 } else { whole.parse()? };
+        let fraction: u64 = format!("{:0<width$}", fraction, width = DECIMALS).parse()?;
+
+        whole
+            .checked_mul(COIN)
+            .and_then(|units| units.checked_add(fraction))
+            .map(Amount)
+            .ok_or_else(|| format_err!("Amount '{}' is too large", s))
+    }
+}
+
+/// Serializes an amount as a string of coins, and deserializes it from such
+/// a string or from a whole number of coins. Used for documents written by
+/// people.
+pub mod serde_coins {
+    use super::Amount;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(amount: &Amount, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(amount)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Coins {
+            Whole(u64),
+            Text(String),
+        }
+
+        match Coins::deserialize(deserializer)? {
+            Coins::Whole(coins) => coins
+                .checked_mul(super::COIN)
+                .map(Amount::from_units)
+                .ok_or_else(|| de::Error::custom("amount is too large")),
+            Coins::Text(s) => s.parse().map_err(de::Error::custom),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_and_parse_round_trip() {
+        let amounts = [
+            Amount::ZERO,
+            Amount::from_units(1),
+            Amount::from_units(50_000_000),
+            Amount::from_coins(21),
+            Amount::from_units(1_234_567_891),
+            Amount::MAX,
+        ];
+
+        for amount in amounts {
+            let text = amount.to_string();
+            assert_eq!(text.parse::<Amount>().unwrap(), amount, "{}", text);
+        }
+        assert_eq!(Amount::from_units(1).to_string(), "0.00000001");
+        assert_eq!(Amount::MAX.to_string(), "184467440737.09551615");
+    }
+
+    #[test]
+    fn parses_short_forms() {
+        let parse = |s: &str| s.parse::<Amount>().unwrap();
+
+        assert_eq!(parse("3"), Amount::from_coins(3));
+        assert_eq!(parse("3."), Amount::from_coins(3));
+        assert_eq!(parse(".5"), Amount::from_units(50_000_000));
+        assert_eq!(parse(" 0.1 "), Amount::from_units(10_000_000));
+        assert_eq!(parse("007.00000001"), Amount::from_units(700_000_001));
+    }
+
+    #[test]
+    fn rejects_invalid_amounts() {
+        let invalid = [
+            "",
+            ".",
+            "-1",
+            "+1",
+            "1e3",
+            "abc",
+            "1.2.3",
+            "1,5",
+            "0.000000001",
+            "184467440737.09551616",
+            "99999999999999999999",
+        ];
+
+        for s in invalid {
+            assert!(s.parse::<Amount>().is_err(), "{} was accepted", s);
+        }
+    }
+
+    #[test]
+    fn sums_without_overflow() {
+        let total = Amount::sum([Amount::from_coins(1), Amount::from_units(5)]).unwrap();
+        assert_eq!(total, Amount::from_units(COIN + 5));
+        assert!(Amount::sum([Amount::MAX, Amount::from_units(1)]).is_err());
+    }
+}
@@ -1,130 +1,369 @@
-use crate::{errors::Result, transaction::Transaction};
+use crate::{
+    consensus::{pow::ProofOfWork, ConsensusEngine},
+    encoding::{write_all, write_bytes, write_i64, write_u32, write_u64, Decode, Encode, Reader},
+    errors::Result,
+    hash::{BlockHash, MerkleRoot, Txid},
+    merkle::{build_merkle_proof, merkle_root, MerkleProof},
+    miner::{Miner, MiningOutcome, MiningStats},
+    transaction::Transaction,
+};
 use chrono::Utc;
+use failure::format_err;
 use log::info;
-use merkle_cbt::merkle_tree::{Merge, CBMT};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 
 pub const TARGET_HEXS: usize = 4;
 
+/// Version of the blocks created by this node. Version 1 headers, whose
+/// nonce has 32 bits, are still decoded.
+pub const BLOCK_VERSION: u32 = 2;
+
+/// Version of the blocks sealed by the signature of a validator rather than
+/// a proof of work. Their header ends with the seal.
+pub const SEALED_BLOCK_VERSION: u32 = 3;
+
+/// Offset of the nonce in the encoded header, where a miner changes it in
+/// place.
+pub const NONCE_OFFSET: usize = 4 + 32 + 32 + 8 + 8;
+
+/// BlockHeader holds the fields of a block hashed by the proof of work or
+/// signed by a validator. It commits to the transactions through their
+/// merkle root.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub prev_block_hash: BlockHash,
+    pub merkle_root: MerkleRoot,
+    pub timestamp: i64,
+    /// Number of leading zero hex digits the block hash must have.
+    pub target: usize,
+    /// Number changed by the miner until the hash meets the target. Version
+    /// 1 headers only have the low 32 bits.
+    pub nonce: u64,
+    pub height: usize,
+    /// Signature of the validator over the `seal_hash` of a version 3
+    /// header, empty in older versions.
+    #[serde(default)]
+    pub seal: Vec<u8>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
-    timestamp: i64,
+    header: BlockHeader,
+    hash: BlockHash,
     transactions: Vec<Transaction>,
-    prev_block_hash: String,
-    hash: String,
-    nonce: i32,
-    height: usize,
 }
 
+/// BlockTemplate holds a block for an external miner, which searches the
+/// nonce of the header and submits the template back.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct MergeTX;
+pub struct BlockTemplate {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
+}
+
+impl BlockHeader {
+    /// Returns the hash of the canonical encoding of the header, which is
+    /// the hash of the block.
+    pub fn hash(&self) -> Result<BlockHash> {
+        Ok(BlockHash::digest(&self.encode()))
+    }
+
+    /// Returns the hash the seal signs, the hash of the header without its
+    /// seal.
+    pub fn seal_hash(&self) -> BlockHash {
+        let mut out = Vec::new();
+        self.encode_unsealed(&mut out);
+        BlockHash::digest(&out)
+    }
+
+    /// Returns the expected number of hashes to find a nonce meeting the
+    /// target.
+    pub fn work(&self) -> f64 {
+        16f64.powi(self.target as i32)
+    }
+
+    /// Returns the largest nonce the version of the header can hold.
+    pub fn max_nonce(&self) -> u64 {
+        if self.version == 1 {
+            u32::MAX as u64
+        } else {
+            u64::MAX
+        }
+    }
+}
+
+/// Checks whether the hash starts with the target number of zero hex digits.
+pub fn meets_target(hash: &BlockHash, target: usize) -> bool {
+    let mut zeros = 0;
+    for byte in hash.as_bytes() {
+        if *byte == 0 {
+            zeros += 2;
+        } else {
+            if *byte < 0x10 {
+                zeros += 1;
+            }
+            break;
+        }
+    }
+    zeros >= target
+}
 
 impl Block {
     /// Creates new block.
     pub fn new_block(
         transactions: Vec<Transaction>,
-        prev_block_hash: String,
+        prev_block_hash: BlockHash,
         height: usize,
     ) -> Result<Self> {
         let timestamp = Utc::now().timestamp_millis();
+        Block::new_block_at(transactions, prev_block_hash, height, timestamp)
+    }
 
-        let mut block = Block {
-            timestamp,
+    /// Creates new block mined at the time in milliseconds.
+    pub fn new_block_at(
+        transactions: Vec<Transaction>,
+        prev_block_hash: BlockHash,
+        height: usize,
+        timestamp: i64,
+    ) -> Result<Self> {
+        let mut block = Block::new_template(
             transactions,
-            hash: String::new(),
             prev_block_hash,
-            nonce: 0,
             height,
+            timestamp,
+            &ProofOfWork,
+        )?;
+        block.mine(&Miner::default())?;
+        Ok(block)
+    }
+
+    /// Creates a block at the time in milliseconds which still has to be
+    /// sealed by the engine.
+    pub fn new_template(
+        transactions: Vec<Transaction>,
+        prev_block_hash: BlockHash,
+        height: usize,
+        timestamp: i64,
+        engine: &dyn ConsensusEngine,
+    ) -> Result<Self> {
+        let mut block = Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_block_hash,
+                merkle_root: MerkleRoot::ZERO,
+                timestamp,
+                target: TARGET_HEXS,
+                nonce: 0,
+                height,
+                seal: Vec::new(),
+            },
+            hash: BlockHash::ZERO,
+            transactions,
         };
+        engine.prepare(&mut block.header);
+        block.header.merkle_root = block.hash_transactions()?;
+        block.hash = block.header.hash()?;
 
-        block.run_proof_of_work()?;
+        Ok(block)
+    }
+
+    /// Creates the block of a template, mined or not.
+    pub fn from_template(template: BlockTemplate) -> Result<Self> {
+        Ok(Block {
+            hash: template.header.hash()?,
+            header: template.header,
+            transactions: template.transactions,
+        })
+    }
 
+    /// Creates a new genesis block sealed by the engine.
+    pub fn new_genesis_block(coninbase: Transaction, engine: &dyn ConsensusEngine) -> Result<Self> {
+        let timestamp = Utc::now().timestamp_millis();
+        let mut block =
+            Block::new_template(vec![coninbase], BlockHash::ZERO, 0, timestamp, engine)?;
+        engine
+            .seal(&mut block, &Miner::default())?
+            .ok_or_else(|| format_err!("Sealing the genesis block was cancelled"))?;
         Ok(block)
     }
 
-    /// Creates a new genesis block.
-    pub fn new_genesis_block(coninbase: Transaction) -> Self {
-        Block::new_block(vec![coninbase], String::new(), 0).unwrap()
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
     }
 
     pub fn get_transactions(&self) -> &Vec<Transaction> {
         &self.transactions
     }
 
-    pub fn get_hash(&self) -> String {
-        self.hash.clone()
+    pub fn get_hash(&self) -> BlockHash {
+        self.hash
     }
 
-    pub fn get_prev_hash(&self) -> String {
-        self.prev_block_hash.clone()
+    pub fn get_prev_hash(&self) -> BlockHash {
+        self.header.prev_block_hash
     }
 
-    /// Run performs a proof-of work.
-    fn run_proof_of_work(&mut self) -> Result<()> {
-        info!("Mining the block");
+    pub fn get_height(&self) -> usize {
+        self.header.height
+    }
 
-        while !self.validate()? {
-            self.nonce += 1;
-        }
+    /// Returns the time the block was mined at in milliseconds.
+    pub fn get_timestamp(&self) -> i64 {
+        self.header.timestamp
+    }
+
+    /// Performs the proof of work with the miner. When no nonce meets the
+    /// target the extra nonce of the coinbase is bumped, which changes the
+    /// merkle root and gives the miner new nonces to try. Returns the work
+    /// done, or `None` if the miner was cancelled.
+    pub fn mine(&mut self, miner: &Miner) -> Result<Option<MiningStats>> {
+        let mut total = MiningStats::default();
+        miner.reset();
+
+        loop {
+            let (outcome, stats) = miner.mine(&mut self.header);
+            total.hashes += stats.hashes;
+            total.elapsed += stats.elapsed;
 
-        let data = self.prepare_hash_data()?;
-        self.hash = format!("{:X}", Sha256::new().chain_update(&data[..]).finalize());
+            match outcome {
+                MiningOutcome::Found(hash) => {
+                    self.hash = hash;
+                    return Ok(Some(total));
+                }
+                MiningOutcome::Cancelled => return Ok(None),
+                MiningOutcome::Exhausted => self.bump_extra_nonce()?,
+            }
+        }
+    }
 
+    /// Sets the seal of the header, which changes the hash of the block.
+    pub(crate) fn set_seal(&mut self, seal: Vec<u8>) -> Result<()> {
+        self.header.seal = seal;
+        self.hash = self.header.hash()?;
         Ok(())
     }
 
-    /// Returns a hash of the transactions in the block.
-    fn hash_transactions(&self) -> Result<Vec<u8>> {
-        let transactions = self
+    /// Changes the extra nonce of the coinbase and restarts the nonce.
+    fn bump_extra_nonce(&mut self) -> Result<()> {
+        let coinbase = self
             .transactions
-            .iter()
-            .map(|t| t.hash())
-            .collect::<Result<Vec<_>>>()?
-            .iter()
-            .map(|v| v.as_bytes().to_owned())
-            .collect::<Vec<_>>();
+            .first_mut()
+            .filter(|tx| tx.is_coinbase())
+            .ok_or_else(|| {
+                format_err!("No nonce meets the target and the block has no coinbase to change")
+            })?;
 
-        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&transactions);
+        let extra_nonce = coinbase.get_extra_nonce().unwrap_or(0).wrapping_add(1);
+        coinbase.set_extra_nonce(extra_nonce)?;
+        info!("Bumped the extra nonce of the coinbase to {}", extra_nonce);
 
-        Ok(tree.root())
+        self.header.merkle_root = self.hash_transactions()?;
+        self.header.nonce = 0;
+        Ok(())
     }
 
-    fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-        let content = (
-            self.prev_block_hash.clone(),
-            self.hash_transactions()?,
-            self.timestamp,
-            TARGET_HEXS,
-            self.nonce,
-        );
+    /// Returns a hash of the transactions in the block.
+    pub fn hash_transactions(&self) -> Result<MerkleRoot> {
+        MerkleRoot::from_slice(&merkle_root(&self.transaction_hashes()?))
+    }
 
-        bincode::serialize(&content).map_err(|e| e.into())
+    /// Returns the proof that the transaction is in the block.
+    pub fn merkle_proof(&self, txid: &Txid) -> Result<Option<MerkleProof>> {
+        let index = match self.transactions.iter().position(|tx| tx.id == *txid) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+
+        Ok(build_merkle_proof(&self.transaction_hashes()?, index))
     }
 
-    fn validate(&self) -> Result<bool> {
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
+    /// Returns the hashes of the transactions, the leaves of the merkle tree.
+    fn transaction_hashes(&self) -> Result<Vec<Vec<u8>>> {
+        self.transactions
+            .iter()
+            .map(|t| t.hash().map(|h| h.as_bytes().to_vec()))
+            .collect()
+    }
+}
 
-        hasher.update(&data[..]);
-        let mut vec1: Vec<u8> = vec![];
-        vec1.resize(TARGET_HEXS, b'0');
+impl From<Block> for BlockTemplate {
+    fn from(block: Block) -> Self {
+        BlockTemplate {
+            header: block.header,
+            transactions: block.transactions,
+        }
+    }
+}
 
-        Ok(format!("{:X}", hasher.finalize())[0..TARGET_HEXS] == String::from_utf8(vec1)?)
+impl BlockHeader {
+    /// Writes the fields of the header but the seal.
+    fn encode_unsealed(&self, out: &mut Vec<u8>) {
+        write_u32(out, self.version);
+        out.extend_from_slice(self.prev_block_hash.as_bytes());
+        out.extend_from_slice(self.merkle_root.as_bytes());
+        write_i64(out, self.timestamp);
+        write_u64(out, self.target as u64);
+        if self.version == 1 {
+            write_u32(out, self.nonce as u32);
+        } else {
+            write_u64(out, self.nonce);
+        }
+        write_u64(out, self.height as u64);
     }
 }
 
-impl Merge for MergeTX {
-    type Item = Vec<u8>;
+impl Encode for BlockHeader {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.encode_unsealed(out);
+        if self.version >= SEALED_BLOCK_VERSION {
+            write_bytes(out, &self.seal);
+        }
+    }
+}
+
+impl Decode for BlockHeader {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<BlockHeader> {
+        let version = reader.read_u32()?;
+        if !(1..=SEALED_BLOCK_VERSION).contains(&version) {
+            return Err(format_err!("Unsupported block version {}", version));
+        }
 
-    fn merge(left: &Self::Item, right: &Self::Item) -> Self::Item {
-        let mut data = left.clone();
-        data.append(&mut right.clone());
+        Ok(BlockHeader {
+            version,
+            prev_block_hash: BlockHash::from_bytes(reader.read_hash()?),
+            merkle_root: MerkleRoot::from_bytes(reader.read_hash()?),
+            timestamp: reader.read_i64()?,
+            target: reader.read_usize()?,
+            nonce: if version == 1 {
+                reader.read_u32()? as u64
+            } else {
+                reader.read_u64()?
+            },
+            height: reader.read_usize()?,
+            seal: if version >= SEALED_BLOCK_VERSION {
+                reader.read_bytes()?.to_vec()
+            } else {
+                Vec::new()
+            },
+        })
+    }
+}
 
-        let hasher = Sha256::new_with_prefix(&data);
-        let mut re = [0u8; 32];
+impl Encode for Block {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.header.encode_to(out);
+        write_all(out, &self.transactions);
+    }
+}
 
-        re.copy_from_slice(&hasher.finalize());
-        re.to_vec()
+impl Decode for Block {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Block> {
+        let header = BlockHeader::decode_from(reader)?;
+        Ok(Block {
+            hash: header.hash()?,
+            header,
+            transactions: reader.read_all()?,
+        })
     }
 }
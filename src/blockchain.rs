@@ -1,25 +1,82 @@
 use crate::{
-    block::{Block, TARGET_HEXS},
+    amount::Amount,
+    block::{Block, BlockHeader},
+    consensus::{ConsensusConfig, ConsensusEngine},
     db,
+    encoding::{Decode, Encode},
     errors::Result,
-    transaction::{tx::TXOutputs, Transaction},
+    filter::BlockFilter,
+    hash::{BlockHash, Txid},
+    mempool,
+    miner::{Miner, MiningStats},
+    params::ChainParams,
+    script::ScriptType,
+    transaction::{
+        tx::{RelativeLock, TXOutputs},
+        Transaction, LOCKTIME_THRESHOLD,
+    },
 };
+use chrono::Utc;
 use failure::format_err;
 use log::{debug, error, info};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 const GENESIS_COINBASE_DATA: &str =
     "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
 
+/// Name of the tree of the blocks DB holding block headers.
+const HEADERS_TREE: &str = "headers";
+
+/// Name of the tree of the blocks DB holding block filters.
+const FILTERS_TREE: &str = "filters";
+
+/// Number of blocks before a new one whose median time it must be after.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Version of the format of the blocks DB. Version 1 keyed blocks by hex
+/// strings, version 2 stored them with bincode, both are upgraded by
+/// `migration::migrate`. Version 3 stores blocks and headers in the
+/// canonical encoding.
+pub const BLOCKS_DB_VERSION: u32 = 3;
+
 #[derive(Debug, Clone)]
 pub struct Blockchain {
-    current_hash: String,
+    current_hash: BlockHash,
+    params: ChainParams,
+    consensus: ConsensusConfig,
     db: sled::Db,
+    headers: sled::Tree,
+    filters: sled::Tree,
 }
 
+/// ChainUpdate tells how a stored block changed the chain.
+#[derive(Debug, Clone)]
+pub enum ChainUpdate {
+    /// The block is the new tip.
+    Extended,
+    /// The block is on a branch the fork choice doesn't prefer, the tip is
+    /// kept.
+    SideBranch,
+    /// The branch of the block replaced the end of the chain. Both hold the
+    /// blocks after the one they share, oldest first.
+    Reorganized {
+        connected: Vec<Block>,
+        disconnected: Vec<Block>,
+    },
+}
+
+/// Headers of a branch with their hashes, oldest first.
+pub(crate) type Branch = Vec<(BlockHash, BlockHeader)>;
+
 /// Used to iterate over blockchain blocks.
 pub struct BlockchainIterator<'a> {
-    current_hash: String,
+    current_hash: BlockHash,
+    bc: &'a Blockchain,
+}
+
+/// Used to iterate over block headers without loading the transactions.
+pub struct HeaderIterator<'a> {
+    current_hash: BlockHash,
     bc: &'a Blockchain,
 }
 
@@ -28,100 +85,766 @@ impl Blockchain {
     pub fn new() -> Result<Blockchain> {
         info!("open blockchain");
 
-        let db = sled::open(db::DB_BLOCKS_PATH)?;
-        let hash = match db.get("LAST")? {
-            Some(l) => l.to_vec(),
-            None => Vec::new(),
+        let db = db::open(db::DB_BLOCKS)?;
+        let version = match db.get("VERSION")? {
+            Some(v) => bincode::deserialize(&v)?,
+            None => 1,
+        };
+        let last_hash = match db.get("LAST")? {
+            Some(_) if version != BLOCKS_DB_VERSION => {
+                return Err(format_err!(
+                    "Block database has format version {}, run `blockchain migrate` to upgrade it",
+                    version
+                ));
+            }
+            Some(l) => BlockHash::from_slice(&l)?,
+            None => BlockHash::ZERO,
         };
 
         info!("Found block database");
 
-        let last_hash = if hash.is_empty() {
-            String::new()
-        } else {
-            String::from_utf8(hash.to_vec())?
+        let params = match db.get("PARAMS")? {
+            Some(p) => ChainParams::from_bytes(&p)?,
+            None => ChainParams::default(),
         };
+        // chains created before the engines were pluggable use proof of work
+        let consensus = match db.get("CONSENSUS")? {
+            Some(c) => bincode::deserialize(&c)?,
+            None => ConsensusConfig::default(),
+        };
+        let headers = db.open_tree(HEADERS_TREE)?;
+        let filters = db.open_tree(FILTERS_TREE)?;
 
         Ok(Blockchain {
             current_hash: last_hash,
+            params,
+            consensus,
             db,
+            headers,
+            filters,
         })
     }
 
-    /// Creates a new Blockchain db.
-    pub fn create_blockchain(address: String) -> Result<Blockchain> {
+    /// Creates a new Blockchain db whose blocks are sealed by the engine of
+    /// the consensus config.
+    pub fn create_blockchain(
+        address: String,
+        params: ChainParams,
+        consensus: ConsensusConfig,
+    ) -> Result<Blockchain> {
         info!("creating new blockchain");
 
-        if let Err(e) = std::fs::remove_dir_all(db::DB_BLOCKS_PATH) {
+        let cbtx = Transaction::new_coinbase(
+            address,
+            String::from(GENESIS_COINBASE_DATA),
+            params.subsidy(0),
+        )?;
+        let genesis = Block::new_genesis_block(cbtx, consensus.engine()?.as_ref())?;
+
+        let mut bc = Blockchain::create_empty(params, consensus)?;
+        bc.put_block(&genesis)?;
+        bc.db.flush()?;
+
+        Ok(bc)
+    }
+
+    /// Replaces the Blockchain db with an empty one following the consensus
+    /// rules.
+    pub(crate) fn create_empty(
+        params: ChainParams,
+        consensus: ConsensusConfig,
+    ) -> Result<Blockchain> {
+        if let Err(e) = std::fs::remove_dir_all(db::path(db::DB_BLOCKS)) {
             match e.kind() {
                 std::io::ErrorKind::NotFound => debug!("blocks not exists to delete"),
                 _ => error!("undefined error on delete blocks: {}", e),
             };
         }
 
-        let db = sled::open(db::DB_BLOCKS_PATH)?;
+        let db = db::open(db::DB_BLOCKS)?;
 
         info!("creating new block database");
 
-        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
-        let genesis: Block = Block::new_genesis_block(cbtx);
+        db.insert("PARAMS", params.to_bytes()?)?;
+        db.insert("CONSENSUS", bincode::serialize(&consensus)?)?;
+        db.insert("VERSION", bincode::serialize(&BLOCKS_DB_VERSION)?)?;
+        let headers = db.open_tree(HEADERS_TREE)?;
+        let filters = db.open_tree(FILTERS_TREE)?;
 
-        db.insert(genesis.get_hash(), bincode::serialize(&genesis)?)?;
-        db.insert("LAST", genesis.get_hash().as_bytes())?;
-
-        let bc = Blockchain {
-            current_hash: genesis.get_hash(),
+        Ok(Blockchain {
+            current_hash: BlockHash::ZERO,
+            params,
+            consensus,
             db,
+            headers,
+            filters,
+        })
+    }
+
+    /// Stores the block with its header and filter, without checking it. It
+    /// becomes the new tip if it extends the tip or if the fork choice
+    /// prefers its branch.
+    pub(crate) fn put_block(&mut self, block: &Block) -> Result<ChainUpdate> {
+        let hash = block.get_hash();
+
+        self.headers.insert(hash, block.get_header().encode())?;
+        self.filters
+            .insert(hash, bincode::serialize(&BlockFilter::new(block)?)?)?;
+        self.db.insert(hash, block.encode())?;
+
+        if block.get_prev_hash() == self.current_hash {
+            self.db.insert("LAST", hash.as_bytes())?;
+            self.current_hash = hash;
+            return Ok(ChainUpdate::Extended);
+        }
+
+        let (candidate, current) = branches(hash, self.current_hash, |h| self.get_header(h))?;
+        if !self
+            .engine()?
+            .prefers(&branch_headers(&candidate), &branch_headers(&current))
+        {
+            info!("Block {} is kept on a side branch", hash);
+            return Ok(ChainUpdate::SideBranch);
+        }
+
+        let blocks = |branch: &Branch| {
+            branch
+                .iter()
+                .map(|(hash, _)| self.get_block(hash))
+                .collect::<Result<Vec<_>>>()
         };
+        let connected = blocks(&candidate)?;
+        let disconnected = blocks(&current)?;
+        info!(
+            "Reorganized the chain to block {}, replacing {} blocks with {}",
+            hash,
+            disconnected.len(),
+            connected.len()
+        );
 
-        bc.db.flush()?;
+        self.db.insert("LAST", hash.as_bytes())?;
+        self.current_hash = hash;
+        Ok(ChainUpdate::Reorganized {
+            connected,
+            disconnected,
+        })
+    }
 
-        Ok(bc)
+    /// Returns the chain ending at the stored block, which checks the blocks
+    /// of another branch.
+    fn at(&self, hash: BlockHash) -> Blockchain {
+        Blockchain {
+            current_hash: hash,
+            ..self.clone()
+        }
+    }
+
+    /// Mines a block with the transactions and a coinbase paying the block
+    /// subsidy and the transaction fees to the address.
+    pub fn mine_block(&mut self, transactions: Vec<Transaction>, address: &str) -> Result<Block> {
+        let (block, _) = self
+            .mine_block_with(transactions, address, &Miner::default())?
+            .ok_or_else(|| format_err!("Mining was cancelled"))?;
+        Ok(block)
+    }
+
+    /// Mines a block like `mine_block` with the miner, or seals it with the
+    /// engine of the chain. Returns the block and the work done, or `None` if
+    /// the miner was cancelled.
+    pub fn mine_block_with(
+        &mut self,
+        transactions: Vec<Transaction>,
+        address: &str,
+        miner: &Miner,
+    ) -> Result<Option<(Block, MiningStats)>> {
+        let mut block = self.new_block_template(transactions, address)?;
+        let stats = match self.engine()?.seal(&mut block, miner)? {
+            Some(stats) => stats,
+            None => return Ok(None),
+        };
+
+        self.put_block(&block)?;
+        Ok(Some((block, stats)))
+    }
+
+    /// Creates the block extending the tip with the transactions and a
+    /// coinbase paying the block subsidy and the transaction fees to the
+    /// address, which still has to be sealed.
+    pub fn new_block_template(
+        &self,
+        transactions: Vec<Transaction>,
+        address: &str,
+    ) -> Result<Block> {
+        let height = self.get_best_height()? + 1;
+        let fees = self.transaction_fees(&transactions)?;
+        let reward = self
+            .params
+            .subsidy(height)
+            .checked_add(fees)
+            .ok_or_else(|| format_err!("Block reward overflows"))?;
+
+        let mut txs = Vec::with_capacity(transactions.len() + 1);
+        if !reward.is_zero() {
+            txs.push(Transaction::new_coinbase(
+                address.to_string(),
+                String::new(),
+                reward,
+            )?);
+        }
+        txs.extend(transactions);
+
+        let timestamp = self.next_timestamp()?;
+        self.validate_transactions(&txs, height, timestamp / 1000)?;
+        let block = Block::new_template(
+            txs,
+            self.current_hash,
+            height,
+            timestamp,
+            self.engine()?.as_ref(),
+        )?;
+        self.check_block_size(&block)?;
+
+        Ok(block)
+    }
+
+    /// Chooses the candidate transactions of a block paying to the address.
+    /// A transaction is packed together with its ancestors among the
+    /// candidates, so a child paying a high fee gets its parents mined. The
+    /// packages with the highest fee rates come first, skipping those which
+    /// no longer fit in the maximum block size.
+    pub fn pack_transactions(
+        &self,
+        candidates: Vec<Transaction>,
+        address: &str,
+    ) -> Result<Vec<Transaction>> {
+        // the size of the coinbase doesn't depend on the reward
+        let coinbase = Transaction::new_coinbase(address.to_string(), String::new(), Amount::ZERO)?;
+        let mut size = Block::new_template(
+            vec![coinbase],
+            self.current_hash,
+            0,
+            0,
+            self.engine()?.as_ref(),
+        )?
+        .encode()
+        .len();
+
+        let mut rated = HashMap::new();
+        for tx in &candidates {
+            let fee = self.transaction_fee(tx, &candidates)?;
+            rated.insert(tx.id, (fee, tx.encode().len()));
+        }
+
+        let mut remaining = candidates;
+        let mut packed = Vec::new();
+        while !remaining.is_empty() {
+            let mut best: Option<(Amount, usize, Vec<Txid>)> = None;
+            for tx in &remaining {
+                let package = mempool::ancestors(tx, &remaining);
+                let fee = Amount::sum(package.iter().map(|id| rated[id].0))?;
+                let package_size = package.iter().map(|id| rated[id].1).sum();
+
+                let higher = best.as_ref().map_or(true, |(f, s, _)| {
+                    mempool::lower_rate((*f, *s), (fee, package_size))
+                });
+                if higher {
+                    best = Some((fee, package_size, package));
+                }
+            }
+
+            let (_, package_size, package) = match best {
+                Some(best) => best,
+                None => break,
+            };
+            if size + package_size > self.params.max_block_size {
+                // the ancestors may still fit on their own, the descendants
+                // can't without the transaction
+                let txid = package[package.len() - 1];
+                debug!("Transaction {} doesn't fit in the block", txid);
+                let dropped = mempool::descendants(&txid, &remaining);
+                remaining.retain(|tx| !dropped.contains(&tx.id));
+                continue;
+            }
+
+            size += package_size;
+            for txid in &package {
+                let i = remaining.iter().position(|tx| tx.id == *txid).unwrap();
+                packed.push(remaining.remove(i));
+            }
+        }
+
+        Ok(packed)
+    }
+
+    /// Checks a block mined elsewhere and stores it, as the new tip if it
+    /// extends the tip or wins the fork choice. The outputs spent by a block
+    /// extending the tip are checked by `UTXOSet::submit_block`.
+    pub fn connect_block(&mut self, block: &Block) -> Result<ChainUpdate> {
+        self.validate_block(block)?;
+        let update = self.put_block(block)?;
+        self.flush()?;
+        Ok(update)
     }
 
     /// Adds block into the Blockchain.
     pub fn add_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
-        let lasthash = self.db.get("LAST")?.unwrap();
+        let (block, _) = self
+            .add_block_with(transactions, &Miner::default())?
+            .ok_or_else(|| format_err!("Mining was cancelled"))?;
+        Ok(block)
+    }
+
+    /// Mines a block of the transactions with the miner and adds it. Returns
+    /// the block and the work done, or `None` if the miner was cancelled.
+    pub fn add_block_with(
+        &mut self,
+        transactions: Vec<Transaction>,
+        miner: &Miner,
+    ) -> Result<Option<(Block, MiningStats)>> {
+        let height = self.get_best_height()? + 1;
+        let timestamp = self.next_timestamp()?;
+        self.validate_transactions(&transactions, height, timestamp / 1000)?;
 
-        let new_block = Block::new_block(
+        let engine = self.engine()?;
+        let mut new_block = Block::new_template(
             transactions,
-            String::from_utf8(lasthash.to_vec())?,
-            TARGET_HEXS,
+            self.current_hash,
+            height,
+            timestamp,
+            engine.as_ref(),
         )?;
-        self.db
-            .insert(new_block.get_hash(), bincode::serialize(&new_block)?)?;
-        self.db.insert("LAST", new_block.get_hash().as_bytes())?;
-        self.current_hash = new_block.get_hash();
-        Ok(new_block)
+        self.check_block_size(&new_block)?;
+        let stats = match engine.seal(&mut new_block, miner)? {
+            Some(stats) => stats,
+            None => return Ok(None),
+        };
+
+        self.put_block(&new_block)?;
+        Ok(Some((new_block, stats)))
+    }
+
+    /// Checks that the block extends a stored block with a header the engine
+    /// accepts and valid transactions. A block off the tip is checked against
+    /// the chain ending at its parent, the outputs it spends included.
+    fn validate_block(&self, block: &Block) -> Result<()> {
+        let header = block.get_header();
+        let hash = block.get_hash();
+
+        if self.headers.contains_key(hash)? {
+            return Err(format_err!("Block {} is already stored", hash));
+        }
+        if header.prev_block_hash != self.current_hash {
+            if !self.headers.contains_key(header.prev_block_hash)? {
+                return Err(format_err!("Block {} doesn't extend a known block", hash));
+            }
+            let branch = self.at(header.prev_block_hash);
+            branch.validate_block(block)?;
+            return branch.check_unspent(block);
+        }
+
+        let height = self.get_best_height()? + 1;
+
+        if header.prev_block_hash != self.current_hash || header.height != height {
+            return Err(format_err!(
+                "Block {} doesn't extend the tip {} at height {}",
+                hash,
+                self.current_hash,
+                height
+            ));
+        }
+
+        let engine = self.engine()?;
+        let mut expected = header.clone();
+        engine.prepare(&mut expected);
+        if header.version != expected.version {
+            return Err(format_err!(
+                "Block {} has version {}, {} is required",
+                hash,
+                header.version,
+                expected.version
+            ));
+        }
+        if header.target != expected.target {
+            return Err(format_err!(
+                "Block {} has target {}, {} is required",
+                hash,
+                header.target,
+                expected.target
+            ));
+        }
+        if hash != header.hash()? {
+            return Err(format_err!("Block {} doesn't match its header", hash));
+        }
+        engine.verify_header(header)?;
+        self.check_timestamp(&hash, header)?;
+        self.check_block_size(block)?;
+
+        let transactions = block.get_transactions();
+        if transactions.is_empty() {
+            return Err(format_err!("Block {} has no transactions", hash));
+        }
+        for tx in transactions {
+            if tx.id != tx.hash()? {
+                return Err(format_err!("Transaction {} doesn't match its hash", tx.id));
+            }
+        }
+        if header.merkle_root != block.hash_transactions()? {
+            return Err(format_err!("Block {} doesn't match its merkle root", hash));
+        }
+
+        let mut txids = HashSet::new();
+        let mut spent = HashSet::new();
+        for tx in transactions {
+            if !txids.insert(tx.id) {
+                return Err(format_err!("Block {} repeats transaction {}", hash, tx.id));
+            }
+            if tx.is_coinbase() {
+                continue;
+            }
+            for vin in &tx.vin {
+                if !spent.insert((vin.txid, vin.vout)) {
+                    return Err(format_err!(
+                        "Block {} spends output {}:{} twice",
+                        hash,
+                        vin.txid,
+                        vin.vout
+                    ));
+                }
+            }
+        }
+
+        self.validate_transactions(transactions, height, header.timestamp / 1000)
+    }
+
+    /// Checks that the block only spends unspent outputs or outputs of the
+    /// transactions before them in the block.
+    fn check_unspent(&self, block: &Block) -> Result<()> {
+        let utxos = self.find_utxo();
+        let mut created = HashSet::new();
+
+        for tx in block.get_transactions() {
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    let unspent = utxos
+                        .get(&vin.txid)
+                        .is_some_and(|outs| outs.outputs.contains_key(&vin.vout));
+                    if !unspent && !created.contains(&(vin.txid, vin.vout)) {
+                        return Err(format_err!(
+                            "Transaction {} spends output {}:{} which is spent or unknown",
+                            tx.id,
+                            vin.txid,
+                            vin.vout
+                        ));
+                    }
+                }
+            }
+            created.extend((0..tx.vout.len()).map(|vout| (tx.id, vout as i32)));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the encoded block has at most the maximum number of
+    /// bytes.
+    fn check_block_size(&self, block: &Block) -> Result<()> {
+        let size = block.encode().len();
+        if size > self.params.max_block_size {
+            return Err(format_err!(
+                "Block {} has {} bytes, at most {} are allowed",
+                block.get_hash(),
+                size,
+                self.params.max_block_size
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the time of the header is after the median time of the
+    /// previous blocks and not too far ahead of the clock.
+    fn check_timestamp(&self, hash: &BlockHash, header: &BlockHeader) -> Result<()> {
+        let median = self.median_time_past()?;
+        if header.timestamp <= median {
+            return Err(format_err!(
+                "Block {} has time {}, at or before the median time {} of the previous blocks",
+                hash,
+                header.timestamp,
+                median
+            ));
+        }
+
+        let drift = self.params.max_future_drift.saturating_mul(1000);
+        let latest = Utc::now()
+            .timestamp_millis()
+            .saturating_add(i64::try_from(drift).unwrap_or(i64::MAX));
+        if header.timestamp > latest {
+            return Err(format_err!(
+                "Block {} has time {}, more than {}s in the future",
+                hash,
+                header.timestamp,
+                self.params.max_future_drift
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the median time in milliseconds of the last
+    /// `MEDIAN_TIME_SPAN` blocks, or of all of them in a shorter chain.
+    pub fn median_time_past(&self) -> Result<i64> {
+        let mut times = self
+            .headers()
+            .take(MEDIAN_TIME_SPAN)
+            .map(|h| h.timestamp)
+            .collect::<Vec<_>>();
+        if times.is_empty() {
+            return Err(format_err!("Blockchain is empty"));
+        }
+
+        times.sort_unstable();
+        Ok(times[times.len() / 2])
+    }
+
+    /// Returns the time in milliseconds of a new block: the current time,
+    /// clamped to be after the median time of the previous blocks when the
+    /// clock is behind them.
+    fn next_timestamp(&self) -> Result<i64> {
+        let now = Utc::now().timestamp_millis();
+        let earliest = self.median_time_past()? + 1;
+        if now < earliest {
+            debug!(
+                "Clock is behind the median time of the last blocks, using {}",
+                earliest
+            );
+        }
+
+        Ok(now.max(earliest))
+    }
+
+    /// Checks the transactions of a block at the height and time. Only the
+    /// first transaction may be a coinbase, and it may pay at most the block
+    /// subsidy plus the fees of the other transactions.
+    fn validate_transactions(
+        &self,
+        transactions: &[Transaction],
+        height: usize,
+        time: i64,
+    ) -> Result<()> {
+        let immature = self.immature_coinbases(height);
+        let mut spent = HashSet::new();
+
+        for (i, tx) in transactions.iter().enumerate() {
+            if tx.is_coinbase() && i != 0 {
+                return Err(format_err!(
+                    "Coinbase must be the first transaction of a block"
+                ));
+            }
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    if !spent.insert((vin.txid, vin.vout)) {
+                        return Err(format_err!(
+                            "Output {}:{} is spent twice in the block",
+                            vin.txid,
+                            vin.vout
+                        ));
+                    }
+                }
+            }
+            // only the transactions before it in the block are unconfirmed
+            if !self.verify_transaction(tx, &transactions[..i])? {
+                return Err(format_err!("Invalid transaction {}", tx.id));
+            }
+            tx.check_data_outputs(self.params.max_data_size)?;
+            tx.check_size(self.params.max_tx_size)?;
+            self.check_final(tx, height, time)?;
+            check_mature(tx, &immature, height, self.params.coinbase_maturity)?;
+        }
+
+        if let Some(coinbase) = transactions.first().filter(|tx| tx.is_coinbase()) {
+            let reward = coinbase.output_value()?;
+            let allowed = self
+                .params
+                .subsidy(height)
+                .checked_add(self.transaction_fees(transactions)?)
+                .ok_or_else(|| format_err!("Block reward overflows"))?;
+
+            if reward > allowed {
+                return Err(format_err!(
+                    "Coinbase pays {} but at most {} is allowed at height {}",
+                    reward,
+                    allowed,
+                    height
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the transaction only spends coinbase outputs which are
+    /// mature in a block at the height.
+    pub fn check_maturity(&self, tx: &Transaction, height: usize) -> Result<()> {
+        check_mature(
+            tx,
+            &self.immature_coinbases(height),
+            height,
+            self.params.coinbase_maturity,
+        )
+    }
+
+    /// Returns the coinbase transactions whose outputs can't be spent yet by
+    /// a block at the height, with the heights they were mined at.
+    pub fn immature_coinbases(&self, height: usize) -> HashMap<Txid, usize> {
+        let mut coinbases = HashMap::new();
+
+        for block in self.iter() {
+            if block.get_height() + self.params.coinbase_maturity <= height {
+                break;
+            }
+            if let Some(tx) = block
+                .get_transactions()
+                .first()
+                .filter(|tx| tx.is_coinbase())
+            {
+                coinbases.insert(tx.id, block.get_height());
+            }
+        }
+
+        coinbases
+    }
+
+    /// Checks that the absolute and relative locks of the transaction allow
+    /// it to be mined in a block at the height and time in seconds.
+    pub fn check_final(&self, tx: &Transaction, height: usize, time: i64) -> Result<()> {
+        if tx.is_coinbase() {
+            return Ok(());
+        }
+
+        if !tx.is_final(height, time) {
+            let unit = if tx.lock_time < LOCKTIME_THRESHOLD {
+                "height"
+            } else {
+                "time"
+            };
+            return Err(format_err!(
+                "Transaction {} is locked until after {} {}",
+                tx.id,
+                unit,
+                tx.lock_time
+            ));
+        }
+
+        for vin in &tx.vin {
+            let lock = match vin.relative_lock() {
+                Some(lock) => lock,
+                None => continue,
+            };
+
+            // an unconfirmed output is mined with the transaction at the earliest
+            let (mined_height, mined_time) = match self.find_transaction_block(&vin.txid) {
+                Ok(block) => (block.get_height(), block.get_timestamp() / 1000),
+                Err(_) => (height, time),
+            };
+            let unlocked = match lock {
+                RelativeLock::Blocks(blocks) => mined_height + blocks as usize <= height,
+                RelativeLock::Seconds(seconds) => mined_time + seconds as i64 <= time,
+            };
+
+            if !unlocked {
+                return Err(format_err!(
+                    "Input {}:{} of transaction {} is locked for {}",
+                    vin.txid,
+                    vin.vout,
+                    tx.id,
+                    lock
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the total fee paid by the transactions, which may spend
+    /// outputs of each other.
+    pub fn transaction_fees(&self, transactions: &[Transaction]) -> Result<Amount> {
+        let mut fees = Vec::new();
+
+        for tx in transactions {
+            fees.push(self.transaction_fee(tx, transactions)?);
+        }
+
+        Amount::sum(fees)
+    }
+
+    /// Returns the fee paid by the transaction, which may spend outputs of
+    /// the unconfirmed transactions.
+    pub fn transaction_fee(&self, tx: &Transaction, unconfirmed: &[Transaction]) -> Result<Amount> {
+        if tx.is_coinbase() {
+            return Ok(Amount::ZERO);
+        }
+
+        tx.fee(&self.get_prev_txs(tx, unconfirmed)?)
+    }
+
+    /// Returns the hash of the last block.
+    pub fn get_last_hash(&self) -> BlockHash {
+        self.current_hash
+    }
+
+    /// Writes the pending changes of the db to disk.
+    pub(crate) fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Returns the height of the last block.
+    pub fn get_best_height(&self) -> Result<usize> {
+        let last = self
+            .headers()
+            .next()
+            .ok_or_else(|| format_err!("Blockchain is empty"))?;
+
+        Ok(last.height)
+    }
+
+    /// Returns the consensus rules of the Blockchain.
+    pub fn get_params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// Returns the consensus config the blocks are sealed with.
+    pub fn get_consensus(&self) -> &ConsensusConfig {
+        &self.consensus
+    }
+
+    /// Returns the consensus engine of the Blockchain.
+    pub fn engine(&self) -> Result<Box<dyn ConsensusEngine>> {
+        self.consensus.engine()
     }
 
     /// Finds and returns all unspent transaction outputs
-    pub fn find_utxo(&self) -> HashMap<String, TXOutputs> {
-        let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
-        let mut spend_txos: HashMap<String, Vec<i32>> = HashMap::new();
+    pub fn find_utxo(&self) -> HashMap<Txid, TXOutputs> {
+        let mut utxos: HashMap<Txid, TXOutputs> = HashMap::new();
+        let mut spend_txos: HashMap<Txid, Vec<i32>> = HashMap::new();
 
         for block in self.iter() {
-            for tx in block.get_transactions() {
+            // a transaction may spend outputs of those before it in the block
+            for tx in block.get_transactions().iter().rev() {
                 for index in 0..tx.vout.len() {
                     if let Some(ids) = spend_txos.get(&tx.id) {
                         if ids.contains(&(index as i32)) {
                             continue;
                         }
                     }
-
-                    match utxos.get_mut(&tx.id) {
-                        Some(v) => {
-                            v.outputs.push(tx.vout[index].clone());
-                        }
-                        None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs {
-                                    outputs: vec![tx.vout[index].clone()],
-                                },
-                            );
-                        }
+                    if tx.vout[index].script_pubkey.is_unspendable() {
+                        continue;
                     }
+
+                    utxos
+                        .entry(tx.id)
+                        .or_default()
+                        .outputs
+                        .insert(index as i32, tx.vout[index].clone());
                 }
 
                 if !tx.is_coinbase() {
@@ -131,7 +854,7 @@ impl Blockchain {
                                 v.push(i.vout);
                             }
                             None => {
-                                spend_txos.insert(i.txid.clone(), vec![i.vout]);
+                                spend_txos.insert(i.txid, vec![i.vout]);
                             }
                         }
                     }
@@ -143,18 +866,18 @@ impl Blockchain {
     }
 
     /// Returns Blockchain iterator.
-    pub fn iter(&self) -> BlockchainIterator {
+    pub fn iter(&self) -> BlockchainIterator<'_> {
         BlockchainIterator {
-            current_hash: self.current_hash.clone(),
+            current_hash: self.current_hash,
             bc: self,
         }
     }
 
     /// FindTransaction finds a transaction by its ID
-    pub fn find_transacton(&self, id: &str) -> Result<Transaction> {
+    pub fn find_transacton(&self, id: &Txid) -> Result<Transaction> {
         for b in self.iter() {
             for tx in b.get_transactions() {
-                if tx.id == id {
+                if tx.id == *id {
                     return Ok(tx.clone());
                 }
             }
@@ -163,12 +886,84 @@ impl Blockchain {
         Err(format_err!("Transaction is not found"))
     }
 
-    fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+    /// Returns the header of the block with the hash.
+    pub fn get_header(&self, hash: &BlockHash) -> Result<BlockHeader> {
+        match self.headers.get(hash)? {
+            Some(h) => BlockHeader::decode(&h),
+            None => Err(format_err!("Block {} is not found", hash)),
+        }
+    }
+
+    /// Returns an iterator over the block headers from the last block down
+    /// to the genesis block.
+    pub fn headers(&self) -> HeaderIterator<'_> {
+        HeaderIterator {
+            current_hash: self.current_hash,
+            bc: self,
+        }
+    }
+
+    /// Returns the filter of the block with the hash. Filters of blocks
+    /// added before filters were kept are built and stored on first use.
+    pub fn get_filter(&self, hash: &BlockHash) -> Result<BlockFilter> {
+        if let Some(f) = self.filters.get(hash)? {
+            return Ok(bincode::deserialize(&f)?);
+        }
+
+        let filter = BlockFilter::new(&self.get_block(hash)?)?;
+        self.filters.insert(hash, bincode::serialize(&filter)?)?;
+        Ok(filter)
+    }
+
+    /// Returns the block with the hash.
+    pub fn get_block(&self, hash: &BlockHash) -> Result<Block> {
+        match self.db.get(hash)? {
+            Some(b) => Block::decode(&b),
+            None => Err(format_err!("Block {} is not found", hash)),
+        }
+    }
+
+    /// Finds the first block with an output carrying the data.
+    pub fn find_data(&self, data: &[u8]) -> Option<Block> {
+        let mut found = None;
+
+        // the iterator walks from the tip, so keep the earliest block
+        for block in self.iter() {
+            let carries = block.get_transactions().iter().any(|tx| {
+                tx.vout
+                    .iter()
+                    .any(|out| out.script_pubkey.get_type() == ScriptType::Data(data.to_vec()))
+            });
+            if carries {
+                found = Some(block);
+            }
+        }
+
+        found
+    }
+
+    /// Finds the block holding the transaction.
+    pub fn find_transaction_block(&self, id: &Txid) -> Result<Block> {
+        self.iter()
+            .find(|b| b.get_transactions().iter().any(|tx| tx.id == *id))
+            .ok_or_else(|| format_err!("Transaction is not found"))
+    }
+
+    /// Returns the transactions whose outputs the transaction spends, looked
+    /// up in the unconfirmed transactions first and then in the chain.
+    fn get_prev_txs(
+        &self,
+        tx: &Transaction,
+        unconfirmed: &[Transaction],
+    ) -> Result<HashMap<Txid, Transaction>> {
         let mut prev_txs = HashMap::new();
 
         for vin in &tx.vin {
-            let prev_tx = self.find_transacton(&vin.txid)?;
-            prev_txs.insert(prev_tx.id.clone(), prev_tx);
+            let prev_tx = match unconfirmed.iter().find(|u| u.id == vin.txid) {
+                Some(u) => u.clone(),
+                None => self.find_transacton(&vin.txid)?,
+            };
+            prev_txs.insert(prev_tx.id, prev_tx);
         }
 
         Ok(prev_txs)
@@ -176,19 +971,99 @@ impl Blockchain {
 
     /// Signs inputs of a Transaction.
     pub fn sign_transacton(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
-        let prev_txs = self.get_prev_txs(tx)?;
+        let prev_txs = self.get_prev_txs(tx, &[])?;
         tx.sign(private_key, prev_txs)?;
         Ok(())
     }
+
+    /// Verifies signatures of the Transaction inputs, which may spend outputs
+    /// of the unconfirmed transactions.
+    pub fn verify_transaction(
+        &self,
+        tx: &Transaction,
+        unconfirmed: &[Transaction],
+    ) -> Result<bool> {
+        if tx.is_coinbase() {
+            return Ok(true);
+        }
+
+        let prev_txs = self.get_prev_txs(tx, unconfirmed)?;
+        tx.verify(prev_txs)
+    }
+}
+
+/// Returns the branches ending at the two blocks after the block they share,
+/// walking back from them with the headers the function looks up.
+pub(crate) fn branches<F>(
+    hash: BlockHash,
+    other: BlockHash,
+    get_header: F,
+) -> Result<(Branch, Branch)>
+where
+    F: Fn(&BlockHash) -> Result<BlockHeader>,
+{
+    let mut branch = vec![(hash, get_header(&hash)?)];
+    let mut other_branch = vec![(other, get_header(&other)?)];
+
+    loop {
+        let (hash, header) = &branch[branch.len() - 1];
+        let (other, other_header) = &other_branch[other_branch.len() - 1];
+        if hash == other {
+            break;
+        }
+
+        if header.height >= other_header.height {
+            let prev = header.prev_block_hash;
+            branch.push((prev, get_header(&prev)?));
+        } else {
+            let prev = other_header.prev_block_hash;
+            other_branch.push((prev, get_header(&prev)?));
+        }
+    }
+
+    branch.pop();
+    other_branch.pop();
+    branch.reverse();
+    other_branch.reverse();
+    Ok((branch, other_branch))
+}
+
+/// Returns the headers of the branch, which the fork choice compares.
+pub(crate) fn branch_headers(branch: &Branch) -> Vec<BlockHeader> {
+    branch.iter().map(|(_, h)| h.clone()).collect()
+}
+
+/// Checks that the transaction spends none of the immature coinbases, which
+/// were mined at the heights.
+fn check_mature(
+    tx: &Transaction,
+    immature: &HashMap<Txid, usize>,
+    height: usize,
+    maturity: usize,
+) -> Result<()> {
+    for vin in &tx.vin {
+        if let Some(mined) = immature.get(&vin.txid) {
+            return Err(format_err!(
+                "Transaction {} at height {} spends coinbase {} mined at height {}, which matures at height {}",
+                tx.id,
+                height,
+                vin.txid,
+                mined,
+                mined + maturity
+            ));
+        }
+    }
+
+    Ok(())
 }
 
 impl<'a> Iterator for BlockchainIterator<'a> {
     type Item = Block;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.bc.db.get(&self.current_hash).ok()? {
+        match self.bc.db.get(self.current_hash).ok()? {
             Some(b) => {
-                if let Ok(block) = bincode::deserialize::<Block>(&b) {
+                if let Ok(block) = Block::decode(&b) {
                     self.current_hash = block.get_prev_hash();
                     Some(block)
                 } else {
@@ -199,3 +1074,180 @@ impl<'a> Iterator for BlockchainIterator<'a> {
         }
     }
 }
+
+impl<'a> Iterator for HeaderIterator<'a> {
+    type Item = BlockHeader;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.bc.get_header(&self.current_hash).ok()?;
+        self.current_hash = header.prev_block_hash;
+        Some(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consensus::pow::ProofOfWork,
+        script::Script,
+        transaction::{
+            tx::{TXInput, SEQUENCE_FINAL},
+            TX_VERSION,
+        },
+    };
+
+    /// Returns a chain in a temporary database of unsealed blocks mined at
+    /// the times in seconds, each holding only its coinbase.
+    fn chain(times: &[i64]) -> Blockchain {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut bc = Blockchain {
+            current_hash: BlockHash::ZERO,
+            params: ChainParams::default(),
+            consensus: ConsensusConfig::default(),
+            headers: db.open_tree(HEADERS_TREE).unwrap(),
+            filters: db.open_tree(FILTERS_TREE).unwrap(),
+            db,
+        };
+
+        for (height, time) in times.iter().enumerate() {
+            let block = block(bc.current_hash, height, *time, "block");
+            bc.put_block(&block).unwrap();
+        }
+
+        bc
+    }
+
+    /// Returns an unsealed block extending the block, holding a coinbase
+    /// with the data.
+    fn block(prev_hash: BlockHash, height: usize, time: i64, data: &str) -> Block {
+        let address = Script::new_p2pkh(&[0x33; 20]).to_address().unwrap();
+        let coinbase = Transaction::new_coinbase(
+            address,
+            format!("{} {}", data, height),
+            ChainParams::default().subsidy(height),
+        )
+        .unwrap();
+
+        Block::new_template(vec![coinbase], prev_hash, height, time * 1000, &ProofOfWork).unwrap()
+    }
+
+    /// Returns the header of the tip with the time in milliseconds.
+    fn header_at(bc: &Blockchain, timestamp: i64) -> BlockHeader {
+        let mut header = bc.headers().next().unwrap();
+        header.timestamp = timestamp;
+        header
+    }
+
+    #[test]
+    fn median_time_of_the_last_blocks() {
+        // only the last 11 blocks count, the first ones are late
+        let mut times = vec![9_000, 9_000, 9_000];
+        times.extend(
+            [50, 10, 70, 30, 90, 20, 80, 40, 60, 100, 110]
+                .iter()
+                .map(|t| t * 10),
+        );
+        assert_eq!(chain(&times).median_time_past().unwrap(), 600_000);
+
+        assert_eq!(chain(&[100, 300, 200]).median_time_past().unwrap(), 200_000);
+        assert_eq!(chain(&[100, 200]).median_time_past().unwrap(), 200_000);
+    }
+
+    #[test]
+    fn block_time_after_the_median() {
+        let bc = chain(&[1_000, 1_060, 1_030]);
+        let median = bc.median_time_past().unwrap();
+        assert_eq!(median, 1_030_000);
+
+        for timestamp in [median - 1, median] {
+            let header = header_at(&bc, timestamp);
+            assert!(bc.check_timestamp(&BlockHash::ZERO, &header).is_err());
+        }
+        let header = header_at(&bc, median + 1);
+        bc.check_timestamp(&BlockHash::ZERO, &header).unwrap();
+    }
+
+    #[test]
+    fn block_time_within_the_future_drift() {
+        let bc = chain(&[1_000]);
+        let drift = bc.params.max_future_drift as i64 * 1000;
+        let now = Utc::now().timestamp_millis();
+
+        let header = header_at(&bc, now + drift - 60_000);
+        bc.check_timestamp(&BlockHash::ZERO, &header).unwrap();
+
+        let header = header_at(&bc, now + drift + 60_000);
+        let err = bc.check_timestamp(&BlockHash::ZERO, &header).unwrap_err();
+        assert!(err.to_string().contains("in the future"), "{}", err);
+    }
+
+    #[test]
+    fn coinbase_spent_once_mature() {
+        let mut bc = chain(&[1_000, 1_060, 1_120]);
+        bc.params.coinbase_maturity = 3;
+        let coinbases = bc
+            .iter()
+            .map(|b| (b.get_height(), b.get_transactions()[0].id))
+            .collect::<HashMap<_, _>>();
+
+        let spend = |txid: Txid| {
+            let mut tx = Transaction {
+                id: Txid::ZERO,
+                version: TX_VERSION,
+                vin: vec![TXInput {
+                    txid,
+                    vout: 0,
+                    script_sig: Script(Vec::new()),
+                    sequence: SEQUENCE_FINAL,
+                }],
+                vout: Vec::new(),
+                lock_time: 0,
+            };
+            tx.id = tx.hash().unwrap();
+            tx
+        };
+
+        // the coinbase of block 0 matures at height 3
+        let tx = spend(coinbases[&0]);
+        let err = bc.check_maturity(&tx, 2).unwrap_err();
+        assert!(err.to_string().contains("matures at height 3"), "{}", err);
+        bc.check_maturity(&tx, 3).unwrap();
+
+        let tx = spend(coinbases[&1]);
+        assert!(bc.check_maturity(&tx, 3).is_err());
+        bc.check_maturity(&tx, 4).unwrap();
+    }
+
+    #[test]
+    fn follows_the_heavier_branch() {
+        let mut bc = chain(&[1_000, 1_060, 1_120]);
+        let tip = bc.get_last_hash();
+        let fork = bc.get_header(&tip).unwrap().prev_block_hash;
+
+        // a branch of the same work is kept aside
+        let side = block(fork, 2, 1_130, "side");
+        assert!(matches!(
+            bc.put_block(&side).unwrap(),
+            ChainUpdate::SideBranch
+        ));
+        assert_eq!(bc.get_last_hash(), tip);
+
+        let side_tip = block(side.get_hash(), 3, 1_190, "side");
+        match bc.put_block(&side_tip).unwrap() {
+            ChainUpdate::Reorganized {
+                connected,
+                disconnected,
+            } => {
+                let hashes =
+                    |blocks: &[Block]| blocks.iter().map(|b| b.get_hash()).collect::<Vec<_>>();
+                assert_eq!(hashes(&connected), [side.get_hash(), side_tip.get_hash()]);
+                assert_eq!(hashes(&disconnected), [tip]);
+            }
+            update => panic!("Unexpected update {:?}", update),
+        }
+        assert_eq!(bc.get_last_hash(), side_tip.get_hash());
+        assert_eq!(bc.get_best_height().unwrap(), 3);
+        assert_eq!(bc.iter().nth(2).unwrap().get_hash(), fork);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{
+    amount::{self, Amount},
+    block::BlockHeader,
+    db,
+    encoding::Encode,
+    errors::Result,
+    hash::BlockHash,
+    mempool::Mempool,
+    transaction::utxoset::UTXOSet,
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, path::Path};
+
+/// Number of recent blocks the network hashrate is estimated from.
+pub const HASHRATE_WINDOW: usize = 10;
+
+/// ChainInfo summarizes the state of the chain and of the node.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainInfo {
+    /// Consensus the blocks are sealed with.
+    pub consensus: String,
+    pub tip: BlockHash,
+    pub height: usize,
+    /// Number of leading zero hex digits the hash of the tip has to have.
+    pub target: usize,
+    /// Hashes per second estimated from the time the recent blocks took,
+    /// unknown until two blocks were mined apart in time or when the blocks
+    /// aren't mined.
+    pub network_hashrate: Option<f64>,
+    /// Weight of the chain compared by the fork choice, the expected number
+    /// of hashes done to mine it under proof of work and its length under
+    /// proof of authority.
+    pub total_work: f64,
+    /// Number of unspent outputs.
+    pub utxo_count: usize,
+    #[serde(with = "amount::serde_coins")]
+    pub supply: Amount,
+    /// Size of the data dir in bytes.
+    pub data_dir_size: u64,
+    /// Number of pending transactions.
+    pub mempool_transactions: usize,
+    /// Size of the pending transactions in bytes.
+    pub mempool_bytes: usize,
+}
+
+impl ChainInfo {
+    /// Collects the info of the chain of the UTXO set and of the mempool.
+    pub fn new(utxo_set: &UTXOSet, mempool: &Mempool) -> Result<ChainInfo> {
+        let bc = &utxo_set.blockchain;
+        let headers = bc.headers().collect::<Vec<_>>();
+        let tip = headers.first();
+
+        let pending = mempool.get_all()?;
+        let consensus = bc.get_consensus();
+
+        Ok(ChainInfo {
+            consensus: consensus.to_string(),
+            tip: bc.get_last_hash(),
+            height: bc.get_best_height()?,
+            target: tip.map_or(0, |h| h.target),
+            network_hashrate: if consensus.is_proof_of_work() {
+                estimate_hashrate(&headers)
+            } else {
+                None
+            },
+            total_work: bc.engine()?.chain_weight(&headers),
+            utxo_count: utxo_set.count_outputs()?,
+            supply: utxo_set.get_total_value()?,
+            data_dir_size: dir_size(db::data_dir())?,
+            mempool_transactions: pending.len(),
+            mempool_bytes: pending.iter().map(|tx| tx.encode().len()).sum(),
+        })
+    }
+}
+
+impl fmt::Display for ChainInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Consensus: {}", self.consensus)?;
+        writeln!(f, "Tip: {}", self.tip)?;
+        writeln!(f, "Height: {}", self.height)?;
+        writeln!(f, "Target: {} leading zero hex digits", self.target)?;
+        match self.network_hashrate {
+            Some(hashrate) => writeln!(f, "Network hashrate: {:.0} H/s", hashrate)?,
+            None => writeln!(f, "Network hashrate: unknown")?,
+        }
+        writeln!(f, "Total work: {:.0}", self.total_work)?;
+        writeln!(f, "UTXOs: {}", self.utxo_count)?;
+        writeln!(f, "Supply: {}", self.supply)?;
+        writeln!(f, "Data dir size: {} bytes", self.data_dir_size)?;
+        write!(
+            f,
+            "Mempool: {} transactions, {} bytes",
+            self.mempool_transactions, self.mempool_bytes
+        )
+    }
+}
+
+/// Estimates the hashrate from the work of the last blocks of the headers,
+/// ordered from the tip down, and the time they took.
+fn estimate_hashrate(headers: &[BlockHeader]) -> Option<f64> {
+    let window = headers.get(..=HASHRATE_WINDOW).unwrap_or(headers);
+    let (newest, oldest) = (window.first()?, window.last()?);
+
+    let millis = newest.timestamp - oldest.timestamp;
+    if millis <= 0 {
+        return None;
+    }
+
+    // the oldest block was mined before the span started
+    let work: f64 = window[..window.len() - 1].iter().map(|h| h.work()).sum();
+    Some(work * 1_000.0 / millis as f64)
+}
+
+/// Returns the size of the files in the directory and its subdirectories.
+fn dir_size(path: &Path) -> Result<u64> {
+    if !path.exists() {
+        return Ok(0);
+    }
+
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+
+    Ok(size)
+}
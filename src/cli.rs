@@ -1,11 +1,49 @@
 use crate::{
+    amount::Amount,
+    block::{Block, BlockTemplate},
     blockchain::Blockchain,
+    chaininfo::ChainInfo,
+    consensus::{ConsensusConfig, ConsensusEngine},
+    db,
     errors::Result,
-    transaction::{utxoset::UTXOSet, Transaction},
-    wallet::Wallets,
+    fees::FeeEstimate,
+    hash::Txid,
+    mempool::{self, Mempool, MempoolPolicy, MAX_MEMPOOL_SIZE, MEMPOOL_EXPIRY},
+    merkle::{verify_merkle_proof, TxProof},
+    migration,
+    miner::{Miner, MiningStats},
+    params::{
+        ChainParams, COINBASE_MATURITY, HALVING_INTERVAL, MAX_BLOCK_SIZE, MAX_FUTURE_DRIFT,
+        MAX_SUPPLY, MAX_TX_SIZE,
+    },
+    script::{Htlc, Script, ScriptType, HTLC_PREIMAGE_SIZE},
+    server::{self, Request, Response, DEFAULT_NODE_ADDR},
+    spv::LightClient,
+    transaction::{
+        builder::{consolidate, parse_payment, read_payments, Payment, TransactionBuilder},
+        coinselect::CoinSelection,
+        htlc,
+        raw::RawTransaction,
+        tx::RelativeLock,
+        utxoset::UTXOSet,
+        LOCKTIME_THRESHOLD,
+    },
+    wallet::{Wallet, Wallets, SEED_SIZE},
+};
+use chrono::{TimeZone, Utc};
+use clap::{Args, Parser, Subcommand};
+use failure::format_err;
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
 };
-use bitcoincash_addr::Address;
-use clap::{arg, Parser, Subcommand};
+
+/// Time between checks of the tip while mining.
+const TIP_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 #[derive(Debug, Parser)]
 #[command(
@@ -14,6 +52,29 @@ use clap::{arg, Parser, Subcommand};
     author = "gavrilovivan2000@gmail.com"
 )]
 pub struct Cli {
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        help = "Directory holding the databases [default: data]"
+    )]
+    data_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "BYTES",
+        default_value_t = MAX_MEMPOOL_SIZE,
+        help = "Maximum size of the pending transactions, the lowest fee rates are evicted above it"
+    )]
+    mempool_max_size: usize,
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECONDS",
+        default_value_t = MEMPOOL_EXPIRY,
+        help = "Number of seconds after which a pending transaction is dropped"
+    )]
+    mempool_expiry: i64,
     #[command(subcommand)]
     command: Commands,
 }
@@ -21,11 +82,83 @@ pub struct Cli {
 #[derive(Debug, Subcommand)]
 enum Commands {
     #[command(about = "Print all the chain blocks")]
-    PrintChain,
+    PrintChain {
+        #[arg(long, help = "Print only the block headers")]
+        headers: bool,
+    },
     #[command(about = "Create a wallet")]
     CreateWallet,
+    #[command(about = "Print the seed the wallets are derived from, creating it if needed")]
+    CreateSeed,
+    #[command(about = "Restore the wallets derived from a seed")]
+    RestoreWallets {
+        #[arg(help = "Hex encoded seed")]
+        seed: String,
+        #[arg(long, default_value_t = 20, help = "Number of wallets to derive")]
+        count: u32,
+    },
     #[command(about = "List all addresses")]
     ListAddresses,
+    #[command(about = "Print the public key of a wallet")]
+    GetPubKey {
+        #[arg(help = "Address of the wallet")]
+        address: String,
+    },
+    #[command(about = "Create an address spendable by M of N keys")]
+    CreateMultisig {
+        #[arg(help = "Number of signatures required to spend")]
+        required: usize,
+        #[arg(
+            required = true,
+            help = "Hex encoded public keys or addresses of local wallets"
+        )]
+        keys: Vec<String>,
+    },
+    #[command(about = "Remember a redeem script so money sent to its address can be spent")]
+    ImportScript {
+        #[arg(help = "Hex encoded redeem script")]
+        script: String,
+    },
+    #[command(about = "Lock money in a hash time-locked contract")]
+    HtlcCreate {
+        #[arg(help = "Source wallet address, which can take the money back after the timeout")]
+        from: String,
+        #[arg(help = "Address of the wallet which can claim the money with the secret")]
+        recipient: String,
+        #[arg(help = "Amount of money to lock")]
+        amount: Amount,
+        #[arg(
+            long,
+            value_name = "BLOCKS",
+            help = "Number of blocks after which the sender can take the money back"
+        )]
+        timeout: u32,
+        #[arg(
+            long,
+            value_name = "HASH",
+            help = "Hex encoded SHA-256 hash of the secret, a new secret is made if not given"
+        )]
+        hash: Option<String>,
+    },
+    #[command(about = "Claim the money of an HTLC with its secret")]
+    HtlcClaim {
+        #[arg(help = "Address of the HTLC")]
+        address: String,
+        #[arg(help = "Hex encoded secret")]
+        secret: String,
+    },
+    #[command(about = "Take back the money of an expired HTLC")]
+    HtlcRefund {
+        #[arg(help = "Address of the HTLC")]
+        address: String,
+    },
+    #[command(about = "Print the secret revealed by the claim of an HTLC")]
+    HtlcSecret {
+        #[arg(help = "Address of the HTLC")]
+        address: String,
+    },
+    #[command(about = "Upgrade the databases from an older format")]
+    Migrate,
     #[command(about = "Reindex UTXO")]
     Reindex,
     #[command(about = "Get balance")]
@@ -37,52 +170,388 @@ enum Commands {
     Create {
         #[arg(help = "Address of the wallet")]
         address: String,
+        #[arg(long, default_value_t = HALVING_INTERVAL, help = "Number of blocks after which the block subsidy halves")]
+        halving_interval: usize,
+        #[arg(long, default_value_t = MAX_SUPPLY, help = "Maximum amount of money that can be issued")]
+        max_supply: Amount,
+        #[arg(long, value_name = "SECONDS", default_value_t = MAX_FUTURE_DRIFT, help = "Number of seconds a block time may be ahead of the clock")]
+        max_future_drift: u64,
+        #[arg(long, value_name = "BLOCKS", default_value_t = COINBASE_MATURITY, help = "Number of blocks a coinbase must be below a block spending it")]
+        coinbase_maturity: usize,
+        #[arg(long, value_name = "BYTES", default_value_t = MAX_BLOCK_SIZE, help = "Maximum size of an encoded block")]
+        max_block_size: usize,
+        #[arg(long, value_name = "BYTES", default_value_t = MAX_TX_SIZE, help = "Maximum size of an encoded transaction")]
+        max_tx_size: usize,
+        #[arg(
+            long = "validator",
+            value_name = "KEY",
+            help = "Hex encoded public key or address of a local wallet sealing blocks in turn, makes the chain use proof of authority"
+        )]
+        validators: Vec<String>,
     },
+    #[command(about = "Show how much money has been issued")]
+    Supply,
     #[command(about = "Send money to another account")]
     Send {
         #[arg(help = "Source wallet address")]
         from: String,
-        #[arg(help = "Destination wallet address")]
-        to: String,
-        #[arg(help = "Amount of money to be sent from source wallet to recipient wallet")]
-        amount: i32,
+        #[command(flatten)]
+        payments: PaymentArgs,
+    },
+    #[command(about = "Join small outputs of a wallet into a single output")]
+    Consolidate {
+        #[arg(help = "Address of the wallet")]
+        address: String,
+        #[arg(long, help = "Join only outputs holding at most this amount")]
+        threshold: Option<Amount>,
+    },
+    #[command(about = "Create an unsigned transaction to be signed offline")]
+    CreateRawTx {
+        #[arg(help = "Source wallet address")]
+        from: String,
+        #[command(flatten)]
+        payments: PaymentArgs,
+        #[arg(long, help = "Also spend outputs of pending transactions")]
+        spend_unconfirmed: bool,
+        #[arg(short, long, help = "File to write the transaction to")]
+        output: Option<String>,
+    },
+    #[command(about = "Sign a raw transaction or add multisig signatures with the local wallets")]
+    SignRawTx {
+        #[arg(help = "File with the raw transaction")]
+        file: String,
+        #[arg(short, long, help = "File to write the signed transaction to")]
+        output: Option<String>,
+        #[arg(long, help = "Sign without asking to confirm the outputs and fee")]
+        yes: bool,
+    },
+    #[command(about = "Validate a signed raw transaction and queue it")]
+    SubmitRawTx {
+        #[arg(help = "File with the signed raw transaction")]
+        file: String,
+        #[arg(
+            long,
+            value_name = "ADDRESS",
+            help = "Mine the pending transactions right away, rewarding the address"
+        )]
+        mine: Option<String>,
+    },
+    #[command(about = "Timestamp the hash of a file on the chain")]
+    Anchor {
+        #[arg(help = "File to anchor")]
+        file: PathBuf,
+        #[arg(long, help = "Wallet address paying for the anchoring")]
+        from: String,
+        #[arg(
+            long,
+            default_value_t,
+            help = "Fee paid to the miner of the transaction"
+        )]
+        fee: Amount,
+    },
+    #[command(about = "Find the block a file was anchored in")]
+    VerifyAnchor {
+        #[arg(help = "Anchored file")]
+        file: PathBuf,
+    },
+    #[command(about = "Prove that a transaction was mined in a block")]
+    GetTxProof {
+        #[arg(help = "ID of the transaction")]
+        txid: Txid,
+        #[arg(short, long, help = "File to write the proof to")]
+        output: Option<String>,
+    },
+    #[command(about = "Check a proof that a transaction was mined in a block")]
+    VerifyTxProof {
+        #[arg(help = "File with the proof")]
+        file: String,
+    },
+    #[command(about = "Serve headers and transaction proofs to light clients")]
+    Serve {
+        #[arg(long, default_value = DEFAULT_NODE_ADDR, help = "Address to listen on")]
+        listen: String,
+    },
+    #[command(about = "Sync headers and proven transactions of the wallets from a node")]
+    SpvSync {
+        #[arg(long, default_value = DEFAULT_NODE_ADDR, help = "Address of the node")]
+        node: String,
+    },
+    #[command(about = "Get the balance from the proven transactions")]
+    SpvBalance {
+        #[arg(help = "Address, all the wallets if not given")]
+        address: Option<String>,
+    },
+    #[command(about = "Show the state of the chain and of the node")]
+    ChainInfo {
+        #[arg(long, help = "Print the info as JSON")]
+        json: bool,
+        #[arg(long, help = "Ask the node at the address rather than the local data")]
+        node: Option<String>,
     },
+    #[command(about = "Suggest a fee rate from the recently mined blocks")]
+    EstimateFee {
+        #[arg(
+            default_value_t = 6,
+            help = "Number of blocks the transaction should be mined within"
+        )]
+        blocks: usize,
+        #[arg(long, help = "Print the estimate as JSON")]
+        json: bool,
+        #[arg(long, help = "Ask the node at the address rather than the local data")]
+        node: Option<String>,
+    },
+    #[command(about = "Create a block for an external miner")]
+    GetBlockTemplate {
+        #[arg(help = "Address receiving the block reward")]
+        address: String,
+        #[arg(short, long, help = "File to write the template to")]
+        output: Option<String>,
+        #[arg(long, help = "Ask the node at the address rather than the local data")]
+        node: Option<String>,
+    },
+    #[command(about = "Find the nonce of a block template")]
+    MineTemplate {
+        #[arg(help = "File with the block template")]
+        file: String,
+        #[arg(short, long, help = "File to write the mined template to")]
+        output: Option<String>,
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Number of mining threads, one per core if 0"
+        )]
+        threads: usize,
+    },
+    #[command(about = "Validate a mined block template and add it to the chain")]
+    SubmitBlock {
+        #[arg(help = "File with the mined block template")]
+        file: String,
+        #[arg(
+            long,
+            help = "Submit to the node at the address rather than the local data"
+        )]
+        node: Option<String>,
+    },
+    #[command(about = "Mine pending transactions into a new block")]
+    Mine {
+        #[arg(help = "Address receiving the block reward")]
+        address: String,
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Number of mining threads, one per core if 0"
+        )]
+        threads: usize,
+    },
+}
+
+#[derive(Debug, Args)]
+struct PaymentArgs {
+    #[arg(help = "Destination wallet address", requires = "amount")]
+    to: Option<String>,
+    #[arg(help = "Amount of money to be sent from source wallet to recipient wallet")]
+    amount: Option<Amount>,
+    #[arg(
+        long = "pay",
+        value_name = "ADDRESS:AMOUNT",
+        value_parser = parse_payment_arg,
+        help = "Another recipient of the payment, may be repeated"
+    )]
+    pay: Vec<Payment>,
+    #[arg(long, value_name = "FILE", help = "CSV or JSON file with recipients")]
+    pay_file: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t,
+        help = "Fee paid to the miner of the transaction"
+    )]
+    fee: Amount,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Strategy choosing the outputs to spend"
+    )]
+    coin_selection: CoinSelection,
+    #[arg(
+        long,
+        default_value_t,
+        help = "Block height, or unix time from 500000000 on, before which the transaction can't be mined"
+    )]
+    locktime: u32,
+    #[arg(
+        long,
+        value_name = "BLOCKS|SECONDSs",
+        help = "Number of blocks, or seconds with an 's' suffix, the spent outputs must be buried under"
+    )]
+    relative_lock: Option<RelativeLock>,
+}
+
+impl PaymentArgs {
+    /// Creates a builder of the transaction paying from the address.
+    fn into_builder(self, from: &str) -> Result<TransactionBuilder> {
+        let fee = self.fee;
+        let coin_selection = self.coin_selection;
+        let lock_time = self.locktime;
+        let relative_lock = self.relative_lock;
+
+        let payments = self.into_payments()?;
+        let wallets = Wallets::new()?;
+        for payment in &payments {
+            wallets.check_recipient(&payment.address)?;
+        }
+
+        Ok(TransactionBuilder::new(from)
+            .pay_all(payments)
+            .fee(fee)
+            .coin_selection(coin_selection)
+            .lock_time(lock_time)
+            .relative_lock(relative_lock))
+    }
+
+    /// Collects the payments from all the arguments.
+    fn into_payments(self) -> Result<Vec<Payment>> {
+        let mut payments = Vec::new();
+
+        if let (Some(address), Some(amount)) = (self.to, self.amount) {
+            payments.push(Payment { address, amount });
+        }
+        payments.extend(self.pay);
+        if let Some(path) = self.pay_file {
+            payments.extend(read_payments(&path)?);
+        }
+
+        Ok(payments)
+    }
+}
+
+fn parse_payment_arg(s: &str) -> std::result::Result<Payment, String> {
+    parse_payment(s).map_err(|e| e.to_string())
 }
 
 impl Cli {
     pub fn run() -> Result<()> {
         let cli = Cli::parse();
+        if let Some(dir) = &cli.data_dir {
+            db::set_data_dir(dir);
+        }
+        mempool::set_policy(MempoolPolicy {
+            max_size: cli.mempool_max_size,
+            expiry: cli.mempool_expiry,
+        });
 
         match cli.command {
-            Commands::PrintChain => cmd_print_chain(),
+            Commands::PrintChain { headers } => cmd_print_chain(headers),
             Commands::CreateWallet => {
                 println!("Wallet created with address {}", cmd_create_wallet()?);
                 Ok(())
             }
+            Commands::CreateSeed => cmd_create_seed(),
+            Commands::RestoreWallets { seed, count } => cmd_restore_wallets(&seed, count),
             Commands::ListAddresses => cmd_list_address(),
+            Commands::GetPubKey { address } => cmd_get_pub_key(&address),
+            Commands::CreateMultisig { required, keys } => cmd_create_multisig(required, &keys),
+            Commands::ImportScript { script } => cmd_import_script(&script),
+            Commands::HtlcCreate {
+                from,
+                recipient,
+                amount,
+                timeout,
+                hash,
+            } => cmd_htlc_create(&from, &recipient, amount, timeout, hash.as_deref()),
+            Commands::HtlcClaim { address, secret } => cmd_htlc_claim(&address, &secret),
+            Commands::HtlcRefund { address } => cmd_htlc_refund(&address),
+            Commands::HtlcSecret { address } => cmd_htlc_secret(&address),
+            Commands::Migrate => cmd_migrate(),
             Commands::Reindex => {
                 let count = cmd_reindex()?;
                 println!("Done! There are {} transactions in the UTXO set.", count);
                 Ok(())
             }
             Commands::GetBalance { address } => {
-                let balance = cmd_get_balance(&address)?;
+                let (balance, immature) = cmd_get_balance(&address)?;
                 println!("Balance of '{}'; {} ", &address, balance);
+                if !immature.is_zero() {
+                    println!("Immature: {} (mined coins not spendable yet)", immature);
+                }
                 Ok(())
             }
-            Commands::Create { address } => cmd_create_blockchain(&address),
-            Commands::Send { from, to, amount } => cmd_send(&from, &to, amount),
+            Commands::Create {
+                address,
+                halving_interval,
+                max_supply,
+                max_future_drift,
+                coinbase_maturity,
+                max_block_size,
+                max_tx_size,
+                validators,
+            } => {
+                let params = ChainParams {
+                    halving_interval,
+                    max_supply,
+                    max_future_drift,
+                    coinbase_maturity,
+                    max_block_size,
+                    max_tx_size,
+                    ..ChainParams::default()
+                };
+                cmd_create_blockchain(&address, params, &validators)
+            }
+            Commands::Supply => cmd_supply(),
+            Commands::Send { from, payments } => cmd_send(&from, payments.into_builder(&from)?),
+            Commands::Consolidate { address, threshold } => cmd_consolidate(&address, threshold),
+            Commands::CreateRawTx {
+                from,
+                payments,
+                spend_unconfirmed,
+                output,
+            } => cmd_create_raw_tx(
+                &from,
+                payments.into_builder(&from)?,
+                spend_unconfirmed,
+                output.as_deref(),
+            ),
+            Commands::SignRawTx { file, output, yes } => {
+                cmd_sign_raw_tx(&file, output.as_deref(), yes)
+            }
+            Commands::SubmitRawTx { file, mine } => cmd_submit_raw_tx(&file, mine.as_deref()),
+            Commands::Anchor { file, from, fee } => cmd_anchor(&file, &from, fee),
+            Commands::VerifyAnchor { file } => cmd_verify_anchor(&file),
+            Commands::GetTxProof { txid, output } => cmd_get_tx_proof(&txid, output.as_deref()),
+            Commands::VerifyTxProof { file } => cmd_verify_tx_proof(&file),
+            Commands::Serve { listen } => server::serve(&listen),
+            Commands::SpvSync { node } => cmd_spv_sync(&node),
+            Commands::SpvBalance { address } => cmd_spv_balance(address),
+            Commands::ChainInfo { json, node } => cmd_chain_info(json, node.as_deref()),
+            Commands::EstimateFee { blocks, json, node } => {
+                cmd_estimate_fee(blocks, json, node.as_deref())
+            }
+            Commands::GetBlockTemplate {
+                address,
+                output,
+                node,
+            } => cmd_get_block_template(&address, output.as_deref(), node.as_deref()),
+            Commands::MineTemplate {
+                file,
+                output,
+                threads,
+            } => cmd_mine_template(&file, output.as_deref(), threads),
+            Commands::SubmitBlock { file, node } => cmd_submit_block(&file, node.as_deref()),
+            Commands::Mine { address, threads } => cmd_mine(&address, threads),
         }
     }
 }
 
-fn cmd_send(from: &str, to: &str, amount: i32) -> Result<()> {
+fn cmd_send(from: &str, builder: TransactionBuilder) -> Result<()> {
     let bc = Blockchain::new()?;
     let mut utxo_set = UTXOSet { blockchain: bc };
     let wallets = Wallets::new()?;
-    let wallet = wallets.get_wallet(from).unwrap();
-    let tx = Transaction::new_utxo(wallet, to, amount, &utxo_set)?;
-    let new_block = utxo_set.blockchain.add_block(vec![tx])?;
+    let wallet = wallets
+        .get_wallet(from)
+        .ok_or_else(|| format_err!("Wallet '{}' is not found", from))?;
+    let mut raw = builder.build(&utxo_set)?;
+    raw.sign(wallet)?;
+    let new_block = utxo_set.blockchain.mine_block(vec![raw.tx], from)?;
 
     utxo_set.update(&new_block)?;
 
@@ -90,6 +559,413 @@ fn cmd_send(from: &str, to: &str, amount: i32) -> Result<()> {
     Ok(())
 }
 
+fn cmd_consolidate(address: &str, threshold: Option<Amount>) -> Result<()> {
+    let bc = Blockchain::new()?;
+    let mut utxo_set = UTXOSet { blockchain: bc };
+    let wallets = Wallets::new()?;
+    let wallet = wallets
+        .get_wallet(address)
+        .ok_or_else(|| format_err!("Wallet '{}' is not found", address))?;
+    let mut raw = consolidate(address, threshold, &utxo_set)?;
+    raw.sign(wallet)?;
+    let inputs = raw.tx.vin.len();
+    let new_block = utxo_set.blockchain.mine_block(vec![raw.tx], address)?;
+
+    utxo_set.update(&new_block)?;
+
+    println!("Consolidated {} outputs", inputs);
+    Ok(())
+}
+
+fn cmd_create_raw_tx(
+    from: &str,
+    mut builder: TransactionBuilder,
+    spend_unconfirmed: bool,
+    output: Option<&str>,
+) -> Result<()> {
+    let bc = Blockchain::new()?;
+    let utxo_set = UTXOSet { blockchain: bc };
+    if spend_unconfirmed {
+        let script_pubkey = Script::from_address(from)?;
+        builder = builder.unconfirmed(Mempool::new()?.find_unspent_outputs(&script_pubkey)?);
+    }
+    let mut raw = builder.build(&utxo_set)?;
+
+    // co-signers may not know the script of a multisig address
+    if let Some(script) = Wallets::new()?.get_script(from) {
+        raw.add_redeem_script(script)?;
+    }
+
+    write_output(&raw.encode()?, output)
+}
+
+fn cmd_sign_raw_tx(file: &str, output: Option<&str>, yes: bool) -> Result<()> {
+    let mut raw = RawTransaction::decode(&std::fs::read_to_string(file)?)?;
+    let wallets = Wallets::new()?;
+    let mut signed = 0;
+
+    // the transaction comes from elsewhere, show what it does before signing
+    for out in &raw.tx.vout {
+        match out.script_pubkey.to_address() {
+            Some(address) => eprintln!("Pays {} to {}", out.value, address),
+            None if out.script_pubkey.is_unspendable() => eprintln!("Carries data"),
+            None => eprintln!("Pays {} to a non-standard script", out.value),
+        }
+    }
+    eprintln!("Fee: {}", raw.fee()?);
+    if !yes && !confirm("Sign the transaction?")? {
+        return Err(format_err!("Signing was cancelled"));
+    }
+
+    for address in wallets.get_all_addresses() {
+        signed += raw.sign(wallets.get_wallet(&address).unwrap())?;
+    }
+
+    if signed == 0 {
+        return Err(format_err!("No local wallet owns the transaction inputs"));
+    }
+
+    write_output(&raw.encode()?, output)?;
+    eprintln!(
+        "added {} signatures, {} of {} inputs fully signed",
+        signed,
+        raw.signed_inputs(),
+        raw.tx.vin.len()
+    );
+    Ok(())
+}
+
+fn cmd_submit_raw_tx(file: &str, mine: Option<&str>) -> Result<()> {
+    let raw = RawTransaction::decode(&std::fs::read_to_string(file)?)?;
+    if !raw.is_signed() {
+        return Err(format_err!("Transaction is not fully signed"));
+    }
+
+    let bc = Blockchain::new()?;
+    let utxo_set = UTXOSet { blockchain: bc };
+    let mempool = Mempool::new()?;
+
+    mempool.check_transaction(&raw.tx, &utxo_set)?;
+    mempool.add(&raw.tx, &utxo_set.blockchain)?;
+    println!("Transaction {} queued", raw.tx.id);
+
+    if let Some(address) = mine {
+        drop(utxo_set);
+        drop(mempool);
+        cmd_mine(address, 0)?;
+    }
+
+    Ok(())
+}
+
+fn cmd_mine(address: &str, threads: usize) -> Result<()> {
+    let miner = Miner::new(threads);
+
+    // the databases are closed during the search, so a node serving the chain
+    // can accept blocks meanwhile
+    let (new_block, stats) = loop {
+        let (mut block, engine) = {
+            let utxo_set = UTXOSet {
+                blockchain: Blockchain::new()?,
+            };
+            let mempool = Mempool::new()?;
+            let txs = utxo_set
+                .blockchain
+                .pack_transactions(mempool.get_spendable(&utxo_set)?, address)?;
+            let block = utxo_set.blockchain.new_block_template(txs, address)?;
+            (block, utxo_set.blockchain.engine()?)
+        };
+
+        let stats = match seal_on_tip(&mut block, engine.as_ref(), &miner)? {
+            Some(stats) => stats,
+            None => {
+                println!("A new block extends the chain, mining again");
+                continue;
+            }
+        };
+
+        let mut utxo_set = UTXOSet {
+            blockchain: Blockchain::new()?,
+        };
+        if utxo_set.blockchain.get_last_hash() != block.get_prev_hash() {
+            println!("A new block extends the chain, mining again");
+            continue;
+        }
+        utxo_set.submit_block(&block)?;
+
+        let mempool = Mempool::new()?;
+        for tx in block.get_transactions() {
+            mempool.remove(&tx.id)?;
+        }
+        break (block, stats);
+    };
+
+    println!(
+        "Mined block {} with {} transactions",
+        new_block.get_hash(),
+        new_block.get_transactions().len()
+    );
+    // blocks sealed by a validator take no hashes
+    if stats.hashes > 0 {
+        println!(
+            "{} hashes in {:.2}s on {} threads, {:.0} H/s",
+            stats.hashes,
+            stats.elapsed.as_secs_f64(),
+            miner.get_threads(),
+            stats.hashrate()
+        );
+    }
+    Ok(())
+}
+
+/// Seals the block with the engine, cancelling the miner when another block
+/// extends the chain meanwhile. Returns the work done, or `None` if the
+/// search was cancelled.
+fn seal_on_tip(
+    block: &mut Block,
+    engine: &dyn ConsensusEngine,
+    miner: &Miner,
+) -> Result<Option<MiningStats>> {
+    let tip = block.get_prev_hash();
+    let sealed = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            while !sealed.load(Ordering::Relaxed) {
+                thread::sleep(TIP_POLL_INTERVAL);
+                // the DB is locked while a node handles a request, so a
+                // failed open is retried later
+                if let Ok(bc) = Blockchain::new() {
+                    if bc.get_last_hash() != tip {
+                        miner.cancel();
+                        break;
+                    }
+                }
+            }
+        });
+
+        let stats = engine.seal(block, miner);
+        sealed.store(true, Ordering::Relaxed);
+        stats
+    })
+}
+
+fn cmd_anchor(file: &Path, from: &str, fee: Amount) -> Result<()> {
+    let hash = Sha256::digest(std::fs::read(file)?);
+
+    cmd_send(from, TransactionBuilder::new(from).data(&hash).fee(fee))?;
+
+    println!(
+        "Anchored {} with hash {}",
+        file.display(),
+        hex::encode(hash)
+    );
+    Ok(())
+}
+
+fn cmd_verify_anchor(file: &Path) -> Result<()> {
+    let hash = Sha256::digest(std::fs::read(file)?);
+    let bc = Blockchain::new()?;
+    let block = bc
+        .find_data(&hash)
+        .ok_or_else(|| format_err!("{} is not anchored", file.display()))?;
+    let time = Utc
+        .timestamp_millis_opt(block.get_timestamp())
+        .single()
+        .ok_or_else(|| format_err!("Invalid block timestamp"))?;
+
+    println!("Hash: {}", hex::encode(hash));
+    println!("Block: {}", block.get_hash());
+    println!("Height: {}", block.get_height());
+    println!("Time: {}", time.to_rfc3339());
+    Ok(())
+}
+
+fn cmd_get_tx_proof(txid: &Txid, output: Option<&str>) -> Result<()> {
+    let bc = Blockchain::new()?;
+    let block = bc.find_transaction_block(txid)?;
+    let proof = block
+        .merkle_proof(txid)?
+        .ok_or_else(|| format_err!("Can't prove transaction {}", txid))?;
+
+    let tx_proof = TxProof {
+        block_hash: block.get_hash(),
+        txid: *txid,
+        proof,
+    };
+
+    write_output(&tx_proof.encode()?, output)
+}
+
+fn cmd_verify_tx_proof(file: &str) -> Result<()> {
+    let tx_proof = TxProof::decode(&std::fs::read_to_string(file)?)?;
+    let bc = Blockchain::new()?;
+    let header = bc.get_header(&tx_proof.block_hash)?;
+
+    if !verify_merkle_proof(&header.merkle_root, &tx_proof.txid, &tx_proof.proof)? {
+        return Err(format_err!(
+            "Proof of transaction {} doesn't match block {}",
+            tx_proof.txid,
+            tx_proof.block_hash
+        ));
+    }
+
+    println!(
+        "Transaction {} is in block {} at height {}",
+        tx_proof.txid, tx_proof.block_hash, header.height
+    );
+    Ok(())
+}
+
+fn cmd_spv_sync(node: &str) -> Result<()> {
+    let ws = Wallets::new()?;
+    let client = LightClient::new()?;
+    let report = client.sync(node, &ws.get_all_addresses())?;
+
+    println!(
+        "Synced {} headers and {} transactions from {} matching blocks",
+        report.headers, report.transactions, report.blocks
+    );
+    if let Some(height) = report.height {
+        println!("Height: {}", height);
+    }
+    Ok(())
+}
+
+fn cmd_spv_balance(address: Option<String>) -> Result<()> {
+    let client = LightClient::new()?;
+    let addresses = match address {
+        Some(address) => vec![address],
+        None => Wallets::new()?.get_all_addresses(),
+    };
+
+    for address in addresses {
+        println!("{}: {}", address, client.get_balance(&address)?);
+    }
+    Ok(())
+}
+
+fn cmd_chain_info(json: bool, node: Option<&str>) -> Result<()> {
+    let info = match node {
+        Some(node) => match server::send_request(node, &Request::GetChainInfo)? {
+            Response::ChainInfo(info) => info,
+            _ => return Err(format_err!("Unexpected response to GetChainInfo")),
+        },
+        None => {
+            let utxo_set = UTXOSet {
+                blockchain: Blockchain::new()?,
+            };
+            ChainInfo::new(&utxo_set, &Mempool::new()?)?
+        }
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+    } else {
+        println!("{}", info);
+    }
+    Ok(())
+}
+
+fn cmd_estimate_fee(blocks: usize, json: bool, node: Option<&str>) -> Result<()> {
+    let estimate = match node {
+        Some(node) => match server::send_request(node, &Request::EstimateFee { blocks })? {
+            Response::FeeEstimate(estimate) => estimate,
+            _ => return Err(format_err!("Unexpected response to EstimateFee")),
+        },
+        None => FeeEstimate::new(&Blockchain::new()?, blocks)?,
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&estimate)?);
+    } else {
+        println!("{}", estimate);
+    }
+    Ok(())
+}
+
+fn cmd_get_block_template(address: &str, output: Option<&str>, node: Option<&str>) -> Result<()> {
+    let request = Request::GetBlockTemplate {
+        address: address.to_string(),
+    };
+    let template = match node {
+        Some(node) => match server::send_request(node, &request)? {
+            Response::BlockTemplate(template) => template,
+            _ => return Err(format_err!("Unexpected response to GetBlockTemplate")),
+        },
+        None => {
+            let utxo_set = UTXOSet {
+                blockchain: Blockchain::new()?,
+            };
+            server::get_block_template(&utxo_set, &Mempool::new()?, address)?
+        }
+    };
+
+    write_output(&serde_json::to_string_pretty(&template)?, output)
+}
+
+fn cmd_mine_template(file: &str, output: Option<&str>, threads: usize) -> Result<()> {
+    let template: BlockTemplate = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+    let mut block = Block::from_template(template)?;
+
+    let miner = Miner::new(threads);
+    let stats = block
+        .mine(&miner)?
+        .ok_or_else(|| format_err!("Mining was cancelled"))?;
+    eprintln!(
+        "Mined block {} with {} hashes at {:.0} H/s",
+        block.get_hash(),
+        stats.hashes,
+        stats.hashrate()
+    );
+
+    write_output(
+        &serde_json::to_string_pretty(&BlockTemplate::from(block))?,
+        output,
+    )
+}
+
+fn cmd_submit_block(file: &str, node: Option<&str>) -> Result<()> {
+    let template: BlockTemplate = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+
+    let hash = match node {
+        Some(node) => {
+            match server::send_request(node, &Request::SubmitBlock { block: template })? {
+                Response::BlockAccepted(hash) => hash,
+                _ => return Err(format_err!("Unexpected response to SubmitBlock")),
+            }
+        }
+        None => {
+            let mut utxo_set = UTXOSet {
+                blockchain: Blockchain::new()?,
+            };
+            server::submit_block(&mut utxo_set, &Mempool::new()?, template)?
+        }
+    };
+
+    println!("Block {} added to the chain", hash);
+    Ok(())
+}
+
+/// Asks the question on the terminal and returns whether it was answered
+/// with yes.
+fn confirm(question: &str) -> Result<bool> {
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn write_output(data: &str, output: Option<&str>) -> Result<()> {
+    match output {
+        Some(path) => std::fs::write(path, data)?,
+        None => println!("{}", data),
+    }
+
+    Ok(())
+}
+
 fn cmd_create_wallet() -> Result<String> {
     let mut ws = Wallets::new()?;
     let address = ws.create_wallet();
@@ -98,6 +974,230 @@ fn cmd_create_wallet() -> Result<String> {
     Ok(address)
 }
 
+fn cmd_create_seed() -> Result<()> {
+    let mut ws = Wallets::new()?;
+    let seed = ws.get_or_create_seed();
+    ws.save_all()?;
+
+    println!("Seed: {}", hex::encode(seed));
+    Ok(())
+}
+
+fn cmd_restore_wallets(seed: &str, count: u32) -> Result<()> {
+    let seed = hex::decode(seed)?;
+    if seed.len() != SEED_SIZE {
+        return Err(format_err!("Seed must be {} bytes", SEED_SIZE));
+    }
+
+    let mut ws = Wallets::new()?;
+    let addresses = ws.restore_wallets(&seed, count);
+    ws.save_all()?;
+
+    for address in addresses {
+        println!("{}", address);
+    }
+    Ok(())
+}
+
+fn cmd_get_pub_key(address: &str) -> Result<()> {
+    let ws = Wallets::new()?;
+    let wallet = ws
+        .get_wallet(address)
+        .ok_or_else(|| format_err!("Wallet '{}' is not found", address))?;
+
+    println!("{}", hex::encode(&wallet.public_key));
+    Ok(())
+}
+
+fn cmd_create_multisig(required: usize, keys: &[String]) -> Result<()> {
+    let mut ws = Wallets::new()?;
+    let pub_keys = keys
+        .iter()
+        .map(|key| parse_pub_key(&ws, key))
+        .collect::<Result<Vec<_>>>()?;
+
+    let script = Script::new_multisig(required, &pub_keys)?;
+    let address = ws.add_script(script.clone())?;
+    ws.save_all()?;
+
+    println!("Multisig address {}", address);
+    println!("Redeem script: {}", script);
+    Ok(())
+}
+
+/// Returns the public key given in hex or by the address of a local wallet.
+fn parse_pub_key(ws: &Wallets, key: &str) -> Result<Vec<u8>> {
+    match ws.get_wallet(key) {
+        Some(wallet) => Ok(wallet.public_key.clone()),
+        None => hex::decode(key)
+            .ok()
+            .filter(|k| k.len() == 32)
+            .ok_or_else(|| format_err!("'{}' is neither a public key nor a local wallet", key)),
+    }
+}
+
+fn cmd_import_script(script: &str) -> Result<()> {
+    let script = Script::from_bytes(&hex::decode(script)?)?;
+    let mut ws = Wallets::new()?;
+    let address = ws.add_script(script.clone())?;
+    ws.save_all()?;
+
+    println!("Script address {}", address);
+    println!("Redeem script: {}", script);
+    Ok(())
+}
+
+fn cmd_htlc_create(
+    from: &str,
+    recipient: &str,
+    amount: Amount,
+    timeout: u32,
+    hash: Option<&str>,
+) -> Result<()> {
+    let mut ws = Wallets::new()?;
+    let sender_pub_key_hash = ws
+        .get_wallet(from)
+        .ok_or_else(|| format_err!("Wallet '{}' is not found", from))?
+        .get_pub_key_hash();
+    let recipient_pub_key_hash = match Script::from_address(recipient)?.get_type() {
+        ScriptType::PubKeyHash(hash) => hash,
+        _ => return Err(format_err!("HTLC recipient must be a wallet address")),
+    };
+
+    let mut secret = None;
+    let payment_hash = match hash {
+        Some(hash) => hex::decode(hash)
+            .ok()
+            .filter(|h| h.len() == 32)
+            .ok_or_else(|| format_err!("Invalid payment hash '{}'", hash))?,
+        None => {
+            let mut preimage = vec![0; HTLC_PREIMAGE_SIZE];
+            OsRng.fill_bytes(&mut preimage);
+            let hash = Sha256::digest(&preimage).to_vec();
+            secret = Some(preimage);
+            hash
+        }
+    };
+
+    let height = Blockchain::new()?.get_best_height()?;
+    // the timeout must stay a height, later lock times are unix times
+    let timeout = u32::try_from(height)?
+        .checked_add(timeout)
+        .filter(|t| *t < LOCKTIME_THRESHOLD)
+        .ok_or_else(|| format_err!("Timeout of {} blocks is too long", timeout))?;
+    let script = Script::new_htlc(&Htlc {
+        payment_hash,
+        recipient_pub_key_hash,
+        sender_pub_key_hash,
+        timeout,
+    });
+    let address = ws.add_script(script.clone())?;
+    ws.save_all()?;
+
+    cmd_send(from, TransactionBuilder::new(from).pay(&address, amount))?;
+
+    println!("HTLC address {}", address);
+    println!("Redeem script: {}", hex::encode(script.to_bytes()?));
+    if let Some(htlc) = script.get_htlc() {
+        println!("Payment hash: {}", hex::encode(htlc.payment_hash));
+    }
+    if let Some(secret) = secret {
+        println!("Secret: {}", hex::encode(secret));
+    }
+    println!("Refundable after height {}", timeout);
+    Ok(())
+}
+
+fn cmd_htlc_claim(address: &str, secret: &str) -> Result<()> {
+    let ws = Wallets::new()?;
+    let script = get_htlc_script(&ws, address)?;
+    let htlc = script.get_htlc().unwrap();
+    let wallet = get_htlc_wallet(&ws, &htlc.recipient_pub_key_hash)?;
+
+    let bc = Blockchain::new()?;
+    let mut utxo_set = UTXOSet { blockchain: bc };
+    let tx = htlc::claim(script, &hex::decode(secret)?, wallet, &utxo_set)?;
+    let new_block = utxo_set
+        .blockchain
+        .mine_block(vec![tx], &wallet.get_address())?;
+    utxo_set.update(&new_block)?;
+
+    println!("Claimed HTLC {} to {}", address, wallet.get_address());
+    Ok(())
+}
+
+fn cmd_htlc_refund(address: &str) -> Result<()> {
+    let ws = Wallets::new()?;
+    let script = get_htlc_script(&ws, address)?;
+    let htlc = script.get_htlc().unwrap();
+    let wallet = get_htlc_wallet(&ws, &htlc.sender_pub_key_hash)?;
+
+    let bc = Blockchain::new()?;
+    let mut utxo_set = UTXOSet { blockchain: bc };
+    let tx = htlc::refund(script, wallet, &utxo_set)?;
+    let new_block = utxo_set
+        .blockchain
+        .mine_block(vec![tx], &wallet.get_address())?;
+    utxo_set.update(&new_block)?;
+
+    println!("Refunded HTLC {} to {}", address, wallet.get_address());
+    Ok(())
+}
+
+fn cmd_htlc_secret(address: &str) -> Result<()> {
+    let ws = Wallets::new()?;
+    let script = get_htlc_script(&ws, address)?;
+    let bc = Blockchain::new()?;
+
+    match htlc::find_preimage(script, &bc)? {
+        Some(secret) => println!("{}", hex::encode(secret)),
+        None => return Err(format_err!("HTLC {} is not claimed yet", address)),
+    }
+
+    Ok(())
+}
+
+/// Returns the redeem script of a local HTLC address.
+fn get_htlc_script<'a>(ws: &'a Wallets, address: &str) -> Result<&'a Script> {
+    ws.get_script(address)
+        .filter(|s| s.get_htlc().is_some())
+        .ok_or_else(|| format_err!("HTLC '{}' is not found, import its script", address))
+}
+
+/// Returns the local wallet with the public key hash.
+fn get_htlc_wallet<'a>(ws: &'a Wallets, pub_key_hash: &[u8]) -> Result<&'a Wallet> {
+    let address = Script::new_p2pkh(pub_key_hash)
+        .to_address()
+        .ok_or_else(|| format_err!("Invalid public key hash"))?;
+
+    ws.get_wallet(&address)
+        .ok_or_else(|| format_err!("Wallet '{}' is not found", address))
+}
+
+fn cmd_migrate() -> Result<()> {
+    let report = migration::migrate()?;
+
+    match (report.blocks, report.legacy_path) {
+        (Some(blocks), Some(legacy_path)) => println!(
+            "Migrated {} blocks, the old database is kept in {}",
+            blocks,
+            legacy_path.display()
+        ),
+        _ => println!("Block database is up to date"),
+    }
+    if report.dropped_transactions > 0 {
+        println!(
+            "Dropped {} pending transactions, they must be signed again",
+            report.dropped_transactions
+        );
+    }
+    if report.light_client_reset {
+        println!("Light client data was cleared, run spv-sync again");
+    }
+
+    Ok(())
+}
+
 fn cmd_reindex() -> Result<i32> {
     let bc = Blockchain::new()?;
     let utxo_set = UTXOSet { blockchain: bc };
@@ -105,9 +1205,21 @@ fn cmd_reindex() -> Result<i32> {
     utxo_set.count_transactions()
 }
 
-fn cmd_create_blockchain(address: &str) -> Result<()> {
+fn cmd_create_blockchain(address: &str, params: ChainParams, validators: &[String]) -> Result<()> {
+    let consensus = if validators.is_empty() {
+        ConsensusConfig::ProofOfWork
+    } else {
+        let ws = Wallets::new()?;
+        ConsensusConfig::ProofOfAuthority {
+            validators: validators
+                .iter()
+                .map(|key| parse_pub_key(&ws, key))
+                .collect::<Result<_>>()?,
+        }
+    };
+
     let address = String::from(address);
-    let bc = Blockchain::create_blockchain(address)?;
+    let bc = Blockchain::create_blockchain(address, params, consensus)?;
 
     let utxo_set = UTXOSet { blockchain: bc };
     utxo_set.reindex()?;
@@ -115,25 +1227,40 @@ fn cmd_create_blockchain(address: &str) -> Result<()> {
     Ok(())
 }
 
-fn cmd_get_balance(address: &str) -> Result<i32> {
-    let pub_key_hash = Address::decode(address).unwrap().body;
+fn cmd_supply() -> Result<()> {
     let bc = Blockchain::new()?;
+    let height = bc.get_best_height()?;
+    let params = bc.get_params().clone();
     let utxo_set = UTXOSet { blockchain: bc };
-    let utxos = utxo_set.find_utxo(&pub_key_hash)?;
-    let mut balance = 0;
 
-    for out in utxos.outputs {
-        balance += out.value;
-    }
+    println!("Height: {}", height);
+    println!("Issued: {}", utxo_set.get_total_value()?);
+    println!("Next block subsidy: {}", params.subsidy(height + 1));
+    println!("Halving interval: {} blocks", params.halving_interval);
+    println!("Max supply: {}", params.max_supply);
+    Ok(())
+}
 
-    Ok(balance)
+/// Returns the spendable balance of the address and the value of its
+/// immature coinbase outputs.
+fn cmd_get_balance(address: &str) -> Result<(Amount, Amount)> {
+    let script_pubkey = Script::from_address(address)?;
+    let bc = Blockchain::new()?;
+    let utxo_set = UTXOSet { blockchain: bc };
+    utxo_set.get_balance(&script_pubkey)
 }
 
-fn cmd_print_chain() -> Result<()> {
+fn cmd_print_chain(headers: bool) -> Result<()> {
     let bc = Blockchain::new()?;
 
-    for b in bc.iter() {
-        println!("{:#?}", b);
+    if headers {
+        for h in bc.headers() {
+            println!("{} {:#?}", h.hash()?, h);
+        }
+    } else {
+        for b in bc.iter() {
+            println!("{:#?}", b);
+        }
     }
 
     Ok(())
@@ -144,6 +1271,9 @@ fn cmd_list_address() -> Result<()> {
     for ad in ws.get_all_addresses() {
         println!("{}", ad);
     }
+    for ad in ws.get_script_addresses() {
+        println!("{} (script)", ad);
+    }
 
     Ok(())
 }
@@ -0,0 +1,82 @@
+pub mod poa;
+pub mod pow;
+
+use crate::{
+    block::{Block, BlockHeader},
+    errors::Result,
+    miner::{Miner, MiningStats},
+};
+use poa::ProofOfAuthority;
+use pow::ProofOfWork;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// ConsensusEngine holds the rules deciding who may add a block and which
+/// chain is the best one: it fills and seals the headers of new blocks,
+/// verifies the seals of received headers and weighs chains for the fork
+/// choice.
+pub trait ConsensusEngine: fmt::Debug {
+    /// Sets the fields of a new header the engine decides, its version and
+    /// target, before the merkle root and the seal.
+    fn prepare(&self, header: &mut BlockHeader);
+
+    /// Seals the block so other nodes accept it. Returns the work done, or
+    /// `None` if the miner was cancelled.
+    fn seal(&self, block: &mut Block, miner: &Miner) -> Result<Option<MiningStats>>;
+
+    /// Checks the seal of the header.
+    fn verify_header(&self, header: &BlockHeader) -> Result<()>;
+
+    /// Returns the weight the header adds to its chain.
+    fn weight(&self, header: &BlockHeader) -> f64;
+
+    /// Returns the total weight of the headers of a chain.
+    fn chain_weight(&self, headers: &[BlockHeader]) -> f64 {
+        headers.iter().map(|h| self.weight(h)).sum()
+    }
+
+    /// Fork choice: whether the candidate branch should replace the current
+    /// one, which it does only with more weight. Both branches hold the
+    /// headers after the block they share, oldest first.
+    fn prefers(&self, candidate: &[BlockHeader], current: &[BlockHeader]) -> bool {
+        self.chain_weight(candidate) > self.chain_weight(current)
+    }
+}
+
+/// ConsensusConfig selects the engine of a chain. It is chosen when the
+/// blockchain is created and stored next to the blocks.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum ConsensusConfig {
+    #[default]
+    ProofOfWork,
+    /// Blocks are signed in turn by the validators, given by their ed25519
+    /// public keys.
+    ProofOfAuthority { validators: Vec<Vec<u8>> },
+}
+
+impl ConsensusConfig {
+    /// Returns the engine following the config.
+    pub fn engine(&self) -> Result<Box<dyn ConsensusEngine>> {
+        Ok(match self {
+            ConsensusConfig::ProofOfWork => Box::new(ProofOfWork),
+            ConsensusConfig::ProofOfAuthority { validators } => {
+                Box::new(ProofOfAuthority::new(validators.clone())?)
+            }
+        })
+    }
+
+    pub fn is_proof_of_work(&self) -> bool {
+        *self == ConsensusConfig::ProofOfWork
+    }
+}
+
+impl fmt::Display for ConsensusConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsensusConfig::ProofOfWork => write!(f, "proof of work"),
+            ConsensusConfig::ProofOfAuthority { validators } => {
+                write!(f, "proof of authority with {} validators", validators.len())
+            }
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use super::ConsensusEngine;
+use crate::{
+    block::{Block, BlockHeader, SEALED_BLOCK_VERSION},
+    errors::Result,
+    miner::{Miner, MiningStats},
+    wallet::Wallets,
+};
+use crypto::ed25519;
+use failure::format_err;
+use log::info;
+use std::{cmp::Ordering, collections::HashSet, time::Instant};
+
+/// Size of an ed25519 public key identifying a validator.
+const PUBLIC_KEY_SIZE: usize = 32;
+
+/// Size of an ed25519 signature, the seal of a block.
+const SIGNATURE_SIZE: usize = 64;
+
+/// ProofOfAuthority accepts blocks signed by the validators in rotation: the
+/// block at height `h` is sealed by validator `h % n`. Every block weighs the
+/// same, so the longest chain is the best one.
+#[derive(Debug, Clone)]
+pub struct ProofOfAuthority {
+    validators: Vec<Vec<u8>>,
+}
+
+impl ProofOfAuthority {
+    /// Creates the engine of the validators, given by their ed25519 public
+    /// keys in the order they take turns.
+    pub fn new(validators: Vec<Vec<u8>>) -> Result<ProofOfAuthority> {
+        if validators.is_empty() {
+            return Err(format_err!(
+                "Proof of authority needs at least one validator"
+            ));
+        }
+
+        let mut seen = HashSet::new();
+        for key in &validators {
+            if key.len() != PUBLIC_KEY_SIZE {
+                return Err(format_err!(
+                    "Validator key {} isn't an ed25519 public key",
+                    hex::encode(key)
+                ));
+            }
+            if !seen.insert(key) {
+                return Err(format_err!(
+                    "Validator {} is listed twice",
+                    hex::encode(key)
+                ));
+            }
+        }
+
+        Ok(ProofOfAuthority { validators })
+    }
+
+    /// Returns the public key of the validator sealing the block at the
+    /// height.
+    pub fn validator_at(&self, height: usize) -> &[u8] {
+        &self.validators[height % self.validators.len()]
+    }
+}
+
+impl ConsensusEngine for ProofOfAuthority {
+    /// Also reserves the space of the signature, so the size of the block
+    /// doesn't change when it is sealed.
+    fn prepare(&self, header: &mut BlockHeader) {
+        header.version = SEALED_BLOCK_VERSION;
+        header.target = 0;
+        header.seal = vec![0; SIGNATURE_SIZE];
+    }
+
+    /// Signs the block with the key of the validator in turn, which must be
+    /// one of the local wallets.
+    fn seal(&self, block: &mut Block, _miner: &Miner) -> Result<Option<MiningStats>> {
+        let start = Instant::now();
+        let height = block.get_height();
+        let validator = self.validator_at(height);
+
+        let wallets = Wallets::new()?;
+        let wallet = wallets.get_wallet_by_pub_key(validator).ok_or_else(|| {
+            format_err!(
+                "Validator {} seals the block at height {}, its key isn't in the wallets",
+                hex::encode(validator),
+                height
+            )
+        })?;
+
+        let seal_hash = block.get_header().seal_hash();
+        let signature = ed25519::signature(seal_hash.as_bytes(), &wallet.secret_key);
+        block.set_seal(signature.to_vec())?;
+        info!(
+            "Sealed block {} as validator {}",
+            block.get_hash(),
+            hex::encode(validator)
+        );
+
+        Ok(Some(MiningStats {
+            elapsed: start.elapsed(),
+            ..MiningStats::default()
+        }))
+    }
+
+    /// Checks that the version 3 header is signed by the validator in turn
+    /// at its height.
+    fn verify_header(&self, header: &BlockHeader) -> Result<()> {
+        let hash = header.hash()?;
+
+        if header.version != SEALED_BLOCK_VERSION || header.target != 0 {
+            return Err(format_err!(
+                "Block {} has version {} and target {}, it isn't a proof of authority block",
+                hash,
+                header.version,
+                header.target
+            ));
+        }
+
+        let validator = self.validator_at(header.height);
+        if header.seal.len() != SIGNATURE_SIZE
+            || !ed25519::verify(header.seal_hash().as_bytes(), validator, &header.seal)
+        {
+            return Err(format_err!(
+                "Block {} isn't sealed by validator {}, in turn at height {}",
+                hash,
+                hex::encode(validator),
+                header.height
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn weight(&self, _header: &BlockHeader) -> f64 {
+        1.0
+    }
+
+    /// Prefers the longer branch. The rotation has the same validators seal
+    /// branches of the same length, so the tie goes to the lower tip hash,
+    /// which every node picks alike.
+    fn prefers(&self, candidate: &[BlockHeader], current: &[BlockHeader]) -> bool {
+        let tip_hash = |branch: &[BlockHeader]| branch.last().and_then(|h| h.hash().ok());
+
+        match candidate.len().cmp(&current.len()) {
+            Ordering::Greater => true,
+            Ordering::Less => false,
+            Ordering::Equal => matches!(
+                (tip_hash(candidate), tip_hash(current)),
+                (Some(a), Some(b)) if a < b
+            ),
+        }
+    }
+}
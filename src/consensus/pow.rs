@@ -0,0 +1,48 @@
+use super::ConsensusEngine;
+use crate::{
+    block::{meets_target, Block, BlockHeader, BLOCK_VERSION, TARGET_HEXS},
+    errors::Result,
+    miner::{Miner, MiningStats},
+};
+use failure::format_err;
+
+/// ProofOfWork accepts blocks whose hash starts with `TARGET_HEXS` zero hex
+/// digits. The chain with the most expected hashes is the best one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProofOfWork;
+
+impl ConsensusEngine for ProofOfWork {
+    fn prepare(&self, header: &mut BlockHeader) {
+        header.version = BLOCK_VERSION;
+        header.target = TARGET_HEXS;
+    }
+
+    /// Mines the block, bumping the extra nonce of the coinbase when the
+    /// nonces run out.
+    fn seal(&self, block: &mut Block, miner: &Miner) -> Result<Option<MiningStats>> {
+        block.mine(miner)
+    }
+
+    /// Checks the hash of a version 1 or 2 header against the target.
+    fn verify_header(&self, header: &BlockHeader) -> Result<()> {
+        let hash = header.hash()?;
+
+        if header.version > BLOCK_VERSION || header.target != TARGET_HEXS {
+            return Err(format_err!(
+                "Block {} has version {} and target {}, it isn't a proof of work block",
+                hash,
+                header.version,
+                header.target
+            ));
+        }
+        if !meets_target(&hash, header.target) {
+            return Err(format_err!("Block {} has an invalid proof of work", hash));
+        }
+
+        Ok(())
+    }
+
+    fn weight(&self, header: &BlockHeader) -> f64 {
+        header.work()
+    }
+}
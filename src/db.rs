@@ -1,4 +1,59 @@
+use crate::errors::Result;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    thread,
+    time::Duration,
+};
+
+/// Directory holding the databases unless another one is set.
 pub const DB_PATH: &str = "data";
-pub const DB_UTXOS_PATH: &str = "data/utxos";
-pub const DB_BLOCKS_PATH: &str = "data/blocks";
-pub const DB_WALLETS_PATH: &str = "data/wallets";
+pub const DB_UTXOS: &str = "utxos";
+pub const DB_BLOCKS: &str = "blocks";
+pub const DB_WALLETS: &str = "wallets";
+pub const DB_MEMPOOL: &str = "mempool";
+pub const DB_SPV: &str = "spv";
+
+/// Number of times a database whose lock is held is opened again.
+const OPEN_RETRIES: u32 = 20;
+
+/// Time to wait before opening a database whose lock is held again.
+const OPEN_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Sets the directory holding the databases. Has no effect once a database
+/// path was taken.
+pub fn set_data_dir(dir: &Path) {
+    let _ = DATA_DIR.set(dir.to_path_buf());
+}
+
+/// Returns the directory holding the databases.
+pub fn data_dir() -> &'static Path {
+    DATA_DIR.get_or_init(|| PathBuf::from(DB_PATH))
+}
+
+/// Returns the path of the database.
+pub fn path(db: &str) -> PathBuf {
+    data_dir().join(db)
+}
+
+/// Opens the database. Sled releases the lock of a closed database in the
+/// background, so opening it again right away, or while another process
+/// briefly holds it, is retried for a moment.
+pub fn open(db: &str) -> Result<sled::Db> {
+    let mut retries = 0;
+
+    loop {
+        match sled::open(path(db)) {
+            Err(sled::Error::Io(e))
+                if e.kind() == io::ErrorKind::Other && retries < OPEN_RETRIES =>
+            {
+                retries += 1;
+                thread::sleep(OPEN_RETRY_DELAY);
+            }
+            result => return Ok(result?),
+        }
+    }
+}
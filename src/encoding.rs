@@ -0,0 +1,434 @@
+//! Canonical binary encoding of blocks and transactions, used both to store
+//! them and to hash them. Unlike the serde derived formats it only changes
+//! together with the version fields, so the hashes of existing blocks and
+//! transactions stay the same when the structs change.
+//!
+//! All integers are little-endian and of fixed width, `usize` values are
+//! written as `u64`. Hashes are their 32 bytes. Counts of sequences and
+//! lengths of byte strings are `u64` prefixes.
+//!
+//! Script: the number of ops, then each op as its `u32` tag followed by the
+//! pushed bytes for `PushData` (tag 0) or the `i64` for `Number` (tag 1).
+//! The other ops have no payload, their tags follow the order of [`Op`]
+//! (`Dup` = 2 to `Return` = 21).
+//!
+//! Transaction, whose id is the SHA-256 of the encoding:
+//!
+//! | field       | encoding                                              |
+//! |-------------|-------------------------------------------------------|
+//! | version     | `u32`                                                 |
+//! | inputs      | count, then txid, vout `i32`, script bytes, `u32` sequence |
+//! | outputs     | count, then value `u64`, script bytes                 |
+//! | lock_time   | `u32`                                                 |
+//!
+//! Block header, whose hash is the SHA-256 of the encoding: version `u32`,
+//! previous block hash, merkle root, timestamp `i64`, target `u64`, nonce
+//! `u32` in version 1 and `u64` from version 2, height `u64`. Version 3
+//! headers end with the seal bytes, the signature of a validator over the
+//! hash of the header without the seal.
+//!
+//! Block: the header followed by the count of transactions and each
+//! transaction.
+//!
+//! [`Op`]: crate::script::Op
+
+use crate::errors::Result;
+use failure::format_err;
+
+/// Encode writes a value in the canonical encoding.
+pub trait Encode {
+    fn encode_to(&self, out: &mut Vec<u8>);
+
+    /// Returns the canonical encoding of the value.
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_to(&mut out);
+        out
+    }
+}
+
+/// Decode reads a value from the canonical encoding.
+pub trait Decode: Sized {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Self>;
+
+    /// Decodes the value, which must span the whole data.
+    fn decode(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(data);
+        let value = Self::decode_from(&mut reader)?;
+        reader.finish()?;
+        Ok(value)
+    }
+}
+
+pub fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_i32(out: &mut Vec<u8>, value: i32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_i64(out: &mut Vec<u8>, value: i64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Writes the bytes prefixed with their length.
+pub fn write_bytes(out: &mut Vec<u8>, data: &[u8]) {
+    write_u64(out, data.len() as u64);
+    out.extend_from_slice(data);
+}
+
+/// Writes the values prefixed with their count.
+pub fn write_all<T: Encode>(out: &mut Vec<u8>, values: &[T]) {
+    write_u64(out, values.len() as u64);
+    for value in values {
+        value.encode_to(out);
+    }
+}
+
+/// Reader walks through encoded data.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data, position: 0 }
+    }
+
+    /// Reads the next bytes.
+    pub fn read_slice(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| format_err!("Encoded data is truncated"))?;
+
+        let slice = &self.data[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.read_slice(N)?);
+        Ok(array)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_usize(&mut self) -> Result<usize> {
+        let value = self.read_u64()?;
+        usize::try_from(value).map_err(|_| format_err!("Encoded value {} is too large", value))
+    }
+
+    pub fn read_hash(&mut self) -> Result<[u8; 32]> {
+        self.read_array()
+    }
+
+    /// Reads a count of items, which can't be more than the bytes left as
+    /// every item takes at least one byte.
+    pub fn read_count(&mut self) -> Result<usize> {
+        let count = self.read_usize()?;
+        if count > self.data.len() - self.position {
+            return Err(format_err!("Encoded count {} exceeds the data", count));
+        }
+        Ok(count)
+    }
+
+    /// Reads bytes prefixed with their length.
+    pub fn read_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_count()?;
+        self.read_slice(len)
+    }
+
+    /// Reads values prefixed with their count.
+    pub fn read_all<T: Decode>(&mut self) -> Result<Vec<T>> {
+        let count = self.read_count()?;
+        (0..count).map(|_| T::decode_from(self)).collect()
+    }
+
+    /// Checks that all the data was read.
+    pub fn finish(&self) -> Result<()> {
+        if self.position != self.data.len() {
+            return Err(format_err!(
+                "Encoded data has {} trailing bytes",
+                self.data.len() - self.position
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        amount::Amount,
+        block::BlockHeader,
+        hash::{BlockHash, MerkleRoot, Txid},
+        script::{Htlc, Op, Script},
+        transaction::{
+            tx::{TXInput, TXOutput, SEQUENCE_FINAL, SEQUENCE_LOCKTIME_ENABLED},
+            Transaction, TX_VERSION,
+        },
+    };
+
+    fn header() -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_block_hash: BlockHash::from_bytes([0x11; 32]),
+            merkle_root: MerkleRoot::from_bytes([0x22; 32]),
+            timestamp: 1_700_000_000_000,
+            target: 4,
+            nonce: 42,
+            height: 7,
+            seal: Vec::new(),
+        }
+    }
+
+    fn coinbase() -> Transaction {
+        let mut tx = Transaction {
+            id: Txid::ZERO,
+            version: TX_VERSION,
+            vin: vec![TXInput {
+                txid: Txid::ZERO,
+                vout: -1,
+                script_sig: Script(vec![Op::PushData(b"golden".to_vec())]),
+                sequence: SEQUENCE_FINAL,
+            }],
+            vout: vec![TXOutput {
+                value: Amount::from_coins(10),
+                script_pubkey: Script::new_p2pkh(&[0x33; 20]),
+            }],
+            lock_time: 0,
+        };
+        tx.id = tx.hash().unwrap();
+        tx
+    }
+
+    fn htlc_script() -> Script {
+        Script::new_htlc(&Htlc {
+            payment_hash: vec![0x44; 32],
+            recipient_pub_key_hash: vec![0x55; 20],
+            sender_pub_key_hash: vec![0x66; 20],
+            timeout: 500,
+        })
+    }
+
+    fn spend() -> Transaction {
+        let mut tx = Transaction {
+            id: Txid::ZERO,
+            version: TX_VERSION,
+            vin: vec![TXInput {
+                txid: Txid::from_bytes([0x77; 32]),
+                vout: 1,
+                script_sig: Script::new_p2pkh_unlock(&[0x88; 64], &[0x99; 32]),
+                sequence: SEQUENCE_LOCKTIME_ENABLED,
+            }],
+            vout: vec![
+                TXOutput {
+                    value: Amount::from_units(12_345),
+                    script_pubkey: Script::new_p2sh(&htlc_script().hash().unwrap()),
+                },
+                TXOutput {
+                    value: Amount::ZERO,
+                    script_pubkey: Script::new_data(b"memo"),
+                },
+            ],
+            lock_time: 500,
+        };
+        tx.id = tx.hash().unwrap();
+        tx
+    }
+
+    #[test]
+    fn header_encoding() {
+        assert_eq!(
+            hex::encode(header().encode()),
+            concat!(
+                "01000000",
+                "1111111111111111111111111111111111111111111111111111111111111111",
+                "2222222222222222222222222222222222222222222222222222222222222222",
+                "0068e5cf8b010000",
+                "0400000000000000",
+                "2a000000",
+                "0700000000000000",
+            )
+        );
+        assert_eq!(
+            header().hash().unwrap().to_string(),
+            "4B91660F8181487BE2BB9F05ABCBBD634460EE3B9FF992F7619950855C4A323E"
+        );
+    }
+
+    #[test]
+    fn header_encoding_64_bit_nonce() {
+        let header = BlockHeader {
+            version: 2,
+            nonce: 0x0102_0304_0506_0708,
+            ..header()
+        };
+
+        assert_eq!(
+            hex::encode(header.encode()),
+            concat!(
+                "02000000",
+                "1111111111111111111111111111111111111111111111111111111111111111",
+                "2222222222222222222222222222222222222222222222222222222222222222",
+                "0068e5cf8b010000",
+                "0400000000000000",
+                "0807060504030201",
+                "0700000000000000",
+            )
+        );
+        assert_eq!(
+            header.hash().unwrap().to_string(),
+            "2A9116CB42C2A630D07E4E1F2D57E9710B7C507DCB3D0DBAFF928838ABAEA360"
+        );
+    }
+
+    #[test]
+    fn sealed_header_encoding() {
+        let header = BlockHeader {
+            version: 3,
+            target: 0,
+            nonce: 0,
+            seal: vec![0xab; 4],
+            ..header()
+        };
+
+        assert_eq!(
+            hex::encode(header.encode()),
+            concat!(
+                "03000000",
+                "1111111111111111111111111111111111111111111111111111111111111111",
+                "2222222222222222222222222222222222222222222222222222222222222222",
+                "0068e5cf8b010000",
+                "0000000000000000",
+                "0000000000000000",
+                "0700000000000000",
+                "0400000000000000",
+                "abababab",
+            )
+        );
+        // the seal signs the header without itself
+        assert_eq!(
+            header.seal_hash(),
+            BlockHeader {
+                seal: Vec::new(),
+                ..header.clone()
+            }
+            .seal_hash()
+        );
+        assert_ne!(header.hash().unwrap(), header.seal_hash());
+        assert_eq!(BlockHeader::decode(&header.encode()).unwrap(), header);
+    }
+
+    #[test]
+    fn transaction_encoding() {
+        assert_eq!(
+            hex::encode(coinbase().encode()),
+            concat!(
+                // version
+                "01000000",
+                // one input
+                "0100000000000000",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "ffffffff",
+                // script of one push
+                "1a00000000000000",
+                "0100000000000000",
+                "00000000",
+                "0600000000000000",
+                "676f6c64656e",
+                "ffffffff",
+                // one output
+                "0100000000000000",
+                "00ca9a3b00000000",
+                // P2PKH script
+                "3800000000000000",
+                "0500000000000000",
+                "020000000900000000000000",
+                "1400000000000000",
+                "3333333333333333333333333333333333333333",
+                "070000000b000000",
+                // lock time
+                "00000000",
+            )
+        );
+        assert_eq!(
+            coinbase().id.to_string(),
+            "54E2732AB774FA493F6C8DBA829144DB34FEA04F8ABED643D09544995D60563A"
+        );
+        assert_eq!(
+            spend().id.to_string(),
+            "7245F66F045A8DFA220DD54BA3C5874653FA50FC366A2AA36F2CF027DC4A3CB2"
+        );
+    }
+
+    #[test]
+    fn script_encoding() {
+        // P2SH addresses hash the encoding, so it must not change
+        assert_eq!(
+            hex::encode(htlc_script().hash().unwrap()),
+            "2a8a710a6ead8e024719eb4a1ac61bacdfd44a2b"
+        );
+        assert_eq!(
+            htlc_script().to_p2sh_address().unwrap(),
+            "35Zx9gvq3xD6gmh7CFbqYu9xxwUM5fQkbP"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(BlockHeader::decode(&header().encode()).unwrap(), header());
+
+        for tx in [coinbase(), spend()] {
+            let decoded = Transaction::decode(&tx.encode()).unwrap();
+            assert_eq!(decoded.id, tx.id);
+            assert_eq!(decoded.encode(), tx.encode());
+        }
+
+        let script = htlc_script();
+        assert_eq!(
+            Script::from_bytes(&script.to_bytes().unwrap()).unwrap(),
+            script
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_data() {
+        let mut data = coinbase().encode();
+        data.push(0);
+        assert!(Transaction::decode(&data).is_err());
+        assert!(Transaction::decode(&data[..data.len() - 2]).is_err());
+
+        let mut data = coinbase().encode();
+        data[0] = 2;
+        assert!(Transaction::decode(&data).is_err());
+
+        let mut data = header().encode();
+        data[0] = 0;
+        assert!(BlockHeader::decode(&data).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use crate::{amount::Amount, blockchain::Blockchain, encoding::Encode, errors::Result};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Number of recent blocks fee rates are estimated from.
+pub const FEE_ESTIMATE_WINDOW: usize = 100;
+
+/// Size of a transaction spending one output to a recipient and change.
+pub const TYPICAL_TX_SIZE: usize = 344;
+
+/// Probability of being mined in time the estimate aims at.
+const CONFIDENCE: f64 = 0.95;
+
+/// FeeEstimate suggests the fee rate a transaction should pay to be mined
+/// within a number of blocks.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeeEstimate {
+    /// Number of blocks the transaction should be mined within.
+    pub blocks: usize,
+    /// Fee rate in base units per byte.
+    pub fee_rate: u64,
+    /// Number of recent blocks the estimate is based on.
+    pub sample_blocks: usize,
+}
+
+impl FeeEstimate {
+    /// Estimates the fee rate from the recently mined blocks. Each of them
+    /// took transactions paying at least the lowest rate it holds, or any
+    /// rate when it had room left. The estimate is the lowest rate which
+    /// got into enough of them to be mined within the blocks with a 95%
+    /// chance.
+    pub fn new(bc: &Blockchain, blocks: usize) -> Result<FeeEstimate> {
+        if blocks == 0 {
+            return Err(format_err!("Number of blocks must be positive"));
+        }
+
+        let mut needed = Vec::new();
+        for block in bc.iter().take(FEE_ESTIMATE_WINDOW) {
+            let txs = block.get_transactions();
+            let mut lowest: Option<(u64, usize)> = None;
+            for tx in txs.iter().filter(|tx| !tx.is_coinbase()) {
+                let rate = fee_rate(bc.transaction_fee(tx, txs)?, tx.encode().len());
+                let size = tx.encode().len();
+                lowest = Some(lowest.map_or((rate, size), |(r, s)| (r.min(rate), s.min(size))));
+            }
+
+            needed.push(match lowest {
+                Some((rate, size))
+                    if block.encode().len() + size > bc.get_params().max_block_size =>
+                {
+                    rate
+                }
+                _ => 0,
+            });
+        }
+        needed.sort_unstable();
+
+        let sample_blocks = needed.len();
+        let fee_rate = needed
+            .iter()
+            .enumerate()
+            .find(|(i, _)| {
+                let accepted = (i + 1) as f64 / sample_blocks as f64;
+                1.0 - (1.0 - accepted).powi(blocks as i32) >= CONFIDENCE
+            })
+            .map_or(0, |(_, rate)| *rate);
+
+        Ok(FeeEstimate {
+            blocks,
+            fee_rate,
+            sample_blocks,
+        })
+    }
+
+    /// Returns the fee a transaction of the size pays at the estimated rate.
+    pub fn fee(&self, size: usize) -> Amount {
+        Amount::from_units(self.fee_rate.saturating_mul(size as u64))
+    }
+}
+
+impl fmt::Display for FeeEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Fee rate: {} units per byte to be mined within {} blocks",
+            self.fee_rate, self.blocks
+        )?;
+        writeln!(
+            f,
+            "Fee of a {} byte transaction: {}",
+            TYPICAL_TX_SIZE,
+            self.fee(TYPICAL_TX_SIZE)
+        )?;
+        write!(f, "Based on {} recent blocks", self.sample_blocks)
+    }
+}
+
+/// Returns the fee per byte, rounded up.
+pub fn fee_rate(fee: Amount, size: usize) -> u64 {
+    let size = size.max(1) as u64;
+    fee.units().saturating_add(size - 1) / size
+}
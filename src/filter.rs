@@ -0,0 +1,217 @@
+use crate::{
+    block::Block,
+    errors::Result,
+    hash::BlockHash,
+    script::{Script, ScriptType},
+    transaction::tx::OutPoint,
+};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Number of low bits of each delta written as is by the Golomb-Rice coding.
+pub const FILTER_P: u8 = 19;
+
+/// Inverse of the false positive rate of a filter.
+pub const FILTER_M: u64 = 784_931;
+
+/// BlockFilter is a compact probabilistic set of the pub key hashes paid to
+/// and the outpoints spent by a block. A wallet can test it locally for its
+/// own elements and only fetch the blocks that match, so the node doesn't
+/// learn the addresses of the wallet. A match may be a false positive, a
+/// miss never is.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockFilter {
+    pub block_hash: BlockHash,
+    /// Number of elements in the set.
+    pub n: u32,
+    /// Golomb-Rice coded sorted hashes of the elements.
+    pub data: Vec<u8>,
+}
+
+impl BlockFilter {
+    /// Builds the filter of the block.
+    pub fn new(block: &Block) -> Result<BlockFilter> {
+        let mut elements = Vec::new();
+
+        for tx in block.get_transactions() {
+            for out in &tx.vout {
+                elements.extend(script_element(&out.script_pubkey));
+            }
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    elements.push(outpoint_element(&OutPoint {
+                        txid: vin.txid,
+                        vout: vin.vout,
+                    }));
+                }
+            }
+        }
+        elements.sort();
+        elements.dedup();
+
+        let block_hash = block.get_hash();
+        let n = u32::try_from(elements.len())
+            .map_err(|_| format_err!("Block has too many filter elements"))?;
+        let mut values = hashed_set(&block_hash, n, &elements);
+        values.sort_unstable();
+
+        let mut writer = BitWriter::default();
+        let mut last = 0;
+        for value in values {
+            let delta = value - last;
+            writer.write_unary(delta >> FILTER_P);
+            writer.write_bits(delta, FILTER_P);
+            last = value;
+        }
+
+        Ok(BlockFilter {
+            block_hash,
+            n,
+            data: writer.finish(),
+        })
+    }
+
+    /// Checks whether any of the elements may be in the set.
+    pub fn matches_any(&self, elements: &[Vec<u8>]) -> Result<bool> {
+        if self.n == 0 || elements.is_empty() {
+            return Ok(false);
+        }
+
+        let mut queries = hashed_set(&self.block_hash, self.n, elements);
+        queries.sort_unstable();
+        let mut queries = queries.into_iter().peekable();
+
+        let mut reader = BitReader::new(&self.data);
+        let mut value = 0;
+        for _ in 0..self.n {
+            let quotient = reader.read_unary()?;
+            value += (quotient << FILTER_P) + reader.read_bits(FILTER_P)?;
+
+            while let Some(query) = queries.peek() {
+                if *query == value {
+                    return Ok(true);
+                }
+                if *query > value {
+                    break;
+                }
+                queries.next();
+            }
+            if queries.peek().is_none() {
+                break;
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+/// Returns the filter element of an output script, i.e. the hash of the key
+/// or of the script it pays to.
+pub fn script_element(script: &Script) -> Option<Vec<u8>> {
+    match script.get_type() {
+        ScriptType::PubKeyHash(hash) | ScriptType::ScriptHash(hash) => Some(hash),
+        _ => None,
+    }
+}
+
+/// Returns the filter element of a spent output.
+pub fn outpoint_element(outpoint: &OutPoint) -> Vec<u8> {
+    let mut element = outpoint.txid.as_bytes().to_vec();
+    element.extend_from_slice(&outpoint.vout.to_le_bytes());
+    element
+}
+
+/// Maps the elements to values uniformly spread over the range of a set of
+/// n elements, keyed by the block so every block has other collisions.
+fn hashed_set(block_hash: &BlockHash, n: u32, elements: &[Vec<u8>]) -> Vec<u64> {
+    let range = n as u128 * FILTER_M as u128;
+
+    elements
+        .iter()
+        .map(|element| {
+            let mut hasher = Sha256::new();
+            hasher.update(block_hash);
+            hasher.update(element);
+            let digest = hasher.finalize();
+
+            let mut head = [0u8; 8];
+            head.copy_from_slice(&digest[..8]);
+            ((u64::from_le_bytes(head) as u128 * range) >> 64) as u64
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct BitWriter {
+    data: Vec<u8>,
+    used: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.data.push(0);
+        }
+        if bit {
+            *self.data.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    /// Writes the number as that many ones followed by a zero.
+    fn write_unary(&mut self, n: u64) {
+        for _ in 0..n {
+            self.write_bit(true);
+        }
+        self.write_bit(false);
+    }
+
+    /// Writes the low bits of the number, the highest first.
+    fn write_bits(&mut self, value: u64, bits: u8) {
+        for i in (0..bits).rev() {
+            self.write_bit(value >> i & 1 == 1);
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<bool> {
+        let byte = self
+            .data
+            .get(self.position / 8)
+            .ok_or_else(|| format_err!("Filter data is truncated"))?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Ok(bit)
+    }
+
+    fn read_unary(&mut self) -> Result<u64> {
+        let mut n = 0;
+        while self.read_bit()? {
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    fn read_bits(&mut self, bits: u8) -> Result<u64> {
+        let mut value = 0;
+        for _ in 0..bits {
+            value = value << 1 | self.read_bit()? as u64;
+        }
+        Ok(value)
+    }
+}
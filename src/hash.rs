@@ -0,0 +1,113 @@
+use crate::errors::Result;
+use failure::format_err;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::{fmt, str::FromStr};
+
+/// Size of a hash in bytes.
+pub const HASH_SIZE: usize = 32;
+
+/// Defines a SHA-256 hash type shown as upper case hex. It is serialized as
+/// raw bytes by binary formats and as hex by JSON.
+macro_rules! hash_type {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+        pub struct $name([u8; HASH_SIZE]);
+
+        impl $name {
+            /// The all zero hash, which refers to nothing.
+            pub const ZERO: $name = $name([0; HASH_SIZE]);
+
+            pub fn from_bytes(bytes: [u8; HASH_SIZE]) -> Self {
+                $name(bytes)
+            }
+
+            /// Reads the hash from a slice of exactly `HASH_SIZE` bytes.
+            pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+                let bytes = bytes.try_into().map_err(|_| {
+                    format_err!("{} must be {} bytes", stringify!($name), HASH_SIZE)
+                })?;
+                Ok($name(bytes))
+            }
+
+            /// Returns the SHA-256 hash of the data.
+            pub fn digest(data: &[u8]) -> Self {
+                $name(Sha256::digest(data).into())
+            }
+
+            pub fn as_bytes(&self) -> &[u8; HASH_SIZE] {
+                &self.0
+            }
+
+            pub fn is_zero(&self) -> bool {
+                *self == $name::ZERO
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", hex::encode_upper(self.0))
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", self)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = failure::Error;
+
+            fn from_str(s: &str) -> Result<Self> {
+                let bytes = hex::decode(s)
+                    .map_err(|_| format_err!("Invalid {} '{}'", stringify!($name), s))?;
+                $name::from_slice(&bytes)
+            }
+        }
+
+        impl AsRef<[u8]> for $name {
+            fn as_ref(&self) -> &[u8] {
+                &self.0
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+                if serializer.is_human_readable() {
+                    serializer.serialize_str(&self.to_string())
+                } else {
+                    self.0.serialize(serializer)
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+                if deserializer.is_human_readable() {
+                    let s = String::deserialize(deserializer)?;
+                    s.parse().map_err(de::Error::custom)
+                } else {
+                    Ok($name(<[u8; HASH_SIZE]>::deserialize(deserializer)?))
+                }
+            }
+        }
+    };
+}
+
+hash_type!(
+    /// BlockHash identifies a block by the hash of its header.
+    BlockHash
+);
+
+hash_type!(
+    /// Txid identifies a transaction by its hash.
+    Txid
+);
+
+hash_type!(
+    /// MerkleRoot is the root of the merkle tree of the transactions of a
+    /// block.
+    MerkleRoot
+);
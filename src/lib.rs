@@ -1,7 +1,22 @@
+pub mod amount;
 pub mod block;
 pub mod blockchain;
+pub mod chaininfo;
 pub mod cli;
+pub mod consensus;
 pub mod db;
+pub mod encoding;
 pub mod errors;
+pub mod fees;
+pub mod filter;
+pub mod hash;
+pub mod mempool;
+pub mod merkle;
+pub mod migration;
+pub mod miner;
+pub mod params;
+pub mod script;
+pub mod server;
+pub mod spv;
 pub mod transaction;
 pub mod wallet;
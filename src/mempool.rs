@@ -0,0 +1,504 @@
+use crate::{
+    amount::Amount,
+    blockchain::Blockchain,
+    db,
+    encoding::{Decode, Encode},
+    errors::Result,
+    hash::Txid,
+    script::Script,
+    transaction::{coinselect::UnspentOutput, utxoset::UTXOSet, Transaction},
+};
+use chrono::Utc;
+use failure::format_err;
+use log::info;
+use std::{collections::HashMap, sync::OnceLock};
+
+/// Maximum number of bytes of pending transactions by default.
+pub const MAX_MEMPOOL_SIZE: usize = 5_000_000;
+
+/// Number of seconds after which a pending transaction is dropped by default.
+pub const MEMPOOL_EXPIRY: i64 = 14 * 24 * 60 * 60;
+
+/// Tree of the db holding the time each transaction entered the pool.
+const TIMES_TREE: &str = "times";
+
+/// MempoolPolicy limits the pending transactions a node keeps. It isn't part
+/// of the consensus, so each node chooses its own.
+#[derive(Debug, Clone, Copy)]
+pub struct MempoolPolicy {
+    /// Maximum number of bytes of the pending transactions. Those with the
+    /// lowest fee rates are evicted above it.
+    pub max_size: usize,
+    /// Number of seconds after which a pending transaction is dropped.
+    pub expiry: i64,
+}
+
+impl Default for MempoolPolicy {
+    fn default() -> Self {
+        MempoolPolicy {
+            max_size: MAX_MEMPOOL_SIZE,
+            expiry: MEMPOOL_EXPIRY,
+        }
+    }
+}
+
+static POLICY: OnceLock<MempoolPolicy> = OnceLock::new();
+
+/// Sets the policy of the pending transactions. Has no effect once a pool
+/// was opened.
+pub fn set_policy(policy: MempoolPolicy) {
+    let _ = POLICY.set(policy);
+}
+
+/// Mempool keeps transactions that were submitted but are not mined yet.
+/// They may spend outputs of each other, forming chains of unconfirmed
+/// transactions.
+pub struct Mempool {
+    db: sled::Db,
+    times: sled::Tree,
+    policy: MempoolPolicy,
+}
+
+impl Mempool {
+    /// Opens the pending transactions db.
+    pub fn new() -> Result<Mempool> {
+        let db = db::open(db::DB_MEMPOOL)?;
+        let times = db.open_tree(TIMES_TREE)?;
+        let policy = *POLICY.get_or_init(MempoolPolicy::default);
+        Ok(Mempool { db, times, policy })
+    }
+
+    /// Checks that the transaction spends unspent outputs, or outputs of
+    /// pending transactions, which no other pending transaction spends, and
+    /// that its signatures are valid.
+    pub fn check_transaction(&self, tx: &Transaction, utxo_set: &UTXOSet) -> Result<()> {
+        if tx.is_coinbase() {
+            return Err(format_err!("Coinbase transaction can't be submitted"));
+        }
+
+        if self.db.contains_key(tx.id)? {
+            return Err(format_err!("Transaction {} is already pending", tx.id));
+        }
+
+        let pending = self.get_all()?;
+
+        for vin in &tx.vin {
+            let unspent = match pending.iter().find(|p| p.id == vin.txid) {
+                Some(parent) => usize::try_from(vin.vout)
+                    .ok()
+                    .and_then(|i| parent.vout.get(i))
+                    .is_some_and(|out| !out.script_pubkey.is_unspendable()),
+                None => utxo_set.find_output(&vin.txid, vin.vout)?.is_some(),
+            };
+            if !unspent {
+                return Err(format_err!("Output {}:{} is spent", vin.txid, vin.vout));
+            }
+
+            let double_spend = pending.iter().any(|p| {
+                p.vin
+                    .iter()
+                    .any(|i| i.txid == vin.txid && i.vout == vin.vout)
+            });
+            if double_spend {
+                return Err(format_err!(
+                    "Output {}:{} is spent by a pending transaction",
+                    vin.txid,
+                    vin.vout
+                ));
+            }
+        }
+
+        let bc = &utxo_set.blockchain;
+        if !bc.verify_transaction(tx, &pending)? {
+            return Err(format_err!("Invalid transaction signature"));
+        }
+
+        bc.transaction_fee(tx, &pending)?;
+        tx.check_data_outputs(bc.get_params().max_data_size)?;
+        tx.check_size(bc.get_params().max_tx_size)?;
+        let height = bc.get_best_height()? + 1;
+        bc.check_final(tx, height, Utc::now().timestamp())?;
+        bc.check_maturity(tx, height)?;
+
+        Ok(())
+    }
+
+    /// Adds the transaction to the pending ones, evicting those with the
+    /// lowest fee rates while the pool is too big. Fails if the transaction
+    /// itself is evicted.
+    pub fn add(&self, tx: &Transaction, bc: &Blockchain) -> Result<()> {
+        info!("add pending Transaction: {}", tx.id);
+
+        self.db.insert(tx.id, tx.encode())?;
+        self.times
+            .insert(tx.id, &Utc::now().timestamp().to_be_bytes())?;
+        self.trim(bc)?;
+        self.db.flush()?;
+
+        if !self.db.contains_key(tx.id)? {
+            return Err(format_err!(
+                "Pool is full, transaction {} pays too low a fee rate",
+                tx.id
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns all pending transactions.
+    pub fn get_all(&self) -> Result<Vec<Transaction>> {
+        let mut txs = Vec::new();
+
+        for kv in self.db.iter() {
+            let (_, v) = kv?;
+            txs.push(Transaction::decode(&v)?);
+        }
+
+        Ok(txs)
+    }
+
+    /// Returns the pending transactions which only spend unspent outputs or
+    /// outputs of each other, parents before children. The others conflict
+    /// with mined transactions and are removed, as are the expired ones.
+    pub fn get_spendable(&self, utxo_set: &UTXOSet) -> Result<Vec<Transaction>> {
+        self.expire()?;
+
+        let mut pending = self.get_all()?;
+        let mut txs: Vec<Transaction> = Vec::new();
+
+        // a child may come before its parent, so repeat while transactions
+        // become spendable
+        loop {
+            let spendable_before = txs.len();
+            let mut rest = Vec::new();
+            for tx in pending {
+                let mut spendable = true;
+                for vin in &tx.vin {
+                    spendable &= txs.iter().any(|p| p.id == vin.txid)
+                        || utxo_set.find_output(&vin.txid, vin.vout)?.is_some();
+                }
+
+                if spendable {
+                    txs.push(tx);
+                } else {
+                    rest.push(tx);
+                }
+            }
+
+            pending = rest;
+            if pending.is_empty() || txs.len() == spendable_before {
+                break;
+            }
+        }
+
+        for tx in pending {
+            info!("drop pending Transaction {} spending spent outputs", tx.id);
+            self.remove(&tx.id)?;
+        }
+
+        Ok(txs)
+    }
+
+    /// Returns the outputs of pending transactions locked with the script
+    /// which no other pending transaction spends.
+    pub fn find_unspent_outputs(&self, script_pubkey: &Script) -> Result<Vec<UnspentOutput>> {
+        let pending = self.get_all()?;
+        let mut outputs = Vec::new();
+
+        for tx in &pending {
+            for (vout, output) in tx.vout.iter().enumerate() {
+                let vout = vout as i32;
+                let spent = pending
+                    .iter()
+                    .any(|p| p.vin.iter().any(|i| i.txid == tx.id && i.vout == vout));
+
+                if output.is_locked_with(script_pubkey) && !spent {
+                    outputs.push(UnspentOutput {
+                        txid: tx.id,
+                        vout,
+                        output: output.clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(outputs)
+    }
+
+    /// Removes the transaction from the pending ones.
+    pub fn remove(&self, txid: &Txid) -> Result<()> {
+        self.db.remove(txid)?;
+        self.times.remove(txid)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Removes the transactions pending for longer than the expiry, and
+    /// their descendants.
+    fn expire(&self) -> Result<()> {
+        let now = Utc::now().timestamp();
+        let pending = self.get_all()?;
+
+        for tx in &pending {
+            let time = match self.times.get(tx.id)? {
+                Some(t) => i64::from_be_bytes(
+                    t.as_ref()
+                        .try_into()
+                        .map_err(|_| format_err!("Invalid time of transaction {}", tx.id))?,
+                ),
+                // pending since before times were kept
+                None => {
+                    self.times.insert(tx.id, &now.to_be_bytes())?;
+                    now
+                }
+            };
+
+            if time + self.policy.expiry <= now && self.db.contains_key(tx.id)? {
+                for txid in descendants(&tx.id, &pending) {
+                    info!("drop expired pending Transaction {}", txid);
+                    self.remove(&txid)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Evicts the transactions with the lowest fee rates, counting their
+    /// descendants which can't be mined without them, until the pool fits
+    /// in its maximum size.
+    fn trim(&self, bc: &Blockchain) -> Result<()> {
+        let pending = self.get_all()?;
+        let size: usize = pending.iter().map(|tx| tx.encode().len()).sum();
+        if size <= self.policy.max_size {
+            return Ok(());
+        }
+
+        let mut rated = HashMap::new();
+        for tx in &pending {
+            let fee = bc.transaction_fee(tx, &pending)?;
+            rated.insert(tx.id, (fee, tx.encode().len()));
+        }
+
+        for txid in evictions(&pending, &rated, self.policy.max_size)? {
+            info!("evict pending Transaction {} from the full pool", txid);
+            self.remove(&txid)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the pending transactions to evict so the others fit in the
+/// maximum size, given the fee and size of each. The lowest fee rates go
+/// first, counting the descendants of a transaction with it.
+fn evictions(
+    pending: &[Transaction],
+    rated: &HashMap<Txid, (Amount, usize)>,
+    max_size: usize,
+) -> Result<Vec<Txid>> {
+    let mut pending = pending.to_vec();
+    let mut size: usize = pending.iter().map(|tx| rated[&tx.id].1).sum();
+    let mut evictions = Vec::new();
+
+    while size > max_size {
+        let mut lowest: Option<(Amount, usize, Vec<Txid>)> = None;
+        for tx in &pending {
+            let evicted = descendants(&tx.id, &pending);
+            let fee = Amount::sum(evicted.iter().map(|id| rated[id].0))?;
+            let evicted_size = evicted.iter().map(|id| rated[id].1).sum();
+
+            let lower = lowest
+                .as_ref()
+                .map_or(true, |(f, s, _)| lower_rate((fee, evicted_size), (*f, *s)));
+            if lower {
+                lowest = Some((fee, evicted_size, evicted));
+            }
+        }
+
+        let (_, evicted_size, evicted) = match lowest {
+            Some(lowest) => lowest,
+            None => break,
+        };
+        pending.retain(|tx| !evicted.contains(&tx.id));
+        size -= evicted_size;
+        evictions.extend(evicted);
+    }
+
+    Ok(evictions)
+}
+
+/// Whether the first fee and size pay a lower fee rate than the second,
+/// compared without rounding.
+pub fn lower_rate((fee_a, size_a): (Amount, usize), (fee_b, size_b): (Amount, usize)) -> bool {
+    (fee_a.units() as u128) * (size_b as u128) < (fee_b.units() as u128) * (size_a as u128)
+}
+
+/// Returns the transaction and its ancestors among the unconfirmed
+/// transactions, parents before children.
+pub fn ancestors(tx: &Transaction, unconfirmed: &[Transaction]) -> Vec<Txid> {
+    let mut ids = Vec::new();
+    add_ancestors(tx, unconfirmed, &mut ids);
+    ids
+}
+
+fn add_ancestors(tx: &Transaction, unconfirmed: &[Transaction], ids: &mut Vec<Txid>) {
+    if ids.contains(&tx.id) {
+        return;
+    }
+    for vin in &tx.vin {
+        if let Some(parent) = unconfirmed.iter().find(|u| u.id == vin.txid) {
+            add_ancestors(parent, unconfirmed, ids);
+        }
+    }
+    ids.push(tx.id);
+}
+
+/// Returns the transaction and its descendants among the unconfirmed
+/// transactions.
+pub fn descendants(txid: &Txid, unconfirmed: &[Transaction]) -> Vec<Txid> {
+    let mut ids = vec![*txid];
+    let mut i = 0;
+
+    while i < ids.len() {
+        for tx in unconfirmed {
+            if !ids.contains(&tx.id) && tx.vin.iter().any(|vin| vin.txid == ids[i]) {
+                ids.push(tx.id);
+            }
+        }
+        i += 1;
+    }
+
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{
+        tx::{TXInput, TXOutput, SEQUENCE_FINAL},
+        TX_VERSION,
+    };
+
+    /// Returns a transaction with the id spending the first outputs of the
+    /// parents.
+    fn tx(id: u8, parents: &[u8]) -> Transaction {
+        let parents = parents
+            .iter()
+            .map(|p| Txid::from_bytes([*p; 32]))
+            .collect::<Vec<_>>();
+        spend(Txid::from_bytes([id; 32]), &parents)
+    }
+
+    fn spend(id: Txid, parents: &[Txid]) -> Transaction {
+        Transaction {
+            id,
+            version: TX_VERSION,
+            vin: parents
+                .iter()
+                .map(|txid| TXInput {
+                    txid: *txid,
+                    vout: 0,
+                    script_sig: Script(Vec::new()),
+                    sequence: SEQUENCE_FINAL,
+                })
+                .collect(),
+            vout: vec![TXOutput {
+                value: Amount::from_coins(1),
+                script_pubkey: Script::new_p2pkh(&id.as_bytes()[..20]),
+            }],
+            lock_time: 0,
+        }
+    }
+
+    /// Rates each transaction with its fee in units and a size of 100 bytes.
+    fn rate(fees: &[(u8, u64)]) -> HashMap<Txid, (Amount, usize)> {
+        fees.iter()
+            .map(|(id, fee)| (Txid::from_bytes([*id; 32]), (Amount::from_units(*fee), 100)))
+            .collect()
+    }
+
+    fn ids(ids: &[u8]) -> Vec<Txid> {
+        ids.iter().map(|id| Txid::from_bytes([*id; 32])).collect()
+    }
+
+    #[test]
+    fn evicts_lowest_fee_rates() {
+        let pending = [tx(1, &[]), tx(2, &[]), tx(3, &[])];
+        let rated = rate(&[(1, 1_000), (2, 500), (3, 2_000)]);
+
+        assert!(evictions(&pending, &rated, 300).unwrap().is_empty());
+        assert_eq!(evictions(&pending, &rated, 250).unwrap(), ids(&[2]));
+        assert_eq!(evictions(&pending, &rated, 150).unwrap(), ids(&[2, 1]));
+        assert_eq!(evictions(&pending, &rated, 0).unwrap(), ids(&[2, 1, 3]));
+    }
+
+    #[test]
+    fn evicts_descendants_with_their_parent() {
+        // a child paying for its parent keeps it, a child paying nothing
+        // goes alone
+        let pending = [tx(1, &[]), tx(2, &[1]), tx(3, &[]), tx(4, &[]), tx(5, &[4])];
+        let rated = rate(&[(1, 100), (2, 5_000), (3, 1_000), (4, 3_000), (5, 0)]);
+
+        assert_eq!(evictions(&pending, &rated, 400).unwrap(), ids(&[5]));
+        assert_eq!(evictions(&pending, &rated, 300).unwrap(), ids(&[5, 3]));
+
+        let rated = rate(&[(1, 100), (2, 100), (3, 1_000), (4, 3_000), (5, 0)]);
+        assert_eq!(evictions(&pending, &rated, 300).unwrap(), ids(&[5, 1, 2]));
+    }
+
+    #[test]
+    fn compares_fee_rates_exactly() {
+        let fee = Amount::from_units;
+
+        assert!(lower_rate((fee(1), 3), (fee(1), 2)));
+        assert!(!lower_rate((fee(1), 2), (fee(1), 3)));
+        assert!(!lower_rate((fee(2), 6), (fee(1), 3)));
+        assert!(lower_rate((fee(0), 1), (fee(1), usize::MAX)));
+        assert!(!lower_rate((Amount::MAX, 1), (Amount::MAX, 1)));
+    }
+
+    #[test]
+    fn expires_old_transactions_and_descendants() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mempool = Mempool {
+            times: db.open_tree(TIMES_TREE).unwrap(),
+            db,
+            policy: MempoolPolicy {
+                expiry: 60,
+                ..MempoolPolicy::default()
+            },
+        };
+
+        // the pool keys the transactions by their hashes
+        let hashed = |mut tx: Transaction| {
+            tx.id = tx.hash().unwrap();
+            tx
+        };
+        let parent = hashed(tx(1, &[]));
+        let child = hashed(spend(Txid::ZERO, &[parent.id]));
+        let recent = hashed(tx(3, &[]));
+        // pending since before times were kept, so it is timed from now
+        let untimed = hashed(tx(4, &[]));
+
+        let now = Utc::now().timestamp();
+        for (tx, time) in [(&parent, now - 60), (&child, now), (&recent, now - 30)] {
+            mempool.db.insert(tx.id, tx.encode()).unwrap();
+            mempool.times.insert(tx.id, &time.to_be_bytes()).unwrap();
+        }
+        mempool.db.insert(untimed.id, untimed.encode()).unwrap();
+
+        mempool.expire().unwrap();
+        let mut left = mempool
+            .get_all()
+            .unwrap()
+            .iter()
+            .map(|tx| tx.id)
+            .collect::<Vec<_>>();
+        left.sort();
+        let mut kept = vec![recent.id, untimed.id];
+        kept.sort();
+        assert_eq!(left, kept);
+        assert!(mempool.times.contains_key(untimed.id).unwrap());
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{
+    errors::Result,
+    hash::{BlockHash, MerkleRoot, Txid},
+};
+use failure::format_err;
+use merkle_cbt::merkle_tree::{Merge, CBMT};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// MergeTX hashes two nodes of the merkle tree of block transactions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MergeTX;
+
+/// MerkleProof is the path from a transaction to the merkle root of its
+/// block.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+    /// Index of the transaction node in the tree.
+    pub index: u32,
+    /// Hex encoded sibling hashes needed to rebuild the root.
+    pub lemmas: Vec<String>,
+}
+
+/// TxProof shows that a transaction was mined in a block, which can be
+/// checked against the block header alone.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxProof {
+    pub block_hash: BlockHash,
+    pub txid: Txid,
+    pub proof: MerkleProof,
+}
+
+/// Returns the merkle root of the transaction hashes.
+pub fn merkle_root(tx_hashes: &[Vec<u8>]) -> Vec<u8> {
+    CBMT::<Vec<u8>, MergeTX>::build_merkle_root(tx_hashes)
+}
+
+/// Builds the proof that the transaction hash at the index is one of the
+/// hashes.
+pub fn build_merkle_proof(tx_hashes: &[Vec<u8>], index: usize) -> Option<MerkleProof> {
+    let proof = CBMT::<Vec<u8>, MergeTX>::build_merkle_proof(tx_hashes, &[index as u32])?;
+
+    Some(MerkleProof {
+        index: proof.indices()[0],
+        lemmas: proof.lemmas().iter().map(hex::encode).collect(),
+    })
+}
+
+/// Checks that the proof leads from the transaction to the merkle root.
+pub fn verify_merkle_proof(root: &MerkleRoot, txid: &Txid, proof: &MerkleProof) -> Result<bool> {
+    let lemmas = proof
+        .lemmas
+        .iter()
+        .map(hex::decode)
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let proof = merkle_cbt::MerkleProof::<Vec<u8>, MergeTX>::new(vec![proof.index], lemmas);
+
+    Ok(proof.verify(&root.as_bytes().to_vec(), &[txid.as_bytes().to_vec()]))
+}
+
+impl TxProof {
+    /// Encodes the proof into a portable JSON document.
+    pub fn encode(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| e.into())
+    }
+
+    /// Decodes the proof from a JSON document.
+    pub fn decode(data: &str) -> Result<TxProof> {
+        serde_json::from_str(data).map_err(|e| format_err!("Invalid proof: {}", e))
+    }
+}
+
+impl Merge for MergeTX {
+    type Item = Vec<u8>;
+
+    fn merge(left: &Self::Item, right: &Self::Item) -> Self::Item {
+        let mut data = left.clone();
+        data.append(&mut right.clone());
+
+        let hasher = Sha256::new_with_prefix(&data);
+        let mut re = [0u8; 32];
+
+        re.copy_from_slice(&hasher.finalize());
+        re.to_vec()
+    }
+}
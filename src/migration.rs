@@ -0,0 +1,671 @@
+use crate::{
+    amount::Amount,
+    block::{Block, TARGET_HEXS},
+    blockchain::{Blockchain, BLOCKS_DB_VERSION},
+    consensus::ConsensusConfig,
+    db,
+    encoding::Decode,
+    errors::Result,
+    hash::{Txid, HASH_SIZE},
+    params::ChainParams,
+    script::{Op, Script},
+    transaction::{
+        tx::{TXInput, TXOutput, SEQUENCE_FINAL},
+        utxoset::UTXOSet,
+        Transaction, TX_VERSION,
+    },
+};
+use bincode::Options;
+use failure::format_err;
+use log::{debug, info};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf};
+
+/// Block of a version 1 DB, whose hashes are upper case hex strings. Only
+/// the fields needed to rebuild it are read, the others are kept for the
+/// layout. This is the last layout of version 1, the blocks of the earlier
+/// ones and of a version 2 DB are converted to it.
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct LegacyBlock {
+    header: LegacyHeader,
+    hash: String,
+    transactions: Vec<LegacyTransaction>,
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct LegacyHeader {
+    version: u32,
+    prev_block_hash: String,
+    merkle_root: String,
+    timestamp: i64,
+    target: usize,
+    nonce: i32,
+    height: usize,
+}
+
+#[derive(Deserialize)]
+struct LegacyTransaction {
+    id: String,
+    vin: Vec<LegacyTXInput>,
+    vout: Vec<TXOutput>,
+    lock_time: u32,
+}
+
+#[derive(Deserialize)]
+struct LegacyTXInput {
+    txid: String,
+    vout: i32,
+    script_sig: Script,
+    sequence: u32,
+}
+
+/// Block of a version 1 DB stored whole, before headers were split from the
+/// bodies. Its height may be wrong, the first format stored the target.
+#[derive(Serialize, Deserialize)]
+struct FlatBlock<T> {
+    timestamp: i64,
+    transactions: Vec<T>,
+    prev_block_hash: String,
+    hash: String,
+    nonce: i32,
+    height: usize,
+}
+
+/// Transaction of a version 1 DB locked with scripts, before lock times.
+#[derive(Serialize, Deserialize)]
+struct ScriptTransaction {
+    id: String,
+    vin: Vec<ScriptTXInput>,
+    vout: Vec<TXOutput>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScriptTXInput {
+    txid: String,
+    vout: i32,
+    script_sig: Script,
+}
+
+/// Transaction of a version 1 DB before scripts, whose inputs hold the
+/// signature and the public key and whose outputs are locked with the hash
+/// of a key. The first format counted values in whole coins.
+#[derive(Serialize, Deserialize)]
+struct KeyTransaction<V> {
+    id: String,
+    vin: Vec<KeyTXInput>,
+    vout: Vec<KeyTXOutput<V>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyTXInput {
+    txid: String,
+    vout: i32,
+    signature: Vec<u8>,
+    pub_key: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyTXOutput<V> {
+    value: V,
+    pub_key_hash: Vec<u8>,
+}
+
+/// Reads the blocks of a DB, from the genesis block to the last.
+type BlockReader = fn(&sled::Db) -> Result<Vec<LegacyBlock>>;
+
+/// Layout of the blocks of a version 1 DB.
+trait V1Block: DeserializeOwned {
+    fn into_legacy(self) -> Result<LegacyBlock>;
+}
+
+/// Layout of the transactions of a version 1 DB.
+trait V1Transaction: DeserializeOwned {
+    fn into_legacy(self) -> Result<LegacyTransaction>;
+}
+
+/// Value of an output of a version 1 DB.
+trait V1Value: DeserializeOwned {
+    fn into_amount(self) -> Result<Amount>;
+}
+
+impl V1Block for LegacyBlock {
+    fn into_legacy(self) -> Result<LegacyBlock> {
+        Ok(self)
+    }
+}
+
+impl<T: V1Transaction> V1Block for FlatBlock<T> {
+    fn into_legacy(self) -> Result<LegacyBlock> {
+        Ok(LegacyBlock {
+            header: LegacyHeader {
+                version: 1,
+                prev_block_hash: self.prev_block_hash,
+                merkle_root: String::new(),
+                timestamp: self.timestamp,
+                target: TARGET_HEXS,
+                nonce: self.nonce,
+                height: self.height,
+            },
+            hash: self.hash,
+            transactions: self
+                .transactions
+                .into_iter()
+                .map(V1Transaction::into_legacy)
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+impl V1Transaction for LegacyTransaction {
+    fn into_legacy(self) -> Result<LegacyTransaction> {
+        Ok(self)
+    }
+}
+
+impl V1Transaction for ScriptTransaction {
+    fn into_legacy(self) -> Result<LegacyTransaction> {
+        Ok(LegacyTransaction {
+            id: self.id,
+            vin: self
+                .vin
+                .into_iter()
+                .map(|vin| LegacyTXInput {
+                    txid: vin.txid,
+                    vout: vin.vout,
+                    script_sig: vin.script_sig,
+                    sequence: SEQUENCE_FINAL,
+                })
+                .collect(),
+            vout: self.vout,
+            lock_time: 0,
+        })
+    }
+}
+
+impl<V: V1Value> V1Transaction for KeyTransaction<V> {
+    fn into_legacy(self) -> Result<LegacyTransaction> {
+        // coinbase inputs held the coinbase data in place of the key
+        let coinbase = self.vin.len() == 1 && self.vin[0].txid.is_empty();
+
+        Ok(LegacyTransaction {
+            id: self.id,
+            vin: self
+                .vin
+                .into_iter()
+                .map(|vin| LegacyTXInput {
+                    txid: vin.txid,
+                    vout: vin.vout,
+                    script_sig: if coinbase {
+                        Script(vec![Op::PushData(vin.pub_key)])
+                    } else {
+                        Script::new_p2pkh_unlock(&vin.signature, &vin.pub_key)
+                    },
+                    sequence: SEQUENCE_FINAL,
+                })
+                .collect(),
+            vout: self
+                .vout
+                .into_iter()
+                .map(|out| {
+                    Ok(TXOutput {
+                        value: out.value.into_amount()?,
+                        script_pubkey: Script::new_p2pkh(&out.pub_key_hash),
+                    })
+                })
+                .collect::<Result<_>>()?,
+            lock_time: 0,
+        })
+    }
+}
+
+impl V1Value for i32 {
+    fn into_amount(self) -> Result<Amount> {
+        let coins =
+            u64::try_from(self).map_err(|_| format_err!("Output value {} is negative", self))?;
+        Ok(Amount::from_coins(coins))
+    }
+}
+
+impl V1Value for Amount {
+    fn into_amount(self) -> Result<Amount> {
+        Ok(self)
+    }
+}
+
+/// Block of a version 2 DB, stored with bincode and holding hashes as raw
+/// bytes.
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct V2Block {
+    header: V2Header,
+    hash: [u8; HASH_SIZE],
+    transactions: Vec<V2Transaction>,
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct V2Header {
+    version: u32,
+    prev_block_hash: [u8; HASH_SIZE],
+    merkle_root: [u8; HASH_SIZE],
+    timestamp: i64,
+    target: usize,
+    nonce: i32,
+    height: usize,
+}
+
+#[derive(Deserialize)]
+struct V2Transaction {
+    id: [u8; HASH_SIZE],
+    vin: Vec<V2TXInput>,
+    vout: Vec<TXOutput>,
+    lock_time: u32,
+}
+
+#[derive(Deserialize)]
+struct V2TXInput {
+    txid: [u8; HASH_SIZE],
+    vout: i32,
+    script_sig: Script,
+    sequence: u32,
+}
+
+impl From<V2Block> for LegacyBlock {
+    fn from(block: V2Block) -> LegacyBlock {
+        // version 1 left the spent txid of coinbase inputs empty
+        let hex_id = |id: [u8; HASH_SIZE]| {
+            if id == [0; HASH_SIZE] {
+                String::new()
+            } else {
+                hex::encode_upper(id)
+            }
+        };
+
+        LegacyBlock {
+            header: LegacyHeader {
+                version: block.header.version,
+                prev_block_hash: hex_id(block.header.prev_block_hash),
+                merkle_root: hex::encode_upper(block.header.merkle_root),
+                timestamp: block.header.timestamp,
+                target: block.header.target,
+                nonce: block.header.nonce,
+                height: block.header.height,
+            },
+            hash: hex::encode_upper(block.hash),
+            transactions: block
+                .transactions
+                .into_iter()
+                .map(|tx| LegacyTransaction {
+                    id: hex_id(tx.id),
+                    vin: tx
+                        .vin
+                        .into_iter()
+                        .map(|vin| LegacyTXInput {
+                            txid: hex_id(vin.txid),
+                            vout: vin.vout,
+                            script_sig: vin.script_sig,
+                            sequence: vin.sequence,
+                        })
+                        .collect(),
+                    vout: tx.vout,
+                    lock_time: tx.lock_time,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// MigrationReport tells what a migration changed.
+#[derive(Debug, Clone, Default)]
+pub struct MigrationReport {
+    /// Number of blocks rebuilt, `None` if the blocks DB was up to date.
+    pub blocks: Option<usize>,
+    /// Path the old blocks DB is kept at, if it was rebuilt.
+    pub legacy_path: Option<PathBuf>,
+    /// Number of pending transactions dropped.
+    pub dropped_transactions: usize,
+    /// Whether the light client DB was cleared to be synced again.
+    pub light_client_reset: bool,
+}
+
+/// Upgrades the DBs of the data dir from an older format: version 1 keyed
+/// blocks and transactions by hex strings, version 2 stored them with bincode
+/// rather than the canonical encoding. The UTXO set is rebuilt from the
+/// migrated blocks. Pending transactions were signed over the old encoding
+/// and are dropped, and a light client syncs again from scratch.
+pub fn migrate() -> Result<MigrationReport> {
+    let mut report = MigrationReport::default();
+
+    if let Some((blocks, legacy_path)) = migrate_blocks()? {
+        report.blocks = Some(blocks);
+        report.legacy_path = Some(legacy_path);
+
+        let utxo_set = UTXOSet {
+            blockchain: Blockchain::new()?,
+        };
+        utxo_set.reindex()?;
+    }
+
+    let mempool = db::open(db::DB_MEMPOOL)?;
+    for kv in mempool.iter() {
+        let (k, v) = kv?;
+        if k.len() != HASH_SIZE || Transaction::decode(&v).is_err() {
+            mempool.remove(k)?;
+            report.dropped_transactions += 1;
+        }
+    }
+    mempool.flush()?;
+    drop(mempool);
+
+    let spv = db::open(db::DB_SPV)?;
+    let spv_version = match spv.get("VERSION")? {
+        Some(v) => bincode::deserialize(&v)?,
+        None => 1,
+    };
+    if spv.get("TIP")?.is_some() && spv_version != BLOCKS_DB_VERSION {
+        for name in spv.tree_names() {
+            spv.open_tree(name)?.clear()?;
+        }
+        spv.flush()?;
+        report.light_client_reset = true;
+    }
+
+    Ok(report)
+}
+
+/// Returns the path the blocks DB of the version is kept at after a
+/// migration.
+fn legacy_path(version: u32) -> PathBuf {
+    let mut path = db::path(db::DB_BLOCKS).into_os_string();
+    path.push(format!(".v{}", version));
+    path.into()
+}
+
+/// Reads the blocks of a version 1 DB, from the genesis block to the last.
+/// Version 1 went through several layouts, which are tried from the latest
+/// to the first: split headers, whole blocks with lock times, scripts, then
+/// amounts, and the first format counting whole coins.
+fn read_v1_blocks(db: &sled::Db) -> Result<Vec<LegacyBlock>> {
+    let layouts: [BlockReader; 5] = [
+        read_hex_blocks::<LegacyBlock>,
+        read_hex_blocks::<FlatBlock<LegacyTransaction>>,
+        read_hex_blocks::<FlatBlock<ScriptTransaction>>,
+        read_hex_blocks::<FlatBlock<KeyTransaction<Amount>>>,
+        read_hex_blocks::<FlatBlock<KeyTransaction<i32>>>,
+    ];
+
+    for read in layouts {
+        match read(db) {
+            Ok(blocks) => return Ok(blocks),
+            Err(e) => debug!("blocks don't have the layout: {}", e),
+        }
+    }
+
+    Err(format_err!("Block database has no known version 1 layout"))
+}
+
+/// Reads the blocks of a version 1 DB stored with the layout, from the
+/// genesis block to the last. Fails unless every block decodes without bytes
+/// left and has the hash it is stored at.
+fn read_hex_blocks<B: V1Block>(db: &sled::Db) -> Result<Vec<LegacyBlock>> {
+    let mut hash = match db.get("LAST")? {
+        Some(l) => String::from_utf8(l.to_vec())?,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut blocks = Vec::new();
+    while !hash.is_empty() {
+        let block = match db.get(&hash)? {
+            Some(b) => bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .reject_trailing_bytes()
+                .deserialize::<B>(&b)?
+                .into_legacy()?,
+            None => return Err(format_err!("Block {} is not found", hash)),
+        };
+        if block.hash != hash {
+            return Err(format_err!("Block {} has hash {}", hash, block.hash));
+        }
+        hash = block.header.prev_block_hash.clone();
+        blocks.push(block);
+    }
+    blocks.reverse();
+
+    Ok(blocks)
+}
+
+/// Reads the blocks of a version 2 DB, from the genesis block to the last.
+fn read_v2_blocks(db: &sled::Db) -> Result<Vec<LegacyBlock>> {
+    let mut hash = match db.get("LAST")? {
+        Some(l) => l.to_vec(),
+        None => return Ok(Vec::new()),
+    };
+
+    let mut blocks = Vec::new();
+    while hash != [0; HASH_SIZE] {
+        let block: V2Block = match db.get(&hash)? {
+            Some(b) => bincode::deserialize(&b)?,
+            None => {
+                return Err(format_err!(
+                    "Block {} is not found",
+                    hex::encode_upper(&hash)
+                ))
+            }
+        };
+        hash = block.header.prev_block_hash.to_vec();
+        blocks.push(block.into());
+    }
+    blocks.reverse();
+
+    Ok(blocks)
+}
+
+/// Upgrades a blocks DB of an older version. Every block is rebuilt in the
+/// current format: transactions get new ids hashing their canonical
+/// encoding, inputs refer to the new ids, and the blocks are mined again
+/// keeping their time. The old DB is kept at its `legacy_path`. Signatures
+/// of the migrated transactions were made over the old encoding and aren't
+/// checked, the migration trusts the chain it upgrades. Returns the number of
+/// blocks migrated and where the old DB is kept, or `None` if the DB doesn't
+/// need a migration.
+fn migrate_blocks() -> Result<Option<(usize, PathBuf)>> {
+    let db = db::open(db::DB_BLOCKS)?;
+    let version = match db.get("VERSION")? {
+        Some(v) => bincode::deserialize(&v)?,
+        None => 1,
+    };
+    if version == BLOCKS_DB_VERSION || db.get("LAST")?.is_none() {
+        return Ok(None);
+    }
+    let params: ChainParams = match db.get("PARAMS")? {
+        Some(p) => ChainParams::from_bytes(&p)?,
+        None => ChainParams::default(),
+    };
+
+    let blocks = match version {
+        1 => read_v1_blocks(&db)?,
+        2 => read_v2_blocks(&db)?,
+        _ => {
+            return Err(format_err!(
+                "Block database has unknown format version {}",
+                version
+            ))
+        }
+    };
+    drop(db);
+
+    let legacy_path = legacy_path(version);
+    if legacy_path.exists() {
+        return Err(format_err!(
+            "{} already exists, remove it to migrate again",
+            legacy_path.display()
+        ));
+    }
+    std::fs::rename(db::path(db::DB_BLOCKS), &legacy_path)?;
+
+    let mut bc = Blockchain::create_empty(params, ConsensusConfig::ProofOfWork)?;
+    let mut txids = HashMap::new();
+
+    // the first format stored the target as the height of every block
+    for (height, legacy) in blocks.iter().enumerate() {
+        let mut transactions = Vec::with_capacity(legacy.transactions.len());
+
+        for ltx in &legacy.transactions {
+            let vin = ltx
+                .vin
+                .iter()
+                .map(|lvin| {
+                    let txid = if lvin.txid.is_empty() {
+                        Txid::ZERO
+                    } else {
+                        *txids
+                            .get(&lvin.txid)
+                            .ok_or_else(|| format_err!("Transaction {} is not found", lvin.txid))?
+                    };
+
+                    Ok(TXInput {
+                        txid,
+                        vout: lvin.vout,
+                        script_sig: lvin.script_sig.clone(),
+                        sequence: lvin.sequence,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            let mut tx = Transaction {
+                id: Txid::ZERO,
+                version: TX_VERSION,
+                vin,
+                vout: ltx.vout.clone(),
+                lock_time: ltx.lock_time,
+            };
+            tx.id = tx.hash()?;
+            txids.insert(ltx.id.clone(), tx.id);
+            transactions.push(tx);
+        }
+
+        let block = Block::new_block_at(
+            transactions,
+            bc.get_last_hash(),
+            height,
+            legacy.header.timestamp,
+        )?;
+        info!(
+            "migrated block {} at height {}",
+            block.get_hash(),
+            block.get_height()
+        );
+        bc.put_block(&block)?;
+    }
+
+    bc.flush()?;
+    Ok(Some((blocks.len(), legacy_path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn baseline_tx(
+        id: &str,
+        vin: Vec<KeyTXInput>,
+        vout: Vec<(i32, Vec<u8>)>,
+    ) -> KeyTransaction<i32> {
+        KeyTransaction {
+            id: id.to_string(),
+            vin,
+            vout: vout
+                .into_iter()
+                .map(|(value, pub_key_hash)| KeyTXOutput {
+                    value,
+                    pub_key_hash,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn migrates_baseline_db() {
+        let dir = std::env::temp_dir().join(format!("blockchain-migration-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        db::set_data_dir(&dir);
+
+        let (alice, bob) = (vec![1; 20], vec![2; 20]);
+        let (signature, pub_key) = (vec![3; 64], vec![4; 32]);
+        let coinbase = baseline_tx(
+            "C0",
+            vec![KeyTXInput {
+                txid: String::new(),
+                vout: -1,
+                signature: Vec::new(),
+                pub_key: b"genesis".to_vec(),
+            }],
+            vec![(10, alice.clone())],
+        );
+        let spend = baseline_tx(
+            "5E",
+            vec![KeyTXInput {
+                txid: "C0".to_string(),
+                vout: 0,
+                signature: signature.clone(),
+                pub_key: pub_key.clone(),
+            }],
+            vec![(3, bob.clone()), (7, alice.clone())],
+        );
+        // the first format stored the target as the height of later blocks
+        let blocks = [
+            FlatBlock {
+                timestamp: 1_600_000_000_000,
+                transactions: vec![coinbase],
+                prev_block_hash: String::new(),
+                hash: format!("{:064X}", 1),
+                nonce: 0,
+                height: 0,
+            },
+            FlatBlock {
+                timestamp: 1_600_000_060_000,
+                transactions: vec![spend],
+                prev_block_hash: format!("{:064X}", 1),
+                hash: format!("{:064X}", 2),
+                nonce: 0,
+                height: TARGET_HEXS,
+            },
+        ];
+
+        let db = db::open(db::DB_BLOCKS).unwrap();
+        for block in &blocks {
+            db.insert(&block.hash, bincode::serialize(block).unwrap())
+                .unwrap();
+        }
+        db.insert("LAST", blocks[1].hash.as_bytes()).unwrap();
+        db.flush().unwrap();
+        drop(db);
+
+        let report = migrate().unwrap();
+        assert_eq!(report.blocks, Some(2));
+
+        let utxo_set = UTXOSet {
+            blockchain: Blockchain::new().unwrap(),
+        };
+        assert_eq!(utxo_set.blockchain.get_best_height().unwrap(), 1);
+        let tip = utxo_set.blockchain.iter().next().unwrap();
+        assert_eq!(
+            tip.get_transactions()[0].vin[0].script_sig,
+            Script::new_p2pkh_unlock(&signature, &pub_key)
+        );
+
+        let balance = |pub_key_hash: &[u8]| {
+            utxo_set
+                .get_balance(&Script::new_p2pkh(pub_key_hash))
+                .unwrap()
+                .0
+        };
+        assert_eq!(balance(&alice), Amount::from_coins(7));
+        assert_eq!(balance(&bob), Amount::from_coins(3));
+
+        drop(utxo_set);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{
+    block::{meets_target, BlockHeader, NONCE_OFFSET},
+    encoding::Encode,
+    hash::BlockHash,
+};
+use log::info;
+use sha2::{Digest, Sha256};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Number of nonces a thread tries between checks of the stop flags.
+const BATCH_SIZE: u64 = 4_096;
+
+/// Size of a SHA-256 block, the part of the header hashed once per search.
+const SHA256_BLOCK_SIZE: usize = 64;
+
+/// Miner searches the nonce of a block header on several threads, each
+/// trying its own part of the nonce space. Clones share the cancellation
+/// flag, so another thread can stop a search, e.g. when a new tip arrives
+/// and the block would be stale.
+#[derive(Debug, Clone)]
+pub struct Miner {
+    threads: usize,
+    /// Largest nonce tried before the header needs other content.
+    max_nonce: u64,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// MiningOutcome tells how a search ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MiningOutcome {
+    /// The nonce set in the header gives the hash, which meets the target.
+    Found(BlockHash),
+    /// No nonce meets the target, the content of the header must change.
+    Exhausted,
+    Cancelled,
+}
+
+/// MiningStats tells how much work a search took.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MiningStats {
+    /// Number of headers hashed.
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningStats {
+    /// Returns the number of hashes per second.
+    pub fn hashrate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.hashes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Default for Miner {
+    /// Creates a miner using all the cores.
+    fn default() -> Self {
+        Miner::new(0)
+    }
+}
+
+impl Miner {
+    /// Creates a miner running the threads, or one per core if 0.
+    pub fn new(threads: usize) -> Miner {
+        let threads = if threads == 0 {
+            thread::available_parallelism().map_or(1, |n| n.get())
+        } else {
+            threads
+        };
+
+        Miner {
+            threads,
+            max_nonce: u64::MAX,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Limits the nonces tried for a header, so the miner moves on to a new
+    /// extra nonce sooner.
+    pub fn with_max_nonce(mut self, max_nonce: u64) -> Miner {
+        self.max_nonce = max_nonce;
+        self
+    }
+
+    pub fn get_threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Stops the running search, which ends as cancelled, and the searches
+    /// started later until the miner is reset.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Clears a cancellation, so the miner searches again. Called once per
+    /// block, as a block may take several searches.
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+
+    /// Searches a nonce giving the header a hash meeting its target and sets
+    /// it. Returns how the search ended and the work done.
+    pub fn mine(&self, header: &mut BlockHeader) -> (MiningOutcome, MiningStats) {
+        // the nonce is past the first SHA-256 block, which is hashed once
+        let data = header.encode();
+        let mut midstate = Sha256::new();
+        midstate.update(&data[..SHA256_BLOCK_SIZE]);
+        let tail = &data[SHA256_BLOCK_SIZE..];
+        let nonce_offset = NONCE_OFFSET - SHA256_BLOCK_SIZE;
+        let nonce_size = if header.version == 1 { 4 } else { 8 };
+        let target = header.target;
+
+        let max_nonce = self.max_nonce.min(header.max_nonce());
+        let space = max_nonce as u128 + 1;
+        let threads = (self.threads as u128).min(space);
+        let chunk = space / threads;
+        info!(
+            "Mining the block on {} threads up to nonce {}",
+            threads, max_nonce
+        );
+
+        let start = Instant::now();
+        let found = AtomicBool::new(false);
+        let hashes = AtomicU64::new(0);
+        let solution = Mutex::new(None);
+
+        thread::scope(|scope| {
+            for i in 0..threads {
+                let first = (i * chunk) as u64;
+                let last = if i + 1 == threads {
+                    max_nonce
+                } else {
+                    ((i + 1) * chunk - 1) as u64
+                };
+                let (midstate, found, hashes, solution) = (&midstate, &found, &hashes, &solution);
+
+                scope.spawn(move || {
+                    let mut tail = tail.to_vec();
+                    let mut nonce = first;
+                    let mut count = 0;
+
+                    loop {
+                        if found.load(Ordering::Relaxed) || self.cancelled.load(Ordering::Relaxed) {
+                            break;
+                        }
+
+                        let batch_last = last.min(nonce.saturating_add(BATCH_SIZE - 1));
+                        for n in nonce..=batch_last {
+                            let bytes = n.to_le_bytes();
+                            tail[nonce_offset..nonce_offset + nonce_size]
+                                .copy_from_slice(&bytes[..nonce_size]);
+
+                            let mut hasher = midstate.clone();
+                            hasher.update(&tail);
+                            let hash = BlockHash::from_bytes(hasher.finalize().into());
+                            count += 1;
+
+                            if meets_target(&hash, target) {
+                                found.store(true, Ordering::Relaxed);
+                                *solution.lock().unwrap() = Some((n, hash));
+                                break;
+                            }
+                        }
+
+                        if batch_last == last || found.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        nonce = batch_last + 1;
+                    }
+
+                    hashes.fetch_add(count, Ordering::Relaxed);
+                });
+            }
+        });
+
+        let stats = MiningStats {
+            hashes: hashes.into_inner(),
+            elapsed: start.elapsed(),
+        };
+
+        let cancelled = self.cancelled.load(Ordering::Relaxed);
+        let outcome = match solution.into_inner().unwrap() {
+            Some((nonce, hash)) => {
+                header.nonce = nonce;
+                info!(
+                    "Mined block {} with {} hashes at {:.0} H/s",
+                    hash,
+                    stats.hashes,
+                    stats.hashrate()
+                );
+                MiningOutcome::Found(hash)
+            }
+            None if cancelled => {
+                info!("Mining cancelled after {} hashes", stats.hashes);
+                MiningOutcome::Cancelled
+            }
+            None => {
+                info!("No nonce up to {} meets the target", max_nonce);
+                MiningOutcome::Exhausted
+            }
+        };
+
+        (outcome, stats)
+    }
+}
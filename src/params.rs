@@ -0,0 +1,155 @@
+use crate::{amount::Amount, errors::Result, transaction::SUBSIDY};
+use serde::{Deserialize, Serialize};
+
+/// Number of blocks after which the block subsidy halves by default.
+pub const HALVING_INTERVAL: usize = 1_000;
+
+/// Maximum amount of money that can ever be issued by default.
+pub const MAX_SUPPLY: Amount = Amount::from_coins(21_000);
+
+/// Maximum number of bytes a data output carries by default.
+pub const MAX_DATA_SIZE: usize = 80;
+
+/// Number of seconds a block time may be ahead of the clock of the node by
+/// default.
+pub const MAX_FUTURE_DRIFT: u64 = 2 * 60 * 60;
+
+/// Maximum number of bytes of an encoded block by default.
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
+/// Maximum number of bytes of an encoded transaction by default.
+pub const MAX_TX_SIZE: usize = 100_000;
+
+/// Number of blocks a coinbase must be below a block spending its outputs by
+/// default. None, so mined money can be sent right away.
+pub const COINBASE_MATURITY: usize = 0;
+
+/// ChainParams holds the consensus rules chosen when the blockchain is
+/// created. They are stored next to the blocks, rules added later take their
+/// default on older chains.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ChainParams {
+    pub initial_subsidy: Amount,
+    pub halving_interval: usize,
+    pub max_supply: Amount,
+    pub max_data_size: usize,
+    /// Number of seconds a block time may be ahead of the clock of the node.
+    pub max_future_drift: u64,
+    /// Number of blocks a coinbase must be below a block spending its
+    /// outputs.
+    pub coinbase_maturity: usize,
+    /// Maximum number of bytes of an encoded block.
+    pub max_block_size: usize,
+    /// Maximum number of bytes of an encoded transaction.
+    pub max_tx_size: usize,
+}
+
+/// ChainParams stored with bincode before the params were stored as JSON.
+#[derive(Deserialize)]
+struct LegacyChainParams {
+    initial_subsidy: Amount,
+    halving_interval: usize,
+    max_supply: Amount,
+    max_data_size: usize,
+}
+
+/// ChainParams stored with bincode before the maximum data size was added.
+#[derive(Deserialize)]
+struct SupplyChainParams {
+    initial_subsidy: Amount,
+    halving_interval: usize,
+    max_supply: Amount,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams {
+            initial_subsidy: SUBSIDY,
+            halving_interval: HALVING_INTERVAL,
+            max_supply: MAX_SUPPLY,
+            max_data_size: MAX_DATA_SIZE,
+            max_future_drift: MAX_FUTURE_DRIFT,
+            coinbase_maturity: COINBASE_MATURITY,
+            max_block_size: MAX_BLOCK_SIZE,
+            max_tx_size: MAX_TX_SIZE,
+        }
+    }
+}
+
+impl ChainParams {
+    /// Encodes the params to be stored next to the blocks.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    /// Decodes stored params, either JSON or the bincode of older chains.
+    pub fn from_bytes(data: &[u8]) -> Result<ChainParams> {
+        if let Ok(params) = serde_json::from_slice(data) {
+            return Ok(params);
+        }
+
+        // bincode ignores trailing bytes, so try the longest layout first
+        if let Ok(legacy) = bincode::deserialize::<LegacyChainParams>(data) {
+            return Ok(ChainParams {
+                initial_subsidy: legacy.initial_subsidy,
+                halving_interval: legacy.halving_interval,
+                max_supply: legacy.max_supply,
+                max_data_size: legacy.max_data_size,
+                ..ChainParams::default()
+            });
+        }
+
+        let legacy: SupplyChainParams = bincode::deserialize(data)?;
+        Ok(ChainParams {
+            initial_subsidy: legacy.initial_subsidy,
+            halving_interval: legacy.halving_interval,
+            max_supply: legacy.max_supply,
+            ..ChainParams::default()
+        })
+    }
+
+    /// Returns the amount of new money the coinbase of the block at the
+    /// height may issue.
+    pub fn subsidy(&self, height: usize) -> Amount {
+        let issued = self.issued_before(height);
+        let remaining = self.max_supply.checked_sub(issued).unwrap_or(Amount::ZERO);
+
+        self.scheduled_subsidy(height).min(remaining)
+    }
+
+    /// Returns the amount of money issued by the blocks below the height
+    /// when every coinbase claims its full subsidy.
+    pub fn issued_before(&self, height: usize) -> Amount {
+        let mut issued: u128 = 0;
+        let mut era_start = 0;
+
+        while era_start < height {
+            let era_end = era_start
+                .saturating_add(self.halving_interval.max(1))
+                .min(height);
+            let subsidy = self.scheduled_subsidy(era_start);
+            if subsidy.is_zero() {
+                break;
+            }
+
+            issued += subsidy.units() as u128 * (era_end - era_start) as u128;
+            if issued >= self.max_supply.units() as u128 {
+                return self.max_supply;
+            }
+            era_start = era_end;
+        }
+
+        Amount::from_units(issued as u64)
+    }
+
+    /// Returns the subsidy of the height before applying the supply cap.
+    fn scheduled_subsidy(&self, height: usize) -> Amount {
+        let halvings = height / self.halving_interval.max(1);
+        if halvings >= 64 {
+            return Amount::ZERO;
+        }
+
+        Amount::from_units(self.initial_subsidy.units() >> halvings)
+    }
+}
@@ -0,0 +1,364 @@
+use super::{Op, Script, ScriptType, MAX_MULTISIG_KEYS};
+use crate::{errors::Result, wallet::hash160};
+use failure::format_err;
+use sha2::{Digest, Sha256};
+
+/// Maximum number of non-push ops a script may execute.
+pub const MAX_OPS: usize = 201;
+
+/// Maximum number of elements on the stack.
+pub const MAX_STACK_SIZE: usize = 1_000;
+
+/// Maximum size of a stack element in bytes.
+pub const MAX_ELEMENT_SIZE: usize = 520;
+
+/// SignatureChecker checks signatures against the transaction whose input
+/// is verified.
+pub trait SignatureChecker {
+    /// Checks the signature of the transaction made with the public key.
+    fn check_signature(&self, signature: &[u8], pub_key: &[u8]) -> bool;
+
+    /// Checks that the lock time of the transaction reached the lock.
+    fn check_lock_time(&self, lock_time: u32) -> bool;
+
+    /// Checks that the relative lock of the input reached the sequence.
+    fn check_sequence(&self, sequence: u32) -> bool;
+}
+
+/// Runs the unlocking script of an input followed by the locking script of
+/// the output it spends. Succeeds if the scripts leave a true value on the
+/// stack. For pay-to-script-hash outputs the last element pushed by the
+/// unlocking script is run as the redeem script as well.
+pub fn verify_script(
+    script_sig: &Script,
+    script_pubkey: &Script,
+    checker: &dyn SignatureChecker,
+) -> Result<()> {
+    if !script_sig.is_push_only() {
+        return Err(format_err!("Unlocking script must only push data"));
+    }
+
+    let mut stack = Vec::new();
+    eval(script_sig, &mut stack, checker)?;
+    let mut redeem_stack = stack.clone();
+
+    eval(script_pubkey, &mut stack, checker)?;
+    if !stack.last().is_some_and(|top| is_true(top)) {
+        return Err(format_err!("Script evaluated to false"));
+    }
+
+    if let ScriptType::ScriptHash(_) = script_pubkey.get_type() {
+        let redeem_script = Script::from_bytes(&pop(&mut redeem_stack)?)?;
+
+        eval(&redeem_script, &mut redeem_stack, checker)?;
+        if !redeem_stack.last().is_some_and(|top| is_true(top)) {
+            return Err(format_err!("Redeem script evaluated to false"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Executes the script on the stack.
+fn eval(script: &Script, stack: &mut Vec<Vec<u8>>, checker: &dyn SignatureChecker) -> Result<()> {
+    let mut conditions: Vec<bool> = Vec::new();
+    let mut op_count = 0;
+
+    for op in &script.0 {
+        if !op.is_push() {
+            op_count += 1;
+            if op_count > MAX_OPS {
+                return Err(format_err!("Script executes more than {} ops", MAX_OPS));
+            }
+        }
+
+        let executing = conditions.iter().all(|c| *c);
+
+        match op {
+            Op::If | Op::NotIf => {
+                let mut value = false;
+                if executing {
+                    value = is_true(&pop(stack)?) == (*op == Op::If);
+                }
+                conditions.push(value);
+                continue;
+            }
+            Op::Else => {
+                let last = conditions
+                    .last_mut()
+                    .ok_or_else(|| format_err!("OP_ELSE without OP_IF"))?;
+                *last = !*last;
+                continue;
+            }
+            Op::EndIf => {
+                conditions
+                    .pop()
+                    .ok_or_else(|| format_err!("OP_ENDIF without OP_IF"))?;
+                continue;
+            }
+            _ if !executing => continue,
+            _ => {}
+        }
+
+        match op {
+            Op::PushData(data) => {
+                if data.len() > MAX_ELEMENT_SIZE {
+                    return Err(format_err!(
+                        "Pushed data is larger than {} bytes",
+                        MAX_ELEMENT_SIZE
+                    ));
+                }
+                stack.push(data.clone());
+            }
+            Op::Number(n) => stack.push(encode_num(*n)),
+            Op::Dup => {
+                let top = stack
+                    .last()
+                    .cloned()
+                    .ok_or_else(|| format_err!("Stack is empty"))?;
+                stack.push(top);
+            }
+            Op::Drop => {
+                pop(stack)?;
+            }
+            Op::Swap => {
+                let a = pop(stack)?;
+                let b = pop(stack)?;
+                stack.push(a);
+                stack.push(b);
+            }
+            Op::Size => {
+                let size = stack
+                    .last()
+                    .map(|top| top.len())
+                    .ok_or_else(|| format_err!("Stack is empty"))?;
+                stack.push(encode_num(size as i64));
+            }
+            Op::Equal | Op::EqualVerify => {
+                let a = pop(stack)?;
+                let b = pop(stack)?;
+                if *op == Op::EqualVerify {
+                    if a != b {
+                        return Err(format_err!("OP_EQUALVERIFY failed"));
+                    }
+                } else {
+                    stack.push(encode_bool(a == b));
+                }
+            }
+            Op::Verify => {
+                if !is_true(&pop(stack)?) {
+                    return Err(format_err!("OP_VERIFY failed"));
+                }
+            }
+            Op::Hash160 => {
+                let data = pop(stack)?;
+                stack.push(hash160(&data));
+            }
+            Op::Sha256 => {
+                let data = pop(stack)?;
+                stack.push(Sha256::digest(&data).to_vec());
+            }
+            Op::CheckSig | Op::CheckSigVerify => {
+                let pub_key = pop(stack)?;
+                let signature = pop(stack)?;
+                let valid = checker.check_signature(&signature, &pub_key);
+
+                if *op == Op::CheckSigVerify {
+                    if !valid {
+                        return Err(format_err!("OP_CHECKSIGVERIFY failed"));
+                    }
+                } else {
+                    stack.push(encode_bool(valid));
+                }
+            }
+            Op::CheckMultiSig | Op::CheckMultiSigVerify => {
+                let key_count = decode_count(&pop(stack)?, MAX_MULTISIG_KEYS)?;
+                op_count += key_count;
+                if op_count > MAX_OPS {
+                    return Err(format_err!("Script executes more than {} ops", MAX_OPS));
+                }
+
+                let mut pub_keys = (0..key_count)
+                    .map(|_| pop(stack))
+                    .collect::<Result<Vec<_>>>()?;
+                pub_keys.reverse();
+
+                let sig_count = decode_count(&pop(stack)?, key_count)?;
+                let mut signatures = (0..sig_count)
+                    .map(|_| pop(stack))
+                    .collect::<Result<Vec<_>>>()?;
+                signatures.reverse();
+
+                // signatures must match the keys in order, each key is
+                // tried at most once
+                let mut keys = pub_keys.iter();
+                let valid = signatures.iter().all(|signature| {
+                    keys.any(|pub_key| checker.check_signature(signature, pub_key))
+                });
+
+                if *op == Op::CheckMultiSigVerify {
+                    if !valid {
+                        return Err(format_err!("OP_CHECKMULTISIGVERIFY failed"));
+                    }
+                } else {
+                    stack.push(encode_bool(valid));
+                }
+            }
+            Op::CheckLockTimeVerify | Op::CheckSequenceVerify => {
+                let top = stack.last().ok_or_else(|| format_err!("Stack is empty"))?;
+                let lock = decode_num(top)?;
+                let lock = u32::try_from(lock)
+                    .map_err(|_| format_err!("Lock {} is out of range", lock))?;
+
+                let valid = if *op == Op::CheckLockTimeVerify {
+                    checker.check_lock_time(lock)
+                } else {
+                    checker.check_sequence(lock)
+                };
+                if !valid {
+                    return Err(format_err!("{} failed", op));
+                }
+            }
+            Op::Return => return Err(format_err!("OP_RETURN executed")),
+            Op::If | Op::NotIf | Op::Else | Op::EndIf => unreachable!(),
+        }
+
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(format_err!(
+                "Stack holds more than {} elements",
+                MAX_STACK_SIZE
+            ));
+        }
+    }
+
+    if !conditions.is_empty() {
+        return Err(format_err!("OP_IF without OP_ENDIF"));
+    }
+
+    Ok(())
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>> {
+    stack.pop().ok_or_else(|| format_err!("Stack is empty"))
+}
+
+/// Checks whether the element is true, i.e. has a non-zero byte.
+fn is_true(element: &[u8]) -> bool {
+    element.iter().any(|b| *b != 0)
+}
+
+fn encode_bool(value: bool) -> Vec<u8> {
+    if value {
+        vec![1]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Decodes a count of elements which must be between 0 and the maximum.
+fn decode_count(element: &[u8], max: usize) -> Result<usize> {
+    let n = decode_num(element)?;
+    if n < 0 || n as usize > max {
+        return Err(format_err!("Count {} is out of range 0 to {}", n, max));
+    }
+
+    Ok(n as usize)
+}
+
+/// Encodes a number as a stack element, little-endian without trailing
+/// zero bytes.
+pub fn encode_num(n: i64) -> Vec<u8> {
+    let mut data = n.to_le_bytes().to_vec();
+    while data.last() == Some(&0) {
+        data.pop();
+    }
+    data
+}
+
+/// Decodes a number from a stack element.
+pub fn decode_num(element: &[u8]) -> Result<i64> {
+    if element.len() > 8 {
+        return Err(format_err!("Number is longer than 8 bytes"));
+    }
+
+    let mut data = [0u8; 8];
+    data[..element.len()].copy_from_slice(element);
+    Ok(i64::from_le_bytes(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::ed25519;
+
+    const MESSAGE: &[u8] = b"spend the multisig output";
+
+    /// Checks signatures of a fixed message rather than of a transaction.
+    struct MessageChecker;
+
+    impl SignatureChecker for MessageChecker {
+        fn check_signature(&self, signature: &[u8], pub_key: &[u8]) -> bool {
+            signature.len() == 64
+                && pub_key.len() == 32
+                && ed25519::verify(MESSAGE, pub_key, signature)
+        }
+
+        fn check_lock_time(&self, _lock_time: u32) -> bool {
+            true
+        }
+
+        fn check_sequence(&self, _sequence: u32) -> bool {
+            true
+        }
+    }
+
+    fn keypairs(count: usize) -> Vec<([u8; 64], [u8; 32])> {
+        (0..count)
+            .map(|i| ed25519::keypair(&[i as u8 + 1; 32]))
+            .collect()
+    }
+
+    #[test]
+    fn spends_multisig_with_most_keys() {
+        let keys = keypairs(MAX_MULTISIG_KEYS);
+        let pub_keys = keys.iter().map(|(_, pk)| pk.to_vec()).collect::<Vec<_>>();
+        let redeem_script = Script::new_multisig(MAX_MULTISIG_KEYS, &pub_keys).unwrap();
+        assert!(redeem_script.to_bytes().unwrap().len() <= MAX_ELEMENT_SIZE);
+
+        let signatures = keys
+            .iter()
+            .map(|(sk, _)| ed25519::signature(MESSAGE, sk).to_vec())
+            .collect::<Vec<_>>();
+        let script_sig = Script::new_multisig_unlock(&signatures, &redeem_script).unwrap();
+        let script_pubkey = Script::new_p2sh(&redeem_script.hash().unwrap());
+
+        verify_script(&script_sig, &script_pubkey, &MessageChecker).unwrap();
+    }
+
+    #[test]
+    fn rejects_multisig_with_missing_signature() {
+        let keys = keypairs(MAX_MULTISIG_KEYS);
+        let pub_keys = keys.iter().map(|(_, pk)| pk.to_vec()).collect::<Vec<_>>();
+        let redeem_script = Script::new_multisig(MAX_MULTISIG_KEYS, &pub_keys).unwrap();
+
+        let mut signatures = keys
+            .iter()
+            .map(|(sk, _)| ed25519::signature(MESSAGE, sk).to_vec())
+            .collect::<Vec<_>>();
+        signatures[0] = signatures[1].clone();
+        let script_sig = Script::new_multisig_unlock(&signatures, &redeem_script).unwrap();
+        let script_pubkey = Script::new_p2sh(&redeem_script.hash().unwrap());
+
+        assert!(verify_script(&script_sig, &script_pubkey, &MessageChecker).is_err());
+    }
+
+    #[test]
+    fn refuses_more_multisig_keys() {
+        let pub_keys = keypairs(MAX_MULTISIG_KEYS + 1)
+            .iter()
+            .map(|(_, pk)| pk.to_vec())
+            .collect::<Vec<_>>();
+
+        assert!(Script::new_multisig(1, &pub_keys).is_err());
+    }
+}
@@ -0,0 +1,477 @@
+pub mod interpreter;
+
+use crate::{
+    encoding::{write_all, write_bytes, write_i64, write_u32, Decode, Encode, Reader},
+    errors::Result,
+    wallet::hash160,
+};
+use bitcoincash_addr::{Address, HashType, Scheme};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Maximum size of a serialized script in bytes.
+pub const MAX_SCRIPT_SIZE: u64 = 10_000;
+
+/// Maximum number of public keys of a multisignature script. Its redeem
+/// script takes 36 bytes and 44 per key, and must fit in a single push of
+/// at most 520 bytes to be spent.
+pub const MAX_MULTISIG_KEYS: usize = 11;
+
+/// Op is a single instruction of a script.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Op {
+    /// Pushes the data onto the stack.
+    PushData(Vec<u8>),
+    /// Pushes the number onto the stack.
+    Number(i64),
+    Dup,
+    Drop,
+    Swap,
+    Size,
+    Equal,
+    EqualVerify,
+    Verify,
+    Hash160,
+    Sha256,
+    CheckSig,
+    CheckSigVerify,
+    CheckMultiSig,
+    CheckMultiSigVerify,
+    /// Fails unless the transaction lock time reached the value on the stack.
+    CheckLockTimeVerify,
+    /// Fails unless the input relative lock reached the value on the stack.
+    CheckSequenceVerify,
+    If,
+    NotIf,
+    Else,
+    EndIf,
+    /// Marks the output as unspendable.
+    Return,
+}
+
+/// Script is a program locking an output or unlocking an input.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Script(pub Vec<Op>);
+
+/// Htlc holds the terms of a hash time-locked contract. The recipient can
+/// spend it with the preimage of the hash, the sender once the timeout passed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Htlc {
+    pub payment_hash: Vec<u8>,
+    pub recipient_pub_key_hash: Vec<u8>,
+    pub sender_pub_key_hash: Vec<u8>,
+    /// Lock time after which the sender can take the money back.
+    pub timeout: u32,
+}
+
+/// Size of the preimage of an HTLC payment hash.
+pub const HTLC_PREIMAGE_SIZE: usize = 32;
+
+/// Standard forms of locking scripts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptType {
+    /// Spendable by the owner of a key with the hash.
+    PubKeyHash(Vec<u8>),
+    /// Spendable by a script with the hash.
+    ScriptHash(Vec<u8>),
+    /// Unspendable output carrying data.
+    Data(Vec<u8>),
+    NonStandard,
+}
+
+impl Op {
+    /// Checks whether the op only pushes data onto the stack.
+    pub fn is_push(&self) -> bool {
+        matches!(self, Op::PushData(_) | Op::Number(_))
+    }
+}
+
+impl Script {
+    /// Creates a script paying to the owner of the public key hash.
+    pub fn new_p2pkh(pub_key_hash: &[u8]) -> Script {
+        Script(vec![
+            Op::Dup,
+            Op::Hash160,
+            Op::PushData(pub_key_hash.to_vec()),
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    /// Creates a script paying to a script with the hash.
+    pub fn new_p2sh(script_hash: &[u8]) -> Script {
+        Script(vec![
+            Op::Hash160,
+            Op::PushData(script_hash.to_vec()),
+            Op::Equal,
+        ])
+    }
+
+    /// Creates an unspendable script carrying the data.
+    pub fn new_data(data: &[u8]) -> Script {
+        Script(vec![Op::Return, Op::PushData(data.to_vec())])
+    }
+
+    /// Creates a script requiring signatures of `required` of the public
+    /// keys. Used as a redeem script of a pay-to-script-hash output.
+    pub fn new_multisig(required: usize, pub_keys: &[Vec<u8>]) -> Result<Script> {
+        if required == 0 || required > pub_keys.len() || pub_keys.len() > MAX_MULTISIG_KEYS {
+            return Err(format_err!(
+                "Can't require {} signatures of {} keys, at most {} keys are allowed",
+                required,
+                pub_keys.len(),
+                MAX_MULTISIG_KEYS
+            ));
+        }
+
+        let mut ops = vec![Op::Number(required as i64)];
+        ops.extend(pub_keys.iter().map(|k| Op::PushData(k.clone())));
+        ops.push(Op::Number(pub_keys.len() as i64));
+        ops.push(Op::CheckMultiSig);
+
+        Ok(Script(ops))
+    }
+
+    /// Creates a script unlocking a pay-to-script-hash output locked with
+    /// the multisig redeem script. The signatures must be in the order of
+    /// their public keys.
+    pub fn new_multisig_unlock(signatures: &[Vec<u8>], redeem_script: &Script) -> Result<Script> {
+        let mut ops = signatures
+            .iter()
+            .map(|s| Op::PushData(s.clone()))
+            .collect::<Vec<_>>();
+        ops.push(Op::PushData(redeem_script.to_bytes()?));
+
+        Ok(Script(ops))
+    }
+
+    /// Returns the number of required signatures and the public keys of a
+    /// multisig script.
+    pub fn get_multisig(&self) -> Option<(usize, Vec<Vec<u8>>)> {
+        match self.0.as_slice() {
+            [Op::Number(m), keys @ .., Op::Number(n), Op::CheckMultiSig]
+                if *n as usize == keys.len() && *m >= 1 && *m <= *n =>
+            {
+                let pub_keys = keys
+                    .iter()
+                    .map(|k| match k {
+                        Op::PushData(k) => Some(k.clone()),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some((*m as usize, pub_keys))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the address of a pay-to-script-hash output locked with the
+    /// script.
+    pub fn to_p2sh_address(&self) -> Result<String> {
+        Script::new_p2sh(&self.hash()?)
+            .to_address()
+            .ok_or_else(|| format_err!("Can't encode script address"))
+    }
+
+    /// Creates a hash time-locked contract. Used as a redeem script of a
+    /// pay-to-script-hash output.
+    pub fn new_htlc(htlc: &Htlc) -> Script {
+        Script(vec![
+            Op::If,
+            Op::Size,
+            Op::Number(HTLC_PREIMAGE_SIZE as i64),
+            Op::EqualVerify,
+            Op::Sha256,
+            Op::PushData(htlc.payment_hash.clone()),
+            Op::EqualVerify,
+            Op::Dup,
+            Op::Hash160,
+            Op::PushData(htlc.recipient_pub_key_hash.clone()),
+            Op::Else,
+            Op::Number(htlc.timeout as i64),
+            Op::CheckLockTimeVerify,
+            Op::Drop,
+            Op::Dup,
+            Op::Hash160,
+            Op::PushData(htlc.sender_pub_key_hash.clone()),
+            Op::EndIf,
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    /// Returns the terms of an HTLC script.
+    pub fn get_htlc(&self) -> Option<Htlc> {
+        match self.0.as_slice() {
+            [Op::If, Op::Size, Op::Number(size), Op::EqualVerify, Op::Sha256, Op::PushData(payment_hash), Op::EqualVerify, Op::Dup, Op::Hash160, Op::PushData(recipient), Op::Else, Op::Number(timeout), Op::CheckLockTimeVerify, Op::Drop, Op::Dup, Op::Hash160, Op::PushData(sender), Op::EndIf, Op::EqualVerify, Op::CheckSig]
+                if *size == HTLC_PREIMAGE_SIZE as i64 =>
+            {
+                Some(Htlc {
+                    payment_hash: payment_hash.clone(),
+                    recipient_pub_key_hash: recipient.clone(),
+                    sender_pub_key_hash: sender.clone(),
+                    timeout: u32::try_from(*timeout).ok()?,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Creates a script claiming an HTLC with the preimage of its hash.
+    pub fn new_htlc_claim(
+        signature: &[u8],
+        pub_key: &[u8],
+        preimage: &[u8],
+        redeem_script: &Script,
+    ) -> Result<Script> {
+        Ok(Script(vec![
+            Op::PushData(signature.to_vec()),
+            Op::PushData(pub_key.to_vec()),
+            Op::PushData(preimage.to_vec()),
+            Op::Number(1),
+            Op::PushData(redeem_script.to_bytes()?),
+        ]))
+    }
+
+    /// Creates a script taking back the money of an expired HTLC.
+    pub fn new_htlc_refund(
+        signature: &[u8],
+        pub_key: &[u8],
+        redeem_script: &Script,
+    ) -> Result<Script> {
+        Ok(Script(vec![
+            Op::PushData(signature.to_vec()),
+            Op::PushData(pub_key.to_vec()),
+            Op::Number(0),
+            Op::PushData(redeem_script.to_bytes()?),
+        ]))
+    }
+
+    /// Returns the preimage revealed by a script claiming an HTLC.
+    pub fn get_htlc_preimage(&self) -> Option<Vec<u8>> {
+        match self.0.as_slice() {
+            [Op::PushData(_), Op::PushData(_), Op::PushData(preimage), Op::Number(1), Op::PushData(_)] => {
+                Some(preimage.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Creates a script unlocking a pay-to-pubkey-hash output.
+    pub fn new_p2pkh_unlock(signature: &[u8], pub_key: &[u8]) -> Script {
+        Script(vec![
+            Op::PushData(signature.to_vec()),
+            Op::PushData(pub_key.to_vec()),
+        ])
+    }
+
+    /// Creates the locking script of the address.
+    pub fn from_address(address: &str) -> Result<Script> {
+        let address =
+            Address::decode(address).map_err(|_| format_err!("Invalid address '{}'", address))?;
+
+        match address.hash_type {
+            HashType::Key => Ok(Script::new_p2pkh(&address.body)),
+            HashType::Script => Ok(Script::new_p2sh(&address.body)),
+        }
+    }
+
+    /// Returns the address the script pays to, if it has one.
+    pub fn to_address(&self) -> Option<String> {
+        let (body, hash_type) = match self.get_type() {
+            ScriptType::PubKeyHash(h) => (h, HashType::Key),
+            ScriptType::ScriptHash(h) => (h, HashType::Script),
+            _ => return None,
+        };
+
+        Address {
+            body,
+            scheme: Scheme::Base58,
+            hash_type,
+            ..Default::default()
+        }
+        .encode()
+        .ok()
+    }
+
+    /// Returns the standard form of the script.
+    pub fn get_type(&self) -> ScriptType {
+        match self.0.as_slice() {
+            [Op::Dup, Op::Hash160, Op::PushData(h), Op::EqualVerify, Op::CheckSig]
+                if h.len() == 20 =>
+            {
+                ScriptType::PubKeyHash(h.clone())
+            }
+            [Op::Hash160, Op::PushData(h), Op::Equal] if h.len() == 20 => {
+                ScriptType::ScriptHash(h.clone())
+            }
+            [Op::Return, Op::PushData(data)] => ScriptType::Data(data.clone()),
+            _ => ScriptType::NonStandard,
+        }
+    }
+
+    /// Checks whether an output locked with the script can never be spent.
+    pub fn is_unspendable(&self) -> bool {
+        matches!(self.0.first(), Some(Op::Return))
+    }
+
+    /// Checks whether the script only pushes data onto the stack.
+    pub fn is_push_only(&self) -> bool {
+        self.0.iter().all(Op::is_push)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the hash identifying the script in a pay-to-script-hash
+    /// output.
+    pub fn hash(&self) -> Result<Vec<u8>> {
+        Ok(hash160(&self.to_bytes()?))
+    }
+
+    /// Serializes the script in the canonical encoding.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(self.encode())
+    }
+
+    /// Deserializes a script, refusing scripts larger than
+    /// [`MAX_SCRIPT_SIZE`].
+    pub fn from_bytes(data: &[u8]) -> Result<Script> {
+        if data.len() as u64 > MAX_SCRIPT_SIZE {
+            return Err(format_err!(
+                "Script of {} bytes is larger than {} bytes",
+                data.len(),
+                MAX_SCRIPT_SIZE
+            ));
+        }
+        Script::decode(data)
+    }
+}
+
+impl Op {
+    /// Returns the tag of the op in the canonical encoding.
+    fn tag(&self) -> u32 {
+        match self {
+            Op::PushData(_) => 0,
+            Op::Number(_) => 1,
+            Op::Dup => 2,
+            Op::Drop => 3,
+            Op::Swap => 4,
+            Op::Size => 5,
+            Op::Equal => 6,
+            Op::EqualVerify => 7,
+            Op::Verify => 8,
+            Op::Hash160 => 9,
+            Op::Sha256 => 10,
+            Op::CheckSig => 11,
+            Op::CheckSigVerify => 12,
+            Op::CheckMultiSig => 13,
+            Op::CheckMultiSigVerify => 14,
+            Op::CheckLockTimeVerify => 15,
+            Op::CheckSequenceVerify => 16,
+            Op::If => 17,
+            Op::NotIf => 18,
+            Op::Else => 19,
+            Op::EndIf => 20,
+            Op::Return => 21,
+        }
+    }
+}
+
+impl Encode for Op {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        write_u32(out, self.tag());
+        match self {
+            Op::PushData(data) => write_bytes(out, data),
+            Op::Number(n) => write_i64(out, *n),
+            _ => {}
+        }
+    }
+}
+
+impl Decode for Op {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Op> {
+        let op = match reader.read_u32()? {
+            0 => Op::PushData(reader.read_bytes()?.to_vec()),
+            1 => Op::Number(reader.read_i64()?),
+            2 => Op::Dup,
+            3 => Op::Drop,
+            4 => Op::Swap,
+            5 => Op::Size,
+            6 => Op::Equal,
+            7 => Op::EqualVerify,
+            8 => Op::Verify,
+            9 => Op::Hash160,
+            10 => Op::Sha256,
+            11 => Op::CheckSig,
+            12 => Op::CheckSigVerify,
+            13 => Op::CheckMultiSig,
+            14 => Op::CheckMultiSigVerify,
+            15 => Op::CheckLockTimeVerify,
+            16 => Op::CheckSequenceVerify,
+            17 => Op::If,
+            18 => Op::NotIf,
+            19 => Op::Else,
+            20 => Op::EndIf,
+            21 => Op::Return,
+            tag => return Err(format_err!("Unknown op tag {}", tag)),
+        };
+        Ok(op)
+    }
+}
+
+impl Encode for Script {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        write_all(out, &self.0);
+    }
+}
+
+impl Decode for Script {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Script> {
+        Ok(Script(reader.read_all()?))
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::PushData(data) => write!(f, "{}", hex::encode(data)),
+            Op::Number(n) => write!(f, "{}", n),
+            Op::Dup => write!(f, "OP_DUP"),
+            Op::Drop => write!(f, "OP_DROP"),
+            Op::Swap => write!(f, "OP_SWAP"),
+            Op::Size => write!(f, "OP_SIZE"),
+            Op::Equal => write!(f, "OP_EQUAL"),
+            Op::EqualVerify => write!(f, "OP_EQUALVERIFY"),
+            Op::Verify => write!(f, "OP_VERIFY"),
+            Op::Hash160 => write!(f, "OP_HASH160"),
+            Op::Sha256 => write!(f, "OP_SHA256"),
+            Op::CheckSig => write!(f, "OP_CHECKSIG"),
+            Op::CheckSigVerify => write!(f, "OP_CHECKSIGVERIFY"),
+            Op::CheckMultiSig => write!(f, "OP_CHECKMULTISIG"),
+            Op::CheckMultiSigVerify => write!(f, "OP_CHECKMULTISIGVERIFY"),
+            Op::CheckLockTimeVerify => write!(f, "OP_CHECKLOCKTIMEVERIFY"),
+            Op::CheckSequenceVerify => write!(f, "OP_CHECKSEQUENCEVERIFY"),
+            Op::If => write!(f, "OP_IF"),
+            Op::NotIf => write!(f, "OP_NOTIF"),
+            Op::Else => write!(f, "OP_ELSE"),
+            Op::EndIf => write!(f, "OP_ENDIF"),
+            Op::Return => write!(f, "OP_RETURN"),
+        }
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ops = self.0.iter().map(|op| op.to_string()).collect::<Vec<_>>();
+        write!(f, "{}", ops.join(" "))
+    }
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Script({})", self)
+    }
+}
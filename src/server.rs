@@ -0,0 +1,240 @@
+use crate::{
+    block::{Block, BlockHeader, BlockTemplate},
+    blockchain::{Blockchain, ChainUpdate},
+    chaininfo::ChainInfo,
+    consensus::ConsensusConfig,
+    errors::Result,
+    fees::FeeEstimate,
+    filter::BlockFilter,
+    hash::BlockHash,
+    mempool::Mempool,
+    merkle::MerkleProof,
+    transaction::{utxoset::UTXOSet, Transaction},
+};
+use failure::format_err;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+};
+
+/// Address the node listens on by default.
+pub const DEFAULT_NODE_ADDR: &str = "127.0.0.1:7878";
+
+/// Maximum number of headers sent in a single response.
+pub const MAX_HEADERS: usize = 2_000;
+
+/// Maximum number of filters sent in a single response.
+pub const MAX_FILTERS: usize = 1_000;
+
+/// Request is a single line of JSON sent to the node.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Request {
+    /// Asks for the headers of the blocks from the height on.
+    GetHeaders { from_height: usize },
+    /// Asks for the filters of the blocks from the height on.
+    GetFilters { from_height: usize },
+    /// Asks for the block with the hash.
+    GetBlock { hash: BlockHash },
+    /// Asks for a summary of the chain and of the node.
+    GetChainInfo,
+    /// Asks for the consensus config the headers are checked with.
+    GetConsensus,
+    /// Asks for the fee rate to be mined within the number of blocks.
+    EstimateFee { blocks: usize },
+    /// Asks for a block to mine paying to the address.
+    GetBlockTemplate { address: String },
+    /// Submits a mined block template.
+    SubmitBlock { block: BlockTemplate },
+}
+
+/// Response is a single line of JSON sent back by the node.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Response {
+    Headers(Vec<BlockHeader>),
+    Filters(Vec<BlockFilter>),
+    Block(Block),
+    ChainInfo(ChainInfo),
+    Consensus(ConsensusConfig),
+    FeeEstimate(FeeEstimate),
+    BlockTemplate(BlockTemplate),
+    /// Tells the hash of the submitted block, which is the new tip.
+    BlockAccepted(BlockHash),
+    Error(String),
+}
+
+/// ProvenTransaction is a transaction with the proof that it was mined in
+/// the block.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProvenTransaction {
+    pub block_hash: BlockHash,
+    pub tx: Transaction,
+    pub proof: MerkleProof,
+}
+
+/// Serves requests of light clients on the address. The blockchain is opened
+/// for each request, so other commands can use it in between.
+pub fn serve(addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Listening on {}", addr);
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        if let Err(e) = handle_connection(stream) {
+            error!("Connection failed: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends the request to the node and returns its response.
+pub fn send_request(addr: &str, request: &Request) -> Result<Response> {
+    let mut stream = TcpStream::connect(addr)?;
+    writeln!(stream, "{}", serde_json::to_string(request)?)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+
+    match serde_json::from_str(&line)? {
+        Response::Error(e) => Err(format_err!("Node error: {}", e)),
+        response => Ok(response),
+    }
+}
+
+fn handle_connection(stream: TcpStream) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let response = match serde_json::from_str::<Request>(&line?) {
+            Ok(request) => {
+                handle_request(request).unwrap_or_else(|e| Response::Error(e.to_string()))
+            }
+            Err(e) => Response::Error(format!("Invalid request: {}", e)),
+        };
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+    }
+
+    Ok(())
+}
+
+fn handle_request(request: Request) -> Result<Response> {
+    info!("Request: {:?}", request);
+    let bc = Blockchain::new()?;
+
+    match request {
+        Request::GetHeaders { from_height } => {
+            let mut headers = bc
+                .headers()
+                .take_while(|h| h.height >= from_height)
+                .collect::<Vec<_>>();
+            headers.reverse();
+            headers.truncate(MAX_HEADERS);
+
+            Ok(Response::Headers(headers))
+        }
+        Request::GetFilters { from_height } => {
+            let mut hashes = bc
+                .headers()
+                .take_while(|h| h.height >= from_height)
+                .map(|h| h.hash())
+                .collect::<Result<Vec<_>>>()?;
+            hashes.reverse();
+            hashes.truncate(MAX_FILTERS);
+
+            let filters = hashes
+                .iter()
+                .map(|hash| bc.get_filter(hash))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Response::Filters(filters))
+        }
+        Request::GetBlock { hash } => Ok(Response::Block(bc.get_block(&hash)?)),
+        Request::GetChainInfo => {
+            let utxo_set = UTXOSet { blockchain: bc };
+            let info = ChainInfo::new(&utxo_set, &Mempool::new()?)?;
+            Ok(Response::ChainInfo(info))
+        }
+        Request::GetConsensus => Ok(Response::Consensus(bc.get_consensus().clone())),
+        Request::EstimateFee { blocks } => {
+            Ok(Response::FeeEstimate(FeeEstimate::new(&bc, blocks)?))
+        }
+        Request::GetBlockTemplate { address } => {
+            let utxo_set = UTXOSet { blockchain: bc };
+            let template = get_block_template(&utxo_set, &Mempool::new()?, &address)?;
+            Ok(Response::BlockTemplate(template))
+        }
+        Request::SubmitBlock { block } => {
+            let mut utxo_set = UTXOSet { blockchain: bc };
+            let hash = submit_block(&mut utxo_set, &Mempool::new()?, block)?;
+            Ok(Response::BlockAccepted(hash))
+        }
+    }
+}
+
+/// Returns the block extending the tip with the pending transactions that
+/// can be mined and a coinbase paying to the address, for an external miner.
+pub fn get_block_template(
+    utxo_set: &UTXOSet,
+    mempool: &Mempool,
+    address: &str,
+) -> Result<BlockTemplate> {
+    let consensus = utxo_set.blockchain.get_consensus();
+    if !consensus.is_proof_of_work() {
+        return Err(format_err!(
+            "Block templates are only made for miners, the chain uses {}",
+            consensus
+        ));
+    }
+
+    let bc = &utxo_set.blockchain;
+    let txs = bc.pack_transactions(mempool.get_spendable(utxo_set)?, address)?;
+    let block = bc.new_block_template(txs, address)?;
+    info!(
+        "Block template at height {} with {} transactions",
+        block.get_height(),
+        block.get_transactions().len()
+    );
+
+    Ok(block.into())
+}
+
+/// Checks a block template mined by an external miner and adds it to the
+/// chain. The transactions of the blocks joining the chain are no longer
+/// pending, those of the blocks a reorganization drops are pending again.
+pub fn submit_block(
+    utxo_set: &mut UTXOSet,
+    mempool: &Mempool,
+    template: BlockTemplate,
+) -> Result<BlockHash> {
+    let block = Block::from_template(template)?;
+
+    let (connected, disconnected) = match utxo_set.submit_block(&block)? {
+        ChainUpdate::Extended => (vec![block.clone()], Vec::new()),
+        ChainUpdate::SideBranch => (Vec::new(), Vec::new()),
+        ChainUpdate::Reorganized {
+            connected,
+            disconnected,
+        } => (connected, disconnected),
+    };
+    for tx in connected.iter().flat_map(|b| b.get_transactions()) {
+        mempool.remove(&tx.id)?;
+    }
+    for tx in disconnected.iter().flat_map(|b| b.get_transactions()) {
+        if tx.is_coinbase() {
+            continue;
+        }
+        let added = mempool
+            .check_transaction(tx, utxo_set)
+            .and_then(|_| mempool.add(tx, &utxo_set.blockchain));
+        if let Err(e) = added {
+            debug!(
+                "Transaction {} of a dropped block isn't pending: {}",
+                tx.id, e
+            );
+        }
+    }
+    info!("Accepted block {}", block.get_hash());
+
+    Ok(block.get_hash())
+}
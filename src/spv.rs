@@ -0,0 +1,461 @@
+use crate::{
+    amount::Amount,
+    block::{Block, BlockHeader},
+    blockchain::{branch_headers, branches, BLOCKS_DB_VERSION},
+    consensus::{ConsensusConfig, ConsensusEngine},
+    db,
+    encoding::{Decode, Encode},
+    errors::Result,
+    filter::{outpoint_element, script_element},
+    hash::BlockHash,
+    merkle::verify_merkle_proof,
+    script::Script,
+    server::{send_request, ProvenTransaction, Request, Response},
+    transaction::tx::OutPoint,
+};
+use failure::format_err;
+use log::info;
+use std::collections::HashSet;
+
+/// Name of the tree of the light client DB holding block headers.
+const HEADERS_TREE: &str = "headers";
+
+/// Name of the tree of the light client DB holding proven transactions.
+const TRANSACTIONS_TREE: &str = "transactions";
+
+/// LightClient follows the chain of a full node keeping only the block
+/// headers and the transactions of its addresses, each checked against the
+/// headers with a merkle proof. The addresses are never sent to the node:
+/// the client tests the block filters locally and only downloads the blocks
+/// that match.
+pub struct LightClient {
+    db: sled::Db,
+    headers: sled::Tree,
+    transactions: sled::Tree,
+}
+
+/// SyncReport tells what a sync with the node added.
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    pub headers: usize,
+    pub transactions: usize,
+    /// Number of blocks downloaded because their filter matched.
+    pub blocks: usize,
+    pub height: Option<usize>,
+}
+
+impl LightClient {
+    /// Opens the light client db. It follows the format version of the
+    /// blocks DB, as both hold headers and transactions.
+    pub fn new() -> Result<LightClient> {
+        let db = db::open(db::DB_SPV)?;
+        match db.get("VERSION")? {
+            Some(v) if bincode::deserialize::<u32>(&v)? == BLOCKS_DB_VERSION => {}
+            _ if db.get("TIP")?.is_some() => {
+                return Err(format_err!(
+                    "Light client database has an old format, run `blockchain migrate` to upgrade it"
+                ));
+            }
+            _ => {
+                db.insert("VERSION", bincode::serialize(&BLOCKS_DB_VERSION)?)?;
+            }
+        }
+        let headers = db.open_tree(HEADERS_TREE)?;
+        let transactions = db.open_tree(TRANSACTIONS_TREE)?;
+
+        Ok(LightClient {
+            db,
+            headers,
+            transactions,
+        })
+    }
+
+    /// Downloads the new headers and the transactions of the addresses from
+    /// the node.
+    pub fn sync(&self, node: &str, addresses: &[String]) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+        let engine = self.get_consensus(node)?.engine()?;
+
+        // the headers are asked from the tip on, which shows when the node
+        // follows another branch, and further back until they connect
+        let mut back = 0;
+        loop {
+            let from_height = self
+                .get_tip()?
+                .map_or(0, |(_, h)| h.height.saturating_sub(back));
+            let headers = match send_request(node, &Request::GetHeaders { from_height })? {
+                Response::Headers(headers) => headers,
+                _ => return Err(format_err!("Unexpected response to GetHeaders")),
+            };
+
+            let connects = match headers.first() {
+                Some(h) => h.height == 0 || self.headers.contains_key(h.prev_block_hash)?,
+                None => from_height == 0,
+            };
+            if !connects {
+                if from_height == 0 {
+                    return Err(format_err!("Node follows another chain"));
+                }
+                back = (back * 2).max(1);
+                continue;
+            }
+
+            let added = self.add_headers(headers, engine.as_ref())?;
+            if added == 0 {
+                break;
+            }
+            report.headers += added;
+            back = 0;
+        }
+
+        // a changed set of addresses needs the whole chain to be scanned again
+        let mut addresses = addresses.to_vec();
+        addresses.sort();
+        let tracked = bincode::serialize(&addresses)?;
+        let from_height = if self.db.get("ADDRESSES")?.as_deref() == Some(&tracked[..]) {
+            match self.db.get("SCANNED")? {
+                Some(h) => bincode::deserialize::<usize>(&h)? + 1,
+                None => 0,
+            }
+        } else {
+            self.transactions.clear()?;
+            0
+        };
+
+        let scripts = addresses
+            .iter()
+            .map(|a| Script::from_address(a))
+            .collect::<Result<HashSet<_>>>()?;
+        let mut outpoints = self
+            .get_outpoints(&addresses)?
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        let mut height = from_height;
+        loop {
+            let request = Request::GetFilters {
+                from_height: height,
+            };
+            let filters = match send_request(node, &request)? {
+                Response::Filters(filters) => filters,
+                _ => return Err(format_err!("Unexpected response to GetFilters")),
+            };
+            if filters.is_empty() {
+                break;
+            }
+
+            for filter in filters {
+                let header = self.get_header(&filter.block_hash)?;
+                if header.height != height {
+                    return Err(format_err!(
+                        "Filter of block {} is not at height {}",
+                        filter.block_hash,
+                        height
+                    ));
+                }
+
+                let elements = scripts
+                    .iter()
+                    .filter_map(script_element)
+                    .chain(outpoints.iter().map(outpoint_element))
+                    .collect::<Vec<_>>();
+                if filter.matches_any(&elements)? {
+                    report.blocks += 1;
+                    let block = self.get_block(node, &filter.block_hash)?;
+                    report.transactions += self.scan_block(&block, &scripts, &mut outpoints)?;
+                }
+
+                self.db.insert("SCANNED", bincode::serialize(&height)?)?;
+                height += 1;
+            }
+        }
+
+        report.height = self.get_tip()?.map(|(_, h)| h.height);
+        self.db.insert("ADDRESSES", tracked)?;
+        self.db.flush()?;
+
+        Ok(report)
+    }
+
+    /// Returns the balance of the address from the proven transactions.
+    pub fn get_balance(&self, address: &str) -> Result<Amount> {
+        let spent = self
+            .get_transactions()?
+            .iter()
+            .flat_map(|p| p.tx.vin.iter())
+            .map(|vin| OutPoint {
+                txid: vin.txid,
+                vout: vin.vout,
+            })
+            .collect::<HashSet<_>>();
+
+        let unspent = self
+            .get_outpoints(&[address.to_string()])?
+            .into_iter()
+            .filter(|o| !spent.contains(o))
+            .collect::<HashSet<_>>();
+
+        let mut values = Vec::new();
+        for p in self.get_transactions()? {
+            for (vout, out) in p.tx.vout.iter().enumerate() {
+                let outpoint = OutPoint {
+                    txid: p.tx.id,
+                    vout: vout as i32,
+                };
+                if unspent.contains(&outpoint) {
+                    values.push(out.value);
+                }
+            }
+        }
+
+        Amount::sum(values)
+    }
+
+    /// Returns the consensus config the headers are checked with. It is
+    /// asked from the node on the first sync, which the client trusts like
+    /// the genesis block, and kept from then on.
+    fn get_consensus(&self, node: &str) -> Result<ConsensusConfig> {
+        if let Some(c) = self.db.get("CONSENSUS")? {
+            return Ok(bincode::deserialize(&c)?);
+        }
+
+        let consensus = match send_request(node, &Request::GetConsensus)? {
+            Response::Consensus(consensus) => consensus,
+            _ => return Err(format_err!("Unexpected response to GetConsensus")),
+        };
+        info!("Following a chain with {}", consensus);
+        self.db
+            .insert("CONSENSUS", bincode::serialize(&consensus)?)?;
+
+        Ok(consensus)
+    }
+
+    /// Returns the last header and its hash.
+    pub fn get_tip(&self) -> Result<Option<(BlockHash, BlockHeader)>> {
+        let hash = match self.db.get("TIP")? {
+            Some(h) => BlockHash::from_slice(&h)?,
+            None => return Ok(None),
+        };
+
+        Ok(Some((hash, self.get_header(&hash)?)))
+    }
+
+    fn get_header(&self, hash: &BlockHash) -> Result<BlockHeader> {
+        match self.headers.get(hash)? {
+            Some(h) => BlockHeader::decode(&h),
+            None => Err(format_err!("Block {} is not found", hash)),
+        }
+    }
+
+    /// Checks that the headers extend stored headers with seals the engine
+    /// accepts and stores them. The last header of a branch becomes the tip
+    /// if it extends the tip or if the fork choice prefers its branch.
+    /// Returns the number of headers not stored before.
+    fn add_headers(
+        &self,
+        headers: Vec<BlockHeader>,
+        engine: &dyn ConsensusEngine,
+    ) -> Result<usize> {
+        let mut added = 0;
+
+        for header in headers {
+            let hash = header.hash()?;
+            if self.headers.contains_key(hash)? {
+                continue;
+            }
+
+            let tip = self.get_tip()?;
+            let height = match &tip {
+                None if header.prev_block_hash == BlockHash::ZERO => 0,
+                None => return Err(format_err!("Header {} isn't a genesis block", hash)),
+                Some(_) => self.get_header(&header.prev_block_hash)?.height + 1,
+            };
+            if header.height != height {
+                return Err(format_err!(
+                    "Header {} doesn't extend the chain at height {}",
+                    hash,
+                    height
+                ));
+            }
+            // older headers may have older versions, but never another target
+            let mut expected = header.clone();
+            engine.prepare(&mut expected);
+            if header.target != expected.target {
+                return Err(format_err!(
+                    "Header {} has target {}, {} is required",
+                    hash,
+                    header.target,
+                    expected.target
+                ));
+            }
+            engine.verify_header(&header)?;
+
+            self.headers.insert(hash.as_bytes(), header.encode())?;
+            added += 1;
+            match tip {
+                Some((tip_hash, _)) if header.prev_block_hash != tip_hash => {
+                    self.choose_fork(hash, tip_hash, engine)?;
+                }
+                _ => {
+                    self.db.insert("TIP", hash.as_bytes())?;
+                }
+            }
+        }
+
+        Ok(added)
+    }
+
+    /// Makes the stored header the tip if the fork choice prefers its branch
+    /// to the one of the tip. The transactions of the dropped headers are
+    /// removed and the blocks of the new branch are scanned again.
+    fn choose_fork(
+        &self,
+        hash: BlockHash,
+        tip_hash: BlockHash,
+        engine: &dyn ConsensusEngine,
+    ) -> Result<()> {
+        let (candidate, current) = branches(hash, tip_hash, |h| self.get_header(h))?;
+        if !engine.prefers(&branch_headers(&candidate), &branch_headers(&current)) {
+            info!("Header {} is kept on a side branch", hash);
+            return Ok(());
+        }
+
+        let dropped = current.iter().map(|(h, _)| *h).collect::<HashSet<_>>();
+        for proven in self.get_transactions()? {
+            if dropped.contains(&proven.block_hash) {
+                self.transactions.remove(proven.tx.id)?;
+            }
+        }
+        if let Some(h) = self.db.get("SCANNED")? {
+            // the blocks up to the one the branches share stay scanned
+            let shared = candidate[0].1.height - 1;
+            if bincode::deserialize::<usize>(&h)? > shared {
+                self.db.insert("SCANNED", bincode::serialize(&shared)?)?;
+            }
+        }
+
+        info!(
+            "Switched to header {}, dropping {} headers",
+            hash,
+            dropped.len()
+        );
+        self.db.insert("TIP", hash.as_bytes())?;
+        Ok(())
+    }
+
+    /// Downloads the block from the node and checks that its transactions
+    /// match the stored header.
+    fn get_block(&self, node: &str, hash: &BlockHash) -> Result<Block> {
+        let block = match send_request(node, &Request::GetBlock { hash: *hash })? {
+            Response::Block(block) => block,
+            _ => return Err(format_err!("Unexpected response to GetBlock")),
+        };
+
+        let header = self.get_header(hash)?;
+        if block.get_hash() != *hash || block.hash_transactions()? != header.merkle_root {
+            return Err(format_err!("Block {} doesn't match its header", hash));
+        }
+
+        Ok(block)
+    }
+
+    /// Stores the transactions of the block paying to the scripts or spending
+    /// the outpoints, which grow with the outputs paying to the scripts.
+    /// Returns the number of transactions stored.
+    fn scan_block(
+        &self,
+        block: &Block,
+        scripts: &HashSet<Script>,
+        outpoints: &mut HashSet<OutPoint>,
+    ) -> Result<usize> {
+        let mut count = 0;
+
+        for tx in block.get_transactions() {
+            let spends = tx.vin.iter().any(|vin| {
+                outpoints.contains(&OutPoint {
+                    txid: vin.txid,
+                    vout: vin.vout,
+                })
+            });
+
+            let mut pays = false;
+            for (vout, out) in tx.vout.iter().enumerate() {
+                if scripts.contains(&out.script_pubkey) {
+                    pays = true;
+                    outpoints.insert(OutPoint {
+                        txid: tx.id,
+                        vout: vout as i32,
+                    });
+                }
+            }
+
+            if spends || pays {
+                let proof = block
+                    .merkle_proof(&tx.id)?
+                    .ok_or_else(|| format_err!("Can't prove transaction {}", tx.id))?;
+                self.add_transaction(ProvenTransaction {
+                    block_hash: block.get_hash(),
+                    tx: tx.clone(),
+                    proof,
+                })?;
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Checks the proof of the transaction against the stored headers and
+    /// stores it.
+    fn add_transaction(&self, proven: ProvenTransaction) -> Result<()> {
+        let header = self.get_header(&proven.block_hash)?;
+
+        if proven.tx.id != proven.tx.hash()?
+            || !verify_merkle_proof(&header.merkle_root, &proven.tx.id, &proven.proof)?
+        {
+            return Err(format_err!(
+                "Proof of transaction {} doesn't match block {}",
+                proven.tx.id,
+                proven.block_hash
+            ));
+        }
+
+        info!("add proven Transaction: {}", proven.tx.id);
+        self.transactions
+            .insert(proven.tx.id, bincode::serialize(&proven)?)?;
+        Ok(())
+    }
+
+    fn get_transactions(&self) -> Result<Vec<ProvenTransaction>> {
+        let mut txs = Vec::new();
+
+        for kv in self.transactions.iter() {
+            let (_, v) = kv?;
+            txs.push(bincode::deserialize(&v)?);
+        }
+
+        Ok(txs)
+    }
+
+    /// Returns the outputs of the proven transactions paying to the addresses.
+    fn get_outpoints(&self, addresses: &[String]) -> Result<Vec<OutPoint>> {
+        let scripts = addresses
+            .iter()
+            .map(|a| Script::from_address(a))
+            .collect::<Result<HashSet<_>>>()?;
+        let mut outpoints = Vec::new();
+
+        for p in self.get_transactions()? {
+            for (vout, out) in p.tx.vout.iter().enumerate() {
+                if scripts.contains(&out.script_pubkey) {
+                    outpoints.push(OutPoint {
+                        txid: p.tx.id,
+                        vout: vout as i32,
+                    });
+                }
+            }
+        }
+
+        Ok(outpoints)
+    }
+}
@@ -0,0 +1,288 @@
+use super::{
+    coinselect::{CoinSelection, UnspentOutput},
+    raw::{PartialInput, RawTransaction},
+    tx::{RelativeLock, TXInput, TXOutput, SEQUENCE_FINAL, SEQUENCE_LOCKTIME_ENABLED},
+    utxoset::UTXOSet,
+    Transaction, TX_VERSION,
+};
+use crate::{
+    amount::{self, Amount},
+    errors::Result,
+    hash::Txid,
+    script::Script,
+};
+use failure::format_err;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Payment is an amount of money paid to an address.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Payment {
+    pub address: String,
+    #[serde(with = "amount::serde_coins")]
+    pub amount: Amount,
+}
+
+/// TransactionBuilder collects payments from one address into a single
+/// transaction with at most one change output.
+#[derive(Debug, Clone)]
+pub struct TransactionBuilder {
+    from: String,
+    payments: Vec<Payment>,
+    fee: Amount,
+    coin_selection: CoinSelection,
+    lock_time: u32,
+    relative_lock: Option<RelativeLock>,
+    data: Vec<Vec<u8>>,
+    unconfirmed: Vec<UnspentOutput>,
+}
+
+impl TransactionBuilder {
+    /// Creates a builder of a transaction paid from the address.
+    pub fn new(from: &str) -> TransactionBuilder {
+        TransactionBuilder {
+            from: from.to_string(),
+            payments: Vec::new(),
+            fee: Amount::ZERO,
+            coin_selection: CoinSelection::default(),
+            lock_time: 0,
+            relative_lock: None,
+            data: Vec::new(),
+            unconfirmed: Vec::new(),
+        }
+    }
+
+    /// Sets the fee paid to the miner of the transaction.
+    pub fn fee(mut self, fee: Amount) -> TransactionBuilder {
+        self.fee = fee;
+        self
+    }
+
+    /// Sets the strategy choosing the outputs to spend.
+    pub fn coin_selection(mut self, coin_selection: CoinSelection) -> TransactionBuilder {
+        self.coin_selection = coin_selection;
+        self
+    }
+
+    /// Sets the height or time before which the transaction can't be mined.
+    pub fn lock_time(mut self, lock_time: u32) -> TransactionBuilder {
+        self.lock_time = lock_time;
+        self
+    }
+
+    /// Locks the spent outputs until they are old enough.
+    pub fn relative_lock(mut self, relative_lock: Option<RelativeLock>) -> TransactionBuilder {
+        self.relative_lock = relative_lock;
+        self
+    }
+
+    /// Lets the transaction also spend the outputs of pending transactions.
+    pub fn unconfirmed(mut self, outputs: Vec<UnspentOutput>) -> TransactionBuilder {
+        self.unconfirmed = outputs;
+        self
+    }
+
+    /// Adds a payment of the amount to the address.
+    pub fn pay(mut self, to: &str, amount: Amount) -> TransactionBuilder {
+        self.payments.push(Payment {
+            address: to.to_string(),
+            amount,
+        });
+        self
+    }
+
+    /// Adds an unspendable output carrying the data.
+    pub fn data(mut self, data: &[u8]) -> TransactionBuilder {
+        self.data.push(data.to_vec());
+        self
+    }
+
+    /// Adds all the payments.
+    pub fn pay_all(mut self, payments: Vec<Payment>) -> TransactionBuilder {
+        self.payments.extend(payments);
+        self
+    }
+
+    /// Returns the total amount paid to the recipients.
+    pub fn total(&self) -> Result<Amount> {
+        Amount::sum(self.payments.iter().map(|p| p.amount))
+    }
+
+    /// Creates an unsigned transaction spending outputs of the source address.
+    pub fn build(&self, utxo_set: &UTXOSet) -> Result<RawTransaction> {
+        info!(
+            "new raw Transaction from: {} to {} recipients",
+            self.from,
+            self.payments.len()
+        );
+
+        if self.payments.is_empty() && self.data.is_empty() {
+            return Err(format_err!("Transaction has no recipients"));
+        }
+
+        for p in &self.payments {
+            if p.amount.is_zero() {
+                return Err(format_err!(
+                    "Amount paid to '{}' must be positive",
+                    p.address
+                ));
+            }
+        }
+
+        let amount = self
+            .total()?
+            .checked_add(self.fee)
+            .ok_or_else(|| format_err!("Total amount of the payments overflows"))?;
+        let script_pubkey = Script::from_address(&self.from)?;
+        let mut available = utxo_set.find_spendable_outputs(&script_pubkey)?;
+        available.extend(self.unconfirmed.iter().cloned());
+        let selected = self
+            .coin_selection
+            .selector()
+            // a transaction spends at least one output even if it pays nothing
+            .select(&available, amount.max(Amount::from_units(1)))
+            .ok_or_else(|| {
+                error!("Not Enough balance");
+                format_err!(
+                    "Not Enough balance: current balance {}",
+                    Amount::sum(available.iter().map(|u| u.output.value)).unwrap_or(Amount::MAX)
+                )
+            })?;
+        let accumulated = Amount::sum(selected.iter().map(|u| u.output.value))?;
+
+        let mut vout = self
+            .payments
+            .iter()
+            .map(|p| TXOutput::new(p.amount, p.address.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        vout.extend(self.data.iter().map(|data| TXOutput::new_data(data)));
+        if let Some(change) = accumulated.checked_sub(amount).filter(|c| !c.is_zero()) {
+            vout.push(TXOutput::new(change, self.from.clone())?)
+        }
+
+        let sequence = match self.relative_lock {
+            Some(lock) => lock.to_sequence(),
+            None if self.lock_time != 0 => SEQUENCE_LOCKTIME_ENABLED,
+            None => SEQUENCE_FINAL,
+        };
+
+        new_raw_transaction(selected, vout, self.lock_time, sequence)
+    }
+}
+
+/// Creates an unsigned transaction joining the unspent outputs of the
+/// address with a value up to the threshold into a single output.
+pub fn consolidate(
+    address: &str,
+    threshold: Option<Amount>,
+    utxo_set: &UTXOSet,
+) -> Result<RawTransaction> {
+    info!("new consolidation Transaction of: {}", address);
+
+    let script_pubkey = Script::from_address(address)?;
+    let threshold = threshold.unwrap_or(Amount::MAX);
+    let dust = utxo_set
+        .find_spendable_outputs(&script_pubkey)?
+        .into_iter()
+        .filter(|u| u.output.value <= threshold)
+        .collect::<Vec<_>>();
+
+    if dust.len() < 2 {
+        return Err(format_err!(
+            "Nothing to consolidate: {} outputs found",
+            dust.len()
+        ));
+    }
+
+    let total = Amount::sum(dust.iter().map(|u| u.output.value))?;
+
+    new_raw_transaction(
+        dust,
+        vec![TXOutput::new(total, address.to_string())?],
+        0,
+        SEQUENCE_FINAL,
+    )
+}
+
+/// Creates an unsigned transaction spending the unspent outputs.
+pub(super) fn new_raw_transaction(
+    spent: Vec<UnspentOutput>,
+    vout: Vec<TXOutput>,
+    lock_time: u32,
+    sequence: u32,
+) -> Result<RawTransaction> {
+    let mut vin = Vec::new();
+    let mut prev_outputs = Vec::new();
+
+    for u in spent {
+        vin.push(TXInput {
+            txid: u.txid,
+            vout: u.vout,
+            script_sig: Script::default(),
+            sequence,
+        });
+        prev_outputs.push(u.output);
+    }
+
+    let mut tx = Transaction {
+        id: Txid::ZERO,
+        version: TX_VERSION,
+        vin,
+        vout,
+        lock_time,
+    };
+    tx.id = tx.hash()?;
+
+    Ok(RawTransaction {
+        partial_inputs: vec![PartialInput::default(); tx.vin.len()],
+        tx,
+        prev_outputs,
+    })
+}
+
+/// Parses a payment written as `ADDRESS:AMOUNT`.
+pub fn parse_payment(s: &str) -> Result<Payment> {
+    let (address, amount) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format_err!("Payment '{}' must be ADDRESS:AMOUNT", s))?;
+
+    Ok(Payment {
+        address: address.trim().to_string(),
+        amount: amount.trim().parse()?,
+    })
+}
+
+/// Reads payments from a JSON file holding an array of `address` and
+/// `amount` objects, or from a CSV file with an `address,amount` per line.
+pub fn read_payments(path: &Path) -> Result<Vec<Payment>> {
+    let data = std::fs::read_to_string(path)?;
+
+    if path.extension().is_some_and(|ext| ext == "json") || data.trim_start().starts_with('[') {
+        return serde_json::from_str(&data).map_err(|e| e.into());
+    }
+
+    let mut payments = Vec::new();
+
+    for (n, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("address,") {
+            continue;
+        }
+
+        let (address, amount) = line
+            .split_once(',')
+            .ok_or_else(|| format_err!("Line {}: expected address,amount", n + 1))?;
+        let amount = amount
+            .trim()
+            .parse()
+            .map_err(|e| format_err!("Line {}: invalid amount: {}", n + 1, e))?;
+
+        payments.push(Payment {
+            address: address.trim().to_string(),
+            amount,
+        });
+    }
+
+    Ok(payments)
+}
@@ -0,0 +1,215 @@
+use super::tx::TXOutput;
+use crate::{amount::Amount, hash::Txid};
+use clap::ValueEnum;
+use rand::seq::SliceRandom;
+use std::cmp::Reverse;
+
+/// Maximum number of branches the branch-and-bound search explores.
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// UnspentOutput is an output that can be spent by a new transaction.
+#[derive(Debug, Clone)]
+pub struct UnspentOutput {
+    pub txid: Txid,
+    pub vout: i32,
+    pub output: TXOutput,
+}
+
+/// CoinSelector chooses which unspent outputs fund a transaction.
+pub trait CoinSelector {
+    /// Returns outputs holding at least the amount, or `None` if the
+    /// available outputs are not enough.
+    fn select(&self, available: &[UnspentOutput], amount: Amount) -> Option<Vec<UnspentOutput>>;
+}
+
+/// Takes outputs in the order they are stored in the UTXO set.
+pub struct InOrder;
+
+/// Takes the largest outputs first, spending as few outputs as possible.
+pub struct LargestFirst;
+
+/// Takes the smallest outputs first, cleaning up small outputs.
+pub struct SmallestFirst;
+
+/// Searches for outputs matching the amount exactly, so no change output is
+/// needed. Falls back to largest-first if there is no exact match.
+pub struct BranchAndBound;
+
+/// Takes outputs in random order.
+pub struct RandomOrder;
+
+/// Coin selection strategies available to the user.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum CoinSelection {
+    #[default]
+    InOrder,
+    LargestFirst,
+    SmallestFirst,
+    BranchAndBound,
+    Random,
+}
+
+impl CoinSelection {
+    /// Returns the selector implementing the strategy.
+    pub fn selector(&self) -> Box<dyn CoinSelector> {
+        match self {
+            CoinSelection::InOrder => Box::new(InOrder),
+            CoinSelection::LargestFirst => Box::new(LargestFirst),
+            CoinSelection::SmallestFirst => Box::new(SmallestFirst),
+            CoinSelection::BranchAndBound => Box::new(BranchAndBound),
+            CoinSelection::Random => Box::new(RandomOrder),
+        }
+    }
+}
+
+impl CoinSelector for InOrder {
+    fn select(&self, available: &[UnspentOutput], amount: Amount) -> Option<Vec<UnspentOutput>> {
+        accumulate(available.iter(), amount)
+    }
+}
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, available: &[UnspentOutput], amount: Amount) -> Option<Vec<UnspentOutput>> {
+        let mut sorted = available.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|u| Reverse(u.output.value));
+        accumulate(sorted.into_iter(), amount)
+    }
+}
+
+impl CoinSelector for SmallestFirst {
+    fn select(&self, available: &[UnspentOutput], amount: Amount) -> Option<Vec<UnspentOutput>> {
+        let mut sorted = available.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|u| u.output.value);
+        accumulate(sorted.into_iter(), amount)
+    }
+}
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, available: &[UnspentOutput], amount: Amount) -> Option<Vec<UnspentOutput>> {
+        let mut sorted = available.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|u| Reverse(u.output.value));
+
+        // remaining[i] is the value of the outputs from i to the end
+        let mut remaining = vec![0u128; sorted.len() + 1];
+        for i in (0..sorted.len()).rev() {
+            remaining[i] = remaining[i + 1] + sorted[i].output.value.units() as u128;
+        }
+
+        if let Some(selected) = bnb_search(&sorted, &remaining, amount.units() as u128) {
+            return Some(selected.into_iter().map(|i| sorted[i].clone()).collect());
+        }
+
+        LargestFirst.select(available, amount)
+    }
+}
+
+impl CoinSelector for RandomOrder {
+    fn select(&self, available: &[UnspentOutput], amount: Amount) -> Option<Vec<UnspentOutput>> {
+        let mut shuffled = available.iter().collect::<Vec<_>>();
+        shuffled.shuffle(&mut rand::thread_rng());
+        accumulate(shuffled.into_iter(), amount)
+    }
+}
+
+/// Takes outputs until their value reaches the amount.
+fn accumulate<'a>(
+    outputs: impl Iterator<Item = &'a UnspentOutput>,
+    amount: Amount,
+) -> Option<Vec<UnspentOutput>> {
+    let mut accumulated: u128 = 0;
+    let mut selected = Vec::new();
+
+    for out in outputs {
+        if accumulated >= amount.units() as u128 {
+            break;
+        }
+
+        accumulated += out.output.value.units() as u128;
+        selected.push(out.clone());
+    }
+
+    if accumulated >= amount.units() as u128 {
+        Some(selected)
+    } else {
+        None
+    }
+}
+
+/// Depth-first search for outputs that sum up to `target`, trying each output
+/// before skipping it. Returns their indexes, or `None` if there is no match
+/// within the tries. The branch is kept in `selected` rather than on the call
+/// stack, which a wallet with many outputs would overflow.
+fn bnb_search(sorted: &[&UnspentOutput], remaining: &[u128], target: u128) -> Option<Vec<usize>> {
+    let value = |i: usize| sorted[i].output.value.units() as u128;
+    let mut selected: Vec<usize> = Vec::new();
+    let mut index = 0;
+    let mut target = target;
+
+    for _ in 0..BNB_MAX_TRIES {
+        if target == 0 {
+            return Some(selected);
+        }
+
+        if index < sorted.len() && remaining[index] >= target {
+            if value(index) <= target {
+                selected.push(index);
+                target -= value(index);
+            }
+            index += 1;
+        } else {
+            // go back to the last output taken and skip it instead
+            let last = selected.pop()?;
+            target += value(last);
+            index = last + 1;
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::Script;
+
+    fn unspent(values: &[u64]) -> Vec<UnspentOutput> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(vout, value)| UnspentOutput {
+                txid: Txid::ZERO,
+                vout: vout as i32,
+                output: TXOutput {
+                    value: Amount::from_units(*value),
+                    script_pubkey: Script::default(),
+                },
+            })
+            .collect()
+    }
+
+    fn total(selected: &[UnspentOutput]) -> u64 {
+        selected.iter().map(|u| u.output.value.units()).sum()
+    }
+
+    #[test]
+    fn branch_and_bound_matches_amount() {
+        let available = unspent(&[7, 5, 4, 3]);
+        let selected = BranchAndBound
+            .select(&available, Amount::from_units(9))
+            .unwrap();
+        assert_eq!(total(&selected), 9);
+        assert_eq!(selected.len(), 2);
+    }
+
+    #[test]
+    fn branch_and_bound_searches_many_outputs() {
+        let mut values = vec![2; 60_000];
+        values.push(1);
+        let available = unspent(&values);
+
+        let selected = BranchAndBound
+            .select(&available, Amount::from_units(80_001))
+            .unwrap();
+        assert_eq!(total(&selected), 80_001);
+    }
+}
@@ -0,0 +1,119 @@
+use super::{
+    builder::new_raw_transaction,
+    tx::{TXOutput, SEQUENCE_FINAL, SEQUENCE_LOCKTIME_ENABLED},
+    utxoset::UTXOSet,
+    Transaction,
+};
+use crate::{
+    amount::Amount,
+    blockchain::Blockchain,
+    errors::Result,
+    script::{Htlc, Script},
+    wallet::Wallet,
+};
+use failure::format_err;
+use log::info;
+use sha2::{Digest, Sha256};
+
+/// Creates a transaction paying the money locked in the HTLC to the
+/// recipient, revealing the preimage of the payment hash.
+pub fn claim(
+    redeem_script: &Script,
+    preimage: &[u8],
+    wallet: &Wallet,
+    utxo_set: &UTXOSet,
+) -> Result<Transaction> {
+    let htlc = get_htlc(redeem_script)?;
+    if Sha256::digest(preimage).as_slice() != htlc.payment_hash {
+        return Err(format_err!("Secret doesn't match the HTLC payment hash"));
+    }
+    if wallet.get_pub_key_hash() != htlc.recipient_pub_key_hash {
+        return Err(format_err!("Wallet is not the HTLC recipient"));
+    }
+
+    spend(
+        redeem_script,
+        wallet,
+        utxo_set,
+        0,
+        SEQUENCE_FINAL,
+        |signature| Script::new_htlc_claim(signature, &wallet.public_key, preimage, redeem_script),
+    )
+}
+
+/// Creates a transaction paying the money locked in an expired HTLC back to
+/// the sender.
+pub fn refund(redeem_script: &Script, wallet: &Wallet, utxo_set: &UTXOSet) -> Result<Transaction> {
+    let htlc = get_htlc(redeem_script)?;
+    if wallet.get_pub_key_hash() != htlc.sender_pub_key_hash {
+        return Err(format_err!("Wallet is not the HTLC sender"));
+    }
+
+    spend(
+        redeem_script,
+        wallet,
+        utxo_set,
+        htlc.timeout,
+        SEQUENCE_LOCKTIME_ENABLED,
+        |signature| Script::new_htlc_refund(signature, &wallet.public_key, redeem_script),
+    )
+}
+
+/// Finds the preimage of the payment hash revealed by a transaction claiming
+/// the HTLC.
+pub fn find_preimage(redeem_script: &Script, blockchain: &Blockchain) -> Result<Option<Vec<u8>>> {
+    let htlc = get_htlc(redeem_script)?;
+
+    for block in blockchain.iter() {
+        for tx in block.get_transactions() {
+            for vin in &tx.vin {
+                if let Some(preimage) = vin.script_sig.get_htlc_preimage() {
+                    if Sha256::digest(&preimage).as_slice() == htlc.payment_hash {
+                        return Ok(Some(preimage));
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+fn get_htlc(redeem_script: &Script) -> Result<Htlc> {
+    redeem_script
+        .get_htlc()
+        .ok_or_else(|| format_err!("Script is not an HTLC"))
+}
+
+/// Creates a transaction paying all outputs locked with the redeem script to
+/// the wallet, unlocking each input with the script made from its signature.
+fn spend(
+    redeem_script: &Script,
+    wallet: &Wallet,
+    utxo_set: &UTXOSet,
+    lock_time: u32,
+    sequence: u32,
+    unlock: impl Fn(&[u8]) -> Result<Script>,
+) -> Result<Transaction> {
+    let address = redeem_script.to_p2sh_address()?;
+    info!("new HTLC spending Transaction of: {}", address);
+
+    let outputs = utxo_set.find_unspent_outputs(&Script::new_p2sh(&redeem_script.hash()?))?;
+    if outputs.is_empty() {
+        return Err(format_err!("HTLC '{}' holds no money", address));
+    }
+
+    let total = Amount::sum(outputs.iter().map(|u| u.output.value))?;
+    let vout = vec![TXOutput::new(total, wallet.get_address())?];
+    let mut raw = new_raw_transaction(outputs, vout, lock_time, sequence)?;
+
+    for in_id in 0..raw.tx.vin.len() {
+        let signature = raw
+            .tx
+            .signature(in_id, &wallet.secret_key, &raw.prev_outputs[in_id])?;
+        raw.tx.vin[in_id].script_sig = unlock(&signature)?;
+    }
+    raw.tx.id = raw.tx.hash()?;
+
+    Ok(raw.tx)
+}
@@ -1,28 +1,58 @@
+pub mod builder;
+pub mod coinselect;
+pub mod htlc;
+pub mod raw;
 pub mod tx;
 pub mod utxoset;
 
 use crate::{
+    amount::Amount,
+    encoding::{write_all, write_u32, write_u64, Decode, Encode, Reader},
     errors::Result,
-    wallet::{hash_pub_key, Wallet},
+    hash::Txid,
+    script::{
+        interpreter::{verify_script, SignatureChecker},
+        Op, Script, ScriptType,
+    },
+    wallet::Wallet,
 };
 use crypto::ed25519;
 use failure::format_err;
-use log::{error, info};
+use log::info;
 use rand::{rngs::OsRng, RngCore};
+use raw::RawTransaction;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use tx::{TXInput, TXOutput};
+use tx::{
+    TXInput, TXOutput, SEQUENCE_DISABLE_FLAG, SEQUENCE_FINAL, SEQUENCE_MASK, SEQUENCE_TYPE_FLAG,
+};
 use utxoset::UTXOSet;
 
-pub const SUBSIDY: i32 = 10;
+pub const SUBSIDY: Amount = Amount::from_coins(10);
+
+/// Lock times below the threshold are block heights, the others are unix
+/// timestamps in seconds.
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// Version of the transactions created by this node, the only one decoded.
+pub const TX_VERSION: u32 = 1;
+
+/// Size of an ed25519 secret key, made of the seed and the public key.
+const SECRET_KEY_SIZE: usize = 64;
 
 /// Transaction represents a Bitcoin transaction.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
-    pub id: String,
+    /// Hash of the transaction, which commits to its signatures. It isn't
+    /// part of the encoding.
+    pub id: Txid,
+    /// Version of the encoding of the transaction.
+    pub version: u32,
     pub vin: Vec<TXInput>,
     pub vout: Vec<TXOutput>,
+    /// Height or time before which the transaction can't be mined, unless
+    /// all its inputs are final.
+    pub lock_time: u32,
 }
 
 impl Transaction {
@@ -30,7 +60,7 @@ impl Transaction {
     pub fn new_utxo(
         wallet: &Wallet,
         to: &str,
-        amount: i32,
+        amount: Amount,
         utxo_set: &UTXOSet,
     ) -> Result<Transaction> {
         info!(
@@ -39,52 +69,14 @@ impl Transaction {
             to
         );
 
-        let mut vin = Vec::new();
-        let mut pub_key_hash = wallet.public_key.clone();
-        hash_pub_key(&mut pub_key_hash);
-
-        let acc_v = utxo_set.find_spendable_outputs(&pub_key_hash, amount)?;
-
-        if acc_v.0 < amount {
-            error!("Not Enough balance");
-            return Err(format_err!(
-                "Not Enough balance: current balance {}",
-                acc_v.0
-            ));
-        }
-
-        for tx in acc_v.1 {
-            for out in tx.1 {
-                let input = TXInput {
-                    txid: tx.0.clone(),
-                    vout: out,
-                    signature: Vec::new(),
-                    pub_key: wallet.public_key.clone(),
-                };
-                vin.push(input);
-            }
-        }
-
-        let mut vout = vec![TXOutput::new(amount, to.to_string())?];
-        if acc_v.0 > amount {
-            vout.push(TXOutput::new(acc_v.0 - amount, wallet.get_address())?)
-        }
-
-        let mut tx = Transaction {
-            id: String::new(),
-            vin,
-            vout,
-        };
-        tx.id = tx.hash()?;
-        utxo_set
-            .blockchain
-            .sign_transacton(&mut tx, &wallet.secret_key)?;
+        let mut raw = RawTransaction::new(&wallet.get_address(), to, amount, utxo_set)?;
+        raw.sign(wallet)?;
 
-        Ok(tx)
+        Ok(raw.tx)
     }
 
-    /// Creates a new coinbase transaction.
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
+    /// Creates a new coinbase transaction paying the reward.
+    pub fn new_coinbase(to: String, mut data: String, reward: Amount) -> Result<Transaction> {
         info!("new coinbase Transaction to: {}", to);
 
         let mut key: [u8; 32] = [0; 32];
@@ -98,14 +90,19 @@ impl Transaction {
         pub_key.append(&mut Vec::from(key));
 
         let mut tx = Transaction {
-            id: String::new(),
+            id: Txid::ZERO,
+            version: TX_VERSION,
             vin: vec![TXInput {
-                txid: String::new(),
+                txid: Txid::ZERO,
                 vout: -1,
-                signature: Vec::new(),
-                pub_key: Vec::from(data.as_bytes()),
+                script_sig: Script(vec![
+                    Op::PushData(pub_key),
+                    Op::PushData(0u64.to_le_bytes().to_vec()),
+                ]),
+                sequence: SEQUENCE_FINAL,
             }],
-            vout: vec![TXOutput::new(SUBSIDY, to)?],
+            vout: vec![TXOutput::new(reward, to)?],
+            lock_time: 0,
         };
         tx.id = tx.hash()?;
 
@@ -114,50 +111,234 @@ impl Transaction {
 
     /// Checks whether the transaction is coinbase.
     pub fn is_coinbase(&self) -> bool {
-        self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
+        self.vin.len() == 1 && self.vin[0].txid.is_zero() && self.vin[0].vout == -1
+    }
+
+    /// Returns the extra nonce of a coinbase, which the miner changes when no
+    /// nonce of the block header meets the target.
+    pub fn get_extra_nonce(&self) -> Option<u64> {
+        if !self.is_coinbase() {
+            return None;
+        }
+
+        match self.vin[0].script_sig.0.get(1) {
+            Some(Op::PushData(data)) => Some(u64::from_le_bytes(data.as_slice().try_into().ok()?)),
+            _ => None,
+        }
+    }
+
+    /// Sets the extra nonce of a coinbase, the push following its data.
+    pub fn set_extra_nonce(&mut self, extra_nonce: u64) -> Result<()> {
+        if !self.is_coinbase() {
+            return Err(format_err!(
+                "Transaction {} is not a coinbase and has no extra nonce",
+                self.id
+            ));
+        }
+
+        let ops = &mut self.vin[0].script_sig.0;
+        ops.truncate(1);
+        ops.push(Op::PushData(extra_nonce.to_le_bytes().to_vec()));
+        self.id = self.hash()?;
+        Ok(())
+    }
+
+    /// Checks whether the lock time of the transaction allows it to be mined
+    /// in a block at the height and time.
+    pub fn is_final(&self, height: usize, time: i64) -> bool {
+        if self.lock_time == 0 || self.vin.iter().all(|vin| vin.sequence == SEQUENCE_FINAL) {
+            return true;
+        }
+
+        if self.lock_time < LOCKTIME_THRESHOLD {
+            (self.lock_time as usize) < height
+        } else {
+            (self.lock_time as i64) < time
+        }
     }
 
     /// Signs each input of a transaction.
-    pub fn sign(
-        &mut self,
-        private_key: &[u8],
-        prev_txs: HashMap<String, Transaction>,
-    ) -> Result<()> {
+    pub fn sign(&mut self, private_key: &[u8], prev_txs: HashMap<Txid, Transaction>) -> Result<()> {
         if self.is_coinbase() {
             return Ok(());
         }
 
+        if private_key.len() != SECRET_KEY_SIZE {
+            return Err(format_err!(
+                "Private key has {} bytes, expected {}",
+                private_key.len(),
+                SECRET_KEY_SIZE
+            ));
+        }
+        // the secret key ends with the public key
+        let pub_key = &private_key[32..];
+
         for vin in &self.vin {
-            if prev_txs.get(&vin.txid).unwrap().id.is_empty() {
+            if prev_txs.get(&vin.txid).unwrap().id.is_zero() {
                 return Err(format_err!("ERROR: Previous transaction is not correct"));
             }
         }
 
-        let mut tx_copy = self.trim_copy();
+        for in_id in 0..self.vin.len() {
+            let prev_tx = prev_txs.get(&self.vin[in_id].txid).unwrap();
+            let prev_out = prev_tx.vout[self.vin[in_id].vout as usize].clone();
+            let signature = self.signature(in_id, private_key, &prev_out)?;
+            self.vin[in_id].script_sig = Script::new_p2pkh_unlock(&signature, pub_key);
+        }
+        self.id = self.hash()?;
 
-        for in_id in 0..tx_copy.vin.len() {
-            let prev_tx = prev_txs.get(&tx_copy.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key = Vec::new();
-            let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
-            self.vin[in_id].signature = signature.to_vec();
+        Ok(())
+    }
+
+    /// Returns the signature of an input spending the output.
+    pub fn signature(
+        &self,
+        in_id: usize,
+        private_key: &[u8],
+        prev_out: &TXOutput,
+    ) -> Result<Vec<u8>> {
+        let data = self.signature_hash(in_id, prev_out)?;
+        Ok(ed25519::signature(data.as_bytes(), private_key).to_vec())
+    }
+
+    /// Verifies the id and the scripts of the transaction inputs.
+    pub fn verify(&self, prev_txs: HashMap<Txid, Transaction>) -> Result<bool> {
+        if self.id != self.hash()? {
+            return Err(format_err!(
+                "Transaction {} doesn't match its hash",
+                self.id
+            ));
+        }
+        if self.is_coinbase() {
+            return Ok(true);
+        }
+
+        if self.vin.is_empty() {
+            return Err(format_err!("Transaction {} spends nothing", self.id));
+        }
+        self.fee(&prev_txs)?;
+
+        for in_id in 0..self.vin.len() {
+            let prev_out = Transaction::prev_output(&self.vin[in_id], &prev_txs)?;
+            let checker = TransactionChecker {
+                tx: self,
+                in_id,
+                prev_out,
+            };
+
+            if let Err(e) = verify_script(
+                &self.vin[in_id].script_sig,
+                &prev_out.script_pubkey,
+                &checker,
+            ) {
+                info!(
+                    "input {} of Transaction {} is invalid: {}",
+                    in_id, self.id, e
+                );
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Returns the fee paid by the transaction, the value of its inputs left
+    /// after paying its outputs.
+    pub fn fee(&self, prev_txs: &HashMap<Txid, Transaction>) -> Result<Amount> {
+        if self.is_coinbase() {
+            return Ok(Amount::ZERO);
+        }
+
+        let input_value = Amount::sum(
+            self.vin
+                .iter()
+                .map(|vin| Transaction::prev_output(vin, prev_txs).map(|out| out.value))
+                .collect::<Result<Vec<_>>>()?,
+        )?;
+        let output_value = self.output_value()?;
+
+        input_value.checked_sub(output_value).ok_or_else(|| {
+            format_err!(
+                "Transaction {} spends {} but its inputs hold {}",
+                self.id,
+                output_value,
+                input_value
+            )
+        })
+    }
+
+    /// Returns the total value of the outputs. Fails if a spendable output
+    /// holds nothing or the total overflows.
+    pub fn output_value(&self) -> Result<Amount> {
+        if self
+            .vout
+            .iter()
+            .any(|out| out.value.is_zero() && !out.script_pubkey.is_unspendable())
+        {
+            return Err(format_err!("Transaction {} has an empty output", self.id));
+        }
+
+        Amount::sum(self.vout.iter().map(|out| out.value))
+    }
+
+    /// Checks that the data outputs carry at most the number of bytes.
+    pub fn check_data_outputs(&self, max_size: usize) -> Result<()> {
+        for out in &self.vout {
+            if let ScriptType::Data(data) = out.script_pubkey.get_type() {
+                if data.len() > max_size {
+                    return Err(format_err!(
+                        "Data output of transaction {} carries {} bytes, at most {} are allowed",
+                        self.id,
+                        data.len(),
+                        max_size
+                    ));
+                }
+            }
         }
 
         Ok(())
     }
 
-    /// Get hash of the Transaction.
-    pub fn hash(&self) -> Result<String> {
-        let mut copy = self.clone();
-        copy.id = String::new();
-        let data = bincode::serialize(self)?;
-        let hasher = Sha256::new_with_prefix(&data[..]);
+    /// Checks that the encoded transaction has at most the number of bytes.
+    pub fn check_size(&self, max_size: usize) -> Result<()> {
+        let size = self.encode().len();
+        if size > max_size {
+            return Err(format_err!(
+                "Transaction {} has {} bytes, at most {} are allowed",
+                self.id,
+                size,
+                max_size
+            ));
+        }
 
-        Ok(format!("{:X}", hasher.finalize()))
+        Ok(())
+    }
+
+    /// Returns the hash of the canonical encoding of the transaction.
+    pub fn hash(&self) -> Result<Txid> {
+        Ok(Txid::digest(&self.encode()))
+    }
+
+    /// Returns the output spent by the input.
+    fn prev_output<'a>(
+        vin: &TXInput,
+        prev_txs: &'a HashMap<Txid, Transaction>,
+    ) -> Result<&'a TXOutput> {
+        prev_txs
+            .get(&vin.txid)
+            .and_then(|tx| tx.vout.get(vin.vout as usize))
+            .ok_or_else(|| format_err!("ERROR: Previous transaction is not correct"))
+    }
+
+    /// Returns the data signed by the input spending the output. It commits
+    /// to the value of the output, so a signer that can't look it up on the
+    /// chain knows the fee it signs.
+    fn signature_hash(&self, in_id: usize, prev_out: &TXOutput) -> Result<Txid> {
+        let mut tx_copy = self.trim_copy();
+        tx_copy.vin[in_id].script_sig = prev_out.script_pubkey.clone();
+        let mut data = tx_copy.encode();
+        write_u64(&mut data, prev_out.value.units());
+        Ok(Txid::digest(&data))
     }
 
     /// Creates a trimmed copy of the Transaction to be used in signing.
@@ -166,10 +347,10 @@ impl Transaction {
             .vin
             .iter()
             .map(|v| TXInput {
-                txid: v.txid.clone(),
+                txid: v.txid,
                 vout: v.vout,
-                signature: Vec::new(),
-                pub_key: Vec::new(),
+                script_sig: Script::default(),
+                sequence: v.sequence,
             })
             .collect();
 
@@ -178,14 +359,89 @@ impl Transaction {
             .iter()
             .map(|v| TXOutput {
                 value: v.value,
-                pub_key_hash: v.pub_key_hash.clone(),
+                script_pubkey: v.script_pubkey.clone(),
             })
             .collect();
 
         Transaction {
-            id: self.id.clone(),
+            id: self.id,
+            version: self.version,
             vin,
             vout,
+            lock_time: self.lock_time,
         }
     }
 }
+
+impl Encode for Transaction {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        write_u32(out, self.version);
+        write_all(out, &self.vin);
+        write_all(out, &self.vout);
+        write_u32(out, self.lock_time);
+    }
+}
+
+impl Decode for Transaction {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Transaction> {
+        let version = reader.read_u32()?;
+        if version != TX_VERSION {
+            return Err(format_err!("Unsupported transaction version {}", version));
+        }
+
+        let mut tx = Transaction {
+            id: Txid::ZERO,
+            version,
+            vin: reader.read_all()?,
+            vout: reader.read_all()?,
+            lock_time: reader.read_u32()?,
+        };
+        tx.id = tx.hash()?;
+        Ok(tx)
+    }
+}
+
+/// TransactionChecker checks signatures of an input of the transaction.
+struct TransactionChecker<'a> {
+    tx: &'a Transaction,
+    in_id: usize,
+    prev_out: &'a TXOutput,
+}
+
+impl SignatureChecker for TransactionChecker<'_> {
+    fn check_signature(&self, signature: &[u8], pub_key: &[u8]) -> bool {
+        if signature.len() != 64 || pub_key.len() != 32 {
+            return false;
+        }
+
+        match self.tx.signature_hash(self.in_id, self.prev_out) {
+            Ok(data) => ed25519::verify(data.as_bytes(), pub_key, signature),
+            Err(_) => false,
+        }
+    }
+
+    fn check_lock_time(&self, lock_time: u32) -> bool {
+        // heights can't be compared with times
+        if (lock_time < LOCKTIME_THRESHOLD) != (self.tx.lock_time < LOCKTIME_THRESHOLD) {
+            return false;
+        }
+
+        // a final input would let the transaction ignore its lock time
+        lock_time <= self.tx.lock_time && self.tx.vin[self.in_id].sequence != SEQUENCE_FINAL
+    }
+
+    fn check_sequence(&self, sequence: u32) -> bool {
+        if sequence & SEQUENCE_DISABLE_FLAG != 0 {
+            return true;
+        }
+
+        let input_sequence = self.tx.vin[self.in_id].sequence;
+        if input_sequence & SEQUENCE_DISABLE_FLAG != 0
+            || (sequence & SEQUENCE_TYPE_FLAG) != (input_sequence & SEQUENCE_TYPE_FLAG)
+        {
+            return false;
+        }
+
+        sequence & SEQUENCE_MASK <= input_sequence & SEQUENCE_MASK
+    }
+}
@@ -0,0 +1,184 @@
+use super::{builder::TransactionBuilder, tx::TXOutput, utxoset::UTXOSet, Transaction};
+use crate::{amount::Amount, errors::Result, script::Script, wallet::Wallet};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// RawTransaction is a transaction created without a secret key. It carries
+/// the outputs spent by its inputs, so it can be signed on a machine that
+/// has no access to the blockchain.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RawTransaction {
+    pub tx: Transaction,
+    pub prev_outputs: Vec<TXOutput>,
+    #[serde(default)]
+    pub partial_inputs: Vec<PartialInput>,
+}
+
+/// PartialInput collects the signatures of an input spending a multisig
+/// output until enough co-signers have signed it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PartialInput {
+    pub redeem_script: Option<Script>,
+    /// Hex encoded signatures keyed by the hex encoded public key.
+    pub signatures: BTreeMap<String, String>,
+}
+
+impl RawTransaction {
+    /// Creates a new unsigned transaction.
+    pub fn new(from: &str, to: &str, amount: Amount, utxo_set: &UTXOSet) -> Result<RawTransaction> {
+        TransactionBuilder::new(from)
+            .pay(to, amount)
+            .build(utxo_set)
+    }
+
+    /// Attaches the redeem script to the inputs spending outputs locked with
+    /// its hash. Returns the number of such inputs.
+    pub fn add_redeem_script(&mut self, redeem_script: &Script) -> Result<usize> {
+        let script_pubkey = Script::new_p2sh(&redeem_script.hash()?);
+        let mut count = 0;
+
+        for (prev_out, partial) in self.prev_outputs.iter().zip(&mut self.partial_inputs) {
+            if prev_out.is_locked_with(&script_pubkey) {
+                partial.redeem_script = Some(redeem_script.clone());
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Signs the inputs spending outputs of the wallet, or adds the wallet
+    /// signature to the inputs spending multisig outputs it is a co-signer
+    /// of. Returns the number of signatures made.
+    pub fn sign(&mut self, wallet: &Wallet) -> Result<usize> {
+        let script_pubkey = Script::new_p2pkh(&wallet.get_pub_key_hash());
+        let mut signed = 0;
+
+        for in_id in 0..self.tx.vin.len() {
+            let prev_out = &self.prev_outputs[in_id];
+            if prev_out.is_locked_with(&script_pubkey) {
+                let signature = self.tx.signature(in_id, &wallet.secret_key, prev_out)?;
+                self.tx.vin[in_id].script_sig =
+                    Script::new_p2pkh_unlock(&signature, &wallet.public_key);
+                signed += 1;
+            } else if self.add_signature(in_id, wallet)? {
+                signed += 1;
+            }
+        }
+        self.tx.id = self.tx.hash()?;
+
+        Ok(signed)
+    }
+
+    /// Returns the fee paid according to the values of the previous outputs.
+    /// Signatures commit to those values, so the transaction is invalid if
+    /// they were misstated.
+    pub fn fee(&self) -> Result<Amount> {
+        let input_value = Amount::sum(self.prev_outputs.iter().map(|out| out.value))?;
+        let output_value = self.tx.output_value()?;
+
+        input_value.checked_sub(output_value).ok_or_else(|| {
+            format_err!(
+                "Transaction spends {} but its inputs hold {}",
+                output_value,
+                input_value
+            )
+        })
+    }
+
+    /// Checks whether every input of the transaction is signed.
+    pub fn is_signed(&self) -> bool {
+        self.tx.vin.iter().all(|vin| !vin.script_sig.is_empty())
+    }
+
+    /// Returns the number of inputs holding all the signatures they need.
+    pub fn signed_inputs(&self) -> usize {
+        self.tx
+            .vin
+            .iter()
+            .filter(|vin| !vin.script_sig.is_empty())
+            .count()
+    }
+
+    /// Encodes the transaction into a portable JSON document.
+    pub fn encode(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| e.into())
+    }
+
+    /// Decodes the transaction from a JSON document.
+    pub fn decode(data: &str) -> Result<RawTransaction> {
+        let mut raw: RawTransaction = serde_json::from_str(data)?;
+
+        if raw.prev_outputs.len() != raw.tx.vin.len() {
+            return Err(format_err!(
+                "Raw transaction has {} inputs but {} previous outputs",
+                raw.tx.vin.len(),
+                raw.prev_outputs.len()
+            ));
+        }
+
+        if raw.partial_inputs.is_empty() {
+            raw.partial_inputs = vec![PartialInput::default(); raw.tx.vin.len()];
+        } else if raw.partial_inputs.len() != raw.tx.vin.len() {
+            return Err(format_err!(
+                "Raw transaction has {} inputs but {} partial inputs",
+                raw.tx.vin.len(),
+                raw.partial_inputs.len()
+            ));
+        }
+
+        Ok(raw)
+    }
+
+    /// Adds the wallet signature to an input spending a multisig output and
+    /// completes the input once it holds enough signatures. Returns whether a
+    /// signature was added.
+    fn add_signature(&mut self, in_id: usize, wallet: &Wallet) -> Result<bool> {
+        if !self.tx.vin[in_id].script_sig.is_empty() {
+            return Ok(false);
+        }
+
+        let redeem_script = match &self.partial_inputs[in_id].redeem_script {
+            Some(script) => script.clone(),
+            None => return Ok(false),
+        };
+        if !self.prev_outputs[in_id].is_locked_with(&Script::new_p2sh(&redeem_script.hash()?)) {
+            return Err(format_err!(
+                "Redeem script of input {} doesn't match the output it spends",
+                in_id
+            ));
+        }
+
+        let (required, pub_keys) = match redeem_script.get_multisig() {
+            Some(multisig) => multisig,
+            None => return Ok(false),
+        };
+        let key = hex::encode(&wallet.public_key);
+        if !pub_keys.contains(&wallet.public_key)
+            || self.partial_inputs[in_id].signatures.contains_key(&key)
+        {
+            return Ok(false);
+        }
+
+        let signature = self
+            .tx
+            .signature(in_id, &wallet.secret_key, &self.prev_outputs[in_id])?;
+        let partial = &mut self.partial_inputs[in_id];
+        partial.signatures.insert(key, hex::encode(signature));
+
+        // the script checks signatures in the order of the keys
+        let signatures = pub_keys
+            .iter()
+            .filter_map(|k| partial.signatures.get(&hex::encode(k)))
+            .take(required)
+            .map(hex::decode)
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        if signatures.len() == required {
+            self.tx.vin[in_id].script_sig =
+                Script::new_multisig_unlock(&signatures, &redeem_script)?;
+        }
+
+        Ok(true)
+    }
+}
@@ -1,53 +1,203 @@
-use crate::errors::Result;
-use bitcoincash_addr::Address;
+use crate::{
+    amount::Amount,
+    encoding::{write_bytes, write_i32, write_u32, write_u64, Decode, Encode, Reader},
+    errors::Result,
+    hash::Txid,
+    script::Script,
+};
+use failure::format_err;
 use log::debug;
 use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+/// Sequence of an input which has no relative lock and doesn't enable the
+/// lock time of the transaction.
+pub const SEQUENCE_FINAL: u32 = u32::MAX;
+
+/// Sequence of an input which has no relative lock but enables the lock time
+/// of the transaction.
+pub const SEQUENCE_LOCKTIME_ENABLED: u32 = SEQUENCE_FINAL - 1;
+
+/// Set in the sequence of an input which has no relative lock.
+pub const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+
+/// Set in the sequence of an input locked for a time rather than a number of
+/// blocks.
+pub const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+
+/// Bits of the sequence holding the relative lock.
+pub const SEQUENCE_MASK: u32 = 0xffff;
+
+/// Relative time locks are counted in units of 2^9 = 512 seconds.
+pub const SEQUENCE_GRANULARITY: u32 = 9;
 
 /// TXInput represents a transaction input
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXInput {
-    pub txid: String,
+    pub txid: Txid,
     pub vout: i32,
-    pub signature: Vec<u8>,
-    pub pub_key: Vec<u8>,
+    pub script_sig: Script,
+    pub sequence: u32,
+}
+
+/// OutPoint identifies an output of a transaction.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutPoint {
+    pub txid: Txid,
+    pub vout: i32,
+}
+
+/// RelativeLock keeps an input from being mined until the output it spends
+/// is old enough.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelativeLock {
+    /// Number of blocks mined on top of the spent output.
+    Blocks(u32),
+    /// Number of seconds since the spent output was mined, a multiple of 512.
+    Seconds(u32),
 }
 
 /// TXOutput represents a transaction output
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TXOutput {
-    pub value: i32,
-    pub pub_key_hash: Vec<u8>,
+    pub value: Amount,
+    pub script_pubkey: Script,
 }
 
-// TXOutputs collects TXOutput
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// TXOutputs collects TXOutput keyed by their index in the transaction
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
+    pub outputs: BTreeMap<i32, TXOutput>,
 }
 
-impl TXOutput {
-    pub fn new(value: i32, address: String) -> Result<Self> {
-        let mut txo = TXOutput {
-            value,
-            pub_key_hash: Vec::new(),
-        };
+impl Encode for TXInput {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.txid.as_bytes());
+        write_i32(out, self.vout);
+        write_bytes(out, &self.script_sig.encode());
+        write_u32(out, self.sequence);
+    }
+}
 
-        txo.lock(&address)?;
-        Ok(txo)
+impl Decode for TXInput {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<TXInput> {
+        Ok(TXInput {
+            txid: Txid::from_bytes(reader.read_hash()?),
+            vout: reader.read_i32()?,
+            script_sig: Script::from_bytes(reader.read_bytes()?)?,
+            sequence: reader.read_u32()?,
+        })
     }
+}
 
-    /// Checks if the output can be unlocked with the provided data.
-    pub fn can_be_unlock_with(&self, unlocking_data: &[u8]) -> bool {
-        self.pub_key_hash == unlocking_data
+impl Encode for TXOutput {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        write_u64(out, self.value.units());
+        write_bytes(out, &self.script_pubkey.encode());
     }
+}
+
+impl Decode for TXOutput {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<TXOutput> {
+        Ok(TXOutput {
+            value: Amount::from_units(reader.read_u64()?),
+            script_pubkey: Script::from_bytes(reader.read_bytes()?)?,
+        })
+    }
+}
 
-    /// Lock signs the output.
-    fn lock(&mut self, address: &str) -> Result<()> {
-        let pub_key_hash = Address::decode(address).unwrap().body;
+impl TXInput {
+    /// Returns the relative lock encoded in the sequence.
+    pub fn relative_lock(&self) -> Option<RelativeLock> {
+        if self.sequence & SEQUENCE_DISABLE_FLAG != 0 {
+            return None;
+        }
 
+        let value = self.sequence & SEQUENCE_MASK;
+        if self.sequence & SEQUENCE_TYPE_FLAG != 0 {
+            Some(RelativeLock::Seconds(value << SEQUENCE_GRANULARITY))
+        } else {
+            Some(RelativeLock::Blocks(value))
+        }
+    }
+}
+
+impl RelativeLock {
+    /// Returns the input sequence encoding the lock.
+    pub fn to_sequence(self) -> u32 {
+        match self {
+            RelativeLock::Blocks(blocks) => blocks,
+            RelativeLock::Seconds(seconds) => {
+                SEQUENCE_TYPE_FLAG | (seconds >> SEQUENCE_GRANULARITY)
+            }
+        }
+    }
+}
+
+impl fmt::Display for RelativeLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelativeLock::Blocks(blocks) => write!(f, "{} blocks", blocks),
+            RelativeLock::Seconds(seconds) => write!(f, "{} seconds", seconds),
+        }
+    }
+}
+
+impl FromStr for RelativeLock {
+    type Err = failure::Error;
+
+    /// Parses a number of blocks, or a number of seconds with an `s` suffix
+    /// which is rounded up to a multiple of 512.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || format_err!("Invalid relative lock '{}'", s);
+
+        let lock = match s.strip_suffix('s') {
+            Some(seconds) => {
+                let seconds: u32 = seconds.parse().map_err(|_| invalid())?;
+                // rounds up, failing rather than wrapping near u32::MAX
+                let units = seconds
+                    .checked_add((1 << SEQUENCE_GRANULARITY) - 1)
+                    .ok_or_else(invalid)?
+                    >> SEQUENCE_GRANULARITY;
+                if units > SEQUENCE_MASK {
+                    return Err(invalid());
+                }
+                RelativeLock::Seconds(units << SEQUENCE_GRANULARITY)
+            }
+            None => {
+                let blocks: u32 = s.parse().map_err(|_| invalid())?;
+                if blocks > SEQUENCE_MASK {
+                    return Err(invalid());
+                }
+                RelativeLock::Blocks(blocks)
+            }
+        };
+
+        Ok(lock)
+    }
+}
+
+impl TXOutput {
+    /// Creates an output paying the value to the address.
+    pub fn new(value: Amount, address: String) -> Result<Self> {
         debug!("lock: {}", address);
 
-        self.pub_key_hash = pub_key_hash;
-        Ok(())
+        Ok(TXOutput {
+            value,
+            script_pubkey: Script::from_address(&address)?,
+        })
+    }
+
+    /// Creates an unspendable output carrying the data.
+    pub fn new_data(data: &[u8]) -> Self {
+        TXOutput {
+            value: Amount::ZERO,
+            script_pubkey: Script::new_data(data),
+        }
+    }
+
+    /// Checks if the output is locked with the script.
+    pub fn is_locked_with(&self, script_pubkey: &Script) -> bool {
+        self.script_pubkey == *script_pubkey
     }
 }
@@ -1,9 +1,14 @@
-use super::tx::TXOutputs;
-use crate::blockchain::Blockchain;
+use super::{
+    coinselect::UnspentOutput,
+    tx::{TXOutput, TXOutputs},
+};
+use crate::amount::Amount;
+use crate::blockchain::{Blockchain, ChainUpdate};
 use crate::errors::Result;
-use crate::{block::Block, db};
+use crate::{block::Block, db, hash::Txid, script::Script};
+use failure::format_err;
 use log::{debug, error};
-use std::collections::HashMap;
+use std::collections::HashSet;
 
 pub struct UTXOSet {
     pub blockchain: Blockchain,
@@ -12,66 +17,85 @@ pub struct UTXOSet {
 impl UTXOSet {
     /// Rebuilds the UTXO set.
     pub fn reindex(&self) -> Result<()> {
-        if let Err(e) = std::fs::remove_dir_all(db::DB_UTXOS_PATH) {
+        if let Err(e) = std::fs::remove_dir_all(db::path(db::DB_UTXOS)) {
             match e.kind() {
                 std::io::ErrorKind::NotFound => debug!("blocks not exists to delete"),
                 _ => error!("undefined error on delete blocks: {}", e),
             };
         }
 
-        let db = sled::open(db::DB_UTXOS_PATH)?;
+        let db = db::open(db::DB_UTXOS)?;
         let utxos = self.blockchain.find_utxo();
 
         for (txid, outs) in utxos {
-            db.insert(txid.as_bytes(), bincode::serialize(&outs)?)?;
+            db.insert(txid, bincode::serialize(&outs)?)?;
         }
 
         Ok(())
     }
-    pub fn find_spendable_outputs(
-        &self,
-        address: &[u8],
-        amount: i32,
-    ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
-        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut accumulated = 0;
-        let db = sled::open(db::DB_UTXOS_PATH)?;
+    /// Finds all unspent outputs locked with the script.
+    pub fn find_unspent_outputs(&self, script_pubkey: &Script) -> Result<Vec<UnspentOutput>> {
+        let mut unspent_outputs = Vec::new();
+        let db = db::open(db::DB_UTXOS)?;
 
         for kv in db.iter() {
             let (k, v) = kv?;
-            let txid = String::from_utf8(k.to_vec())?;
+            let txid = Txid::from_slice(&k)?;
             let outs: TXOutputs = bincode::deserialize(&v)?;
 
-            for out_idx in 0..outs.outputs.len() {
-                if outs.outputs[out_idx].can_be_unlock_with(address) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                        }
-                    }
+            for (vout, output) in outs.outputs {
+                if output.is_locked_with(script_pubkey) {
+                    unspent_outputs.push(UnspentOutput { txid, vout, output });
                 }
             }
         }
 
-        Ok((accumulated, unspent_outputs))
+        Ok(unspent_outputs)
+    }
+
+    /// Finds the unspent outputs locked with the script which can be spent
+    /// by the next block, leaving out those of immature coinbases.
+    pub fn find_spendable_outputs(&self, script_pubkey: &Script) -> Result<Vec<UnspentOutput>> {
+        let immature = self
+            .blockchain
+            .immature_coinbases(self.blockchain.get_best_height()? + 1);
+
+        Ok(self
+            .find_unspent_outputs(script_pubkey)?
+            .into_iter()
+            .filter(|u| !immature.contains_key(&u.txid))
+            .collect())
+    }
+
+    /// Returns the value of the unspent outputs locked with the script, split
+    /// into the spendable value and the value of immature coinbases.
+    pub fn get_balance(&self, script_pubkey: &Script) -> Result<(Amount, Amount)> {
+        let immature = self
+            .blockchain
+            .immature_coinbases(self.blockchain.get_best_height()? + 1);
+        let (immature, spendable): (Vec<_>, Vec<_>) = self
+            .find_unspent_outputs(script_pubkey)?
+            .into_iter()
+            .partition(|u| immature.contains_key(&u.txid));
+
+        Ok((
+            Amount::sum(spendable.iter().map(|u| u.output.value))?,
+            Amount::sum(immature.iter().map(|u| u.output.value))?,
+        ))
     }
 
-    /// Finds UTXO for a public key hash
-    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs {
-            outputs: Vec::new(),
-        };
-        let db = sled::open(db::DB_UTXOS_PATH)?;
+    /// Finds UTXO locked with the script
+    pub fn find_utxo(&self, script_pubkey: &Script) -> Result<Vec<TXOutput>> {
+        let mut utxos = Vec::new();
+        let db = db::open(db::DB_UTXOS)?;
 
         for kv in db.iter() {
             let (_, v) = kv?;
             let outs: TXOutputs = bincode::deserialize(&v)?;
 
-            for out in outs.outputs {
-                if out.can_be_unlock_with(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
+            for out in outs.outputs.into_values() {
+                if out.is_locked_with(script_pubkey) {
+                    utxos.push(out)
                 }
             }
         }
@@ -79,44 +103,126 @@ impl UTXOSet {
         Ok(utxos)
     }
 
+    /// Returns the unspent output with the index of the transaction.
+    pub fn find_output(&self, txid: &Txid, vout: i32) -> Result<Option<TXOutput>> {
+        let db = db::open(db::DB_UTXOS)?;
+
+        match db.get(txid)? {
+            Some(v) => {
+                let mut outs: TXOutputs = bincode::deserialize(&v)?;
+                Ok(outs.outputs.remove(&vout))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Checks a block mined elsewhere, which must only spend unspent
+    /// outputs or outputs of the transactions before them in the block, and
+    /// adds it to the Blockchain. The UTXO set follows the tip, it is rebuilt
+    /// when the chain is reorganized.
+    pub fn submit_block(&mut self, block: &Block) -> Result<ChainUpdate> {
+        // blocks off the tip are checked against their branch by the Blockchain
+        if block.get_prev_hash() == self.blockchain.get_last_hash() {
+            let mut created = HashSet::new();
+
+            for tx in block.get_transactions() {
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        if !created.contains(&(vin.txid, vin.vout))
+                            && self.find_output(&vin.txid, vin.vout)?.is_none()
+                        {
+                            return Err(format_err!(
+                                "Transaction {} spends output {}:{} which is spent or unknown",
+                                tx.id,
+                                vin.txid,
+                                vin.vout
+                            ));
+                        }
+                    }
+                }
+                created.extend((0..tx.vout.len()).map(|vout| (tx.id, vout as i32)));
+            }
+        }
+
+        let update = self.blockchain.connect_block(block)?;
+        match update {
+            ChainUpdate::Extended => self.update(block)?,
+            ChainUpdate::SideBranch => {}
+            ChainUpdate::Reorganized { .. } => self.reindex()?,
+        }
+
+        Ok(update)
+    }
+
     pub fn update(&self, block: &Block) -> Result<()> {
-        let db = sled::open(db::DB_UTXOS_PATH)?;
+        let db = db::open(db::DB_UTXOS)?;
 
         for tx in block.get_transactions() {
             if !tx.is_coinbase() {
                 for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs {
-                        outputs: Vec::new(),
-                    };
-                    let outs: TXOutputs = bincode::deserialize(&db.get(&vin.txid)?.unwrap())?;
-
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
-                        }
-                    }
+                    let mut outs: TXOutputs = bincode::deserialize(&db.get(vin.txid)?.unwrap())?;
+                    outs.outputs.remove(&vin.vout);
 
-                    if update_outputs.outputs.is_empty() {
-                        db.remove(&vin.txid)?;
+                    if outs.outputs.is_empty() {
+                        db.remove(vin.txid)?;
                     } else {
-                        db.insert(vin.txid.as_bytes(), bincode::serialize(&update_outputs)?)?;
+                        db.insert(vin.txid, bincode::serialize(&outs)?)?;
                     }
                 }
             }
 
+            // unspendable outputs never leave the UTXO set, so keep them out
             let new_outputs = TXOutputs {
-                outputs: tx.vout.to_vec(),
+                outputs: tx
+                    .vout
+                    .iter()
+                    .cloned()
+                    .enumerate()
+                    .filter(|(_, out)| !out.script_pubkey.is_unspendable())
+                    .map(|(i, out)| (i as i32, out))
+                    .collect(),
             };
 
-            db.insert(tx.id.as_bytes(), bincode::serialize(&new_outputs)?)?;
+            if !new_outputs.outputs.is_empty() {
+                db.insert(tx.id, bincode::serialize(&new_outputs)?)?;
+            }
         }
 
         Ok(())
     }
 
+    /// Returns the value of all unspent outputs, which is the amount of
+    /// money issued so far.
+    pub fn get_total_value(&self) -> Result<Amount> {
+        let db = db::open(db::DB_UTXOS)?;
+        let mut values = Vec::new();
+
+        for kv in db.iter() {
+            let (_, v) = kv?;
+            let outs: TXOutputs = bincode::deserialize(&v)?;
+            values.extend(outs.outputs.values().map(|out| out.value));
+        }
+
+        Amount::sum(values)
+    }
+
+    /// Returns the number of unspent outputs.
+    pub fn count_outputs(&self) -> Result<usize> {
+        let db = db::open(db::DB_UTXOS)?;
+        let mut counter = 0;
+
+        for kv in db.iter() {
+            let (_, v) = kv?;
+            let outs: TXOutputs = bincode::deserialize(&v)?;
+            counter += outs.outputs.len();
+        }
+
+        Ok(counter)
+    }
+
     pub fn count_transactions(&self) -> Result<i32> {
         let mut counter = 0;
-        let db = sled::open(db::DB_UTXOS_PATH)?;
+        let db = db::open(db::DB_UTXOS)?;
 
         for kv in db.iter() {
             kv?;
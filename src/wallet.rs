@@ -1,6 +1,7 @@
-use crate::{db, errors::Result};
+use crate::{db, errors::Result, script::Script};
 use bitcoincash_addr::{Address, HashType, Scheme};
 use crypto::ed25519;
+use failure::format_err;
 use log::info;
 use rand::{rngs::OsRng, RngCore};
 use ripemd::Ripemd160;
@@ -16,30 +17,50 @@ pub struct Wallet {
 
 pub struct Wallets {
     wallets: HashMap<String, Wallet>,
+    scripts: HashMap<String, Script>,
+    /// Seed new wallets are derived from, made with the first of them.
+    seed: Option<Vec<u8>>,
+    /// Index of the next wallet derived from the seed.
+    next_index: u32,
 }
 
+/// Size of a wallet seed in bytes.
+pub const SEED_SIZE: usize = 32;
+
+/// Name of the tree of the wallets DB holding redeem scripts.
+const SCRIPTS_TREE: &str = "scripts";
+
+/// Name of the tree of the wallets DB holding the seed and the next index.
+const SEED_TREE: &str = "seed";
+
 impl Wallet {
-    /// Creates a new Wallet.
-    fn new() -> Self {
-        let mut key: [u8; 32] = [0; 32];
-        OsRng.fill_bytes(&mut key);
-        let (secrect_key, public_key) = ed25519::keypair(&key);
-        let secret_key = secrect_key.to_vec();
-        let public_key = public_key.to_vec();
+    /// Derives the wallet at the index from a seed, so the wallets can be
+    /// restored from the seed alone.
+    fn from_seed(seed: &[u8], index: u32) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(seed);
+        hasher.update(index.to_le_bytes());
+        let (secret_key, public_key) = ed25519::keypair(&hasher.finalize());
         Wallet {
-            secret_key,
-            public_key,
+            secret_key: secret_key.to_vec(),
+            public_key: public_key.to_vec(),
         }
     }
 
-    /// Returns wallet address.
-    pub fn get_address(&self) -> String {
+    /// Returns hash of the wallet public key.
+    pub fn get_pub_key_hash(&self) -> Vec<u8> {
         let mut pub_hash = self.public_key.clone();
         hash_pub_key(&mut pub_hash);
+        pub_hash
+    }
+
+    /// Returns wallet address.
+    pub fn get_address(&self) -> String {
+        let pub_hash = self.get_pub_key_hash();
         let address = Address {
             body: pub_hash,
             scheme: Scheme::Base58,
-            hash_type: HashType::Script,
+            hash_type: HashType::Key,
             ..Default::default()
         };
         address.encode().unwrap()
@@ -51,24 +72,44 @@ impl Wallets {
     pub fn new() -> Result<Wallets> {
         let mut wlt = Wallets {
             wallets: HashMap::<String, Wallet>::new(),
+            scripts: HashMap::<String, Script>::new(),
+            seed: None,
+            next_index: 0,
         };
 
-        let db = sled::open(db::DB_WALLETS_PATH)?;
+        let db = db::open(db::DB_WALLETS)?;
         for item in db.into_iter() {
             let i = item?;
-            let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = bincode::deserialize(&i.1)?;
-            wlt.wallets.insert(address, wallet);
+            let wallet: Wallet = bincode::deserialize(&i.1)?;
+            wlt.wallets.insert(wallet.get_address(), wallet);
+        }
+        for item in db.open_tree(SCRIPTS_TREE)?.into_iter() {
+            let i = item?;
+            let script = Script::from_bytes(&i.1)?;
+            wlt.scripts.insert(script.to_p2sh_address()?, script);
+        }
+        let seed = db.open_tree(SEED_TREE)?;
+        wlt.seed = seed.get("SEED")?.map(|s| s.to_vec());
+        if let Some(index) = seed.get("NEXT_INDEX")? {
+            wlt.next_index = bincode::deserialize(&index)?;
         }
 
         drop(db);
         Ok(wlt)
     }
 
-    /// Creates wallet, adds it in Wallets. Returns address of the wallet
-    /// created.
+    /// Returns the seed the wallets are derived from, creating it if the
+    /// wallets don't have one yet.
+    pub fn get_or_create_seed(&mut self) -> Vec<u8> {
+        self.seed.get_or_insert_with(new_seed).clone()
+    }
+
+    /// Creates wallet derived from the seed at the next index, adds it in
+    /// Wallets. Returns address of the wallet created.
     pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
+        let seed = self.get_or_create_seed();
+        let wallet = Wallet::from_seed(&seed, self.next_index);
+        self.next_index += 1;
         let address = wallet.get_address();
         self.wallets.insert(address.clone(), wallet);
 
@@ -77,6 +118,27 @@ impl Wallets {
         address
     }
 
+    /// Adds the first wallets derived from the seed. Returns their addresses.
+    /// The seed becomes the one of new wallets unless there is one already.
+    pub fn restore_wallets(&mut self, seed: &[u8], count: u32) -> Vec<String> {
+        let mut addresses = Vec::new();
+
+        if *self.seed.get_or_insert_with(|| seed.to_vec()) == seed {
+            self.next_index = self.next_index.max(count);
+        }
+
+        for index in 0..count {
+            let wallet = Wallet::from_seed(seed, index);
+            let address = wallet.get_address();
+            self.wallets.insert(address.clone(), wallet);
+
+            info!("Restore wallet: {}", address);
+            addresses.push(address);
+        }
+
+        addresses
+    }
+
     /// Returns an array of addresses store in a wallet file.
     pub fn get_all_addresses(&self) -> Vec<String> {
         self.wallets.keys().cloned().collect()
@@ -87,15 +149,80 @@ impl Wallets {
         self.wallets.get(address)
     }
 
+    /// Checks that the address isn't the old form of a wallet address. Wallet
+    /// addresses used to be encoded as script hashes starting with `3`, and
+    /// money sent to them now could only be spent with a script hashing to
+    /// the public key hash, which doesn't exist.
+    pub fn check_recipient(&self, address: &str) -> Result<()> {
+        let decoded = match Address::decode(address) {
+            Ok(decoded) => decoded,
+            Err(_) => return Ok(()),
+        };
+        if decoded.hash_type != HashType::Script || self.scripts.contains_key(address) {
+            return Ok(());
+        }
+
+        if let Some(wallet) = self
+            .wallets
+            .values()
+            .find(|w| w.get_pub_key_hash() == decoded.body)
+        {
+            return Err(format_err!(
+                "Address '{}' is the old form of wallet '{}', money sent to it would be lost",
+                address,
+                wallet.get_address()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Get wallet by public key.
+    pub fn get_wallet_by_pub_key(&self, pub_key: &[u8]) -> Option<&Wallet> {
+        self.wallets.values().find(|w| w.public_key == pub_key)
+    }
+
+    /// Adds a redeem script, so outputs paid to its address can be spent.
+    /// Returns the address of the script.
+    pub fn add_script(&mut self, script: Script) -> Result<String> {
+        let address = script.to_p2sh_address()?;
+        self.scripts.insert(address.clone(), script);
+
+        info!("Add script: {}", address);
+
+        Ok(address)
+    }
+
+    /// Get redeem script by address.
+    pub fn get_script(&self, address: &str) -> Option<&Script> {
+        self.scripts.get(address)
+    }
+
+    /// Returns an array of addresses of the redeem scripts.
+    pub fn get_script_addresses(&self) -> Vec<String> {
+        self.scripts.keys().cloned().collect()
+    }
+
     /// Saves wallets to a file.
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open(db::DB_WALLETS_PATH)?;
+        let db = db::open(db::DB_WALLETS)?;
 
         for (address, wallet) in &self.wallets {
             let data = bincode::serialize(wallet)?;
             db.insert(address, data)?;
         }
 
+        let scripts = db.open_tree(SCRIPTS_TREE)?;
+        for (address, script) in &self.scripts {
+            scripts.insert(address, script.to_bytes()?)?;
+        }
+
+        let seed = db.open_tree(SEED_TREE)?;
+        if let Some(s) = &self.seed {
+            seed.insert("SEED", s.as_slice())?;
+        }
+        seed.insert("NEXT_INDEX", bincode::serialize(&self.next_index)?)?;
+
         db.flush()?;
         drop(db);
 
@@ -103,14 +230,24 @@ impl Wallets {
     }
 }
 
+/// Creates a random seed to derive wallets from.
+pub fn new_seed() -> Vec<u8> {
+    let mut seed = vec![0; SEED_SIZE];
+    OsRng.fill_bytes(&mut seed);
+    seed
+}
+
 /// Hashes public key.
 pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
+    *pub_key = hash160(pub_key);
+}
+
+/// Returns RIPEMD160 hash of SHA256 hash of the data.
+pub fn hash160(data: &[u8]) -> Vec<u8> {
     let mut hasher1 = Sha256::new();
-    hasher1.update(pub_key.clone());
-    pub_key.copy_from_slice(&hasher1.finalize());
+    hasher1.update(data);
 
     let mut hasher2 = Ripemd160::new();
-    hasher2.update(pub_key.clone());
-    pub_key.resize(20, 0);
-    pub_key.copy_from_slice(&hasher2.finalize());
+    hasher2.update(hasher1.finalize());
+    hasher2.finalize().to_vec()
 }